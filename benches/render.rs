@@ -0,0 +1,132 @@
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use frameserve::{
+    config::Config,
+    package::{Package, RemoteResource, Segment, Variant, VariantKind},
+    playout::{Playlist, Takeovers},
+};
+use jiff::Timestamp;
+use num::rational::Ratio;
+
+const PACKAGES: u32 = 50;
+const SEGMENTS: u64 = 300;
+
+fn config() -> &'static Config {
+    let config = serde_json::from_str(
+        r#"{
+            "bind_address": "127.0.0.1:0",
+            "base": "http://localhost",
+            "media_base": "http://localhost/media"
+        }"#,
+    )
+    .unwrap();
+    Box::leak(Box::new(config))
+}
+
+fn variant(kind: VariantKind, bitrate: u32, time_base: Ratio<u32>, segment_ticks: u64) -> Variant {
+    let segments = (0..SEGMENTS)
+        .map(|i| Segment {
+            src: RemoteResource(format!("{bitrate}/seg{i}.m4s")),
+            start: i * segment_ticks,
+            duration: segment_ticks,
+            range: None,
+            size: None,
+            keyframe: None,
+        })
+        .collect();
+    Variant {
+        init_src: Some(RemoteResource(format!("{bitrate}/init.mp4"))),
+        init_range: None,
+        time_base,
+        bitrate,
+        channels: (kind == VariantKind::Audio).then_some(2),
+        language: None,
+        cues: Vec::new(),
+        ad_breaks: Vec::new(),
+        codecs: None,
+        kind,
+        segments,
+    }
+}
+
+/// Roughly an hour per package with the usual 10s segments, at the time bases
+/// the encoder produces.
+fn package(vid: u32) -> Package {
+    let video = Ratio::new(1, 90000);
+    let audio = Ratio::new(1, 48000);
+    let rung = |width, height, bitrate| {
+        let kind = VariantKind::Video { width, height };
+        variant(kind, bitrate, video, 900000)
+    };
+    Package {
+        vid,
+        packaged_at: Timestamp::UNIX_EPOCH,
+        variants: vec![
+            rung(1920, 1080, 5000000),
+            rung(1280, 720, 1500000),
+            rung(960, 540, 400000),
+            variant(VariantKind::Audio, 192000, audio, 480000),
+        ],
+        poster: None,
+        thumbnails: None,
+        repeats: None,
+    }
+}
+
+fn playlist() -> Playlist {
+    let packages = (1..=PACKAGES).map(package).collect();
+    Playlist::with_config(Timestamp::UNIX_EPOCH, packages, config())
+}
+
+fn bench_render(c: &mut Criterion) {
+    let playlist = playlist();
+    let takeovers = Takeovers::default();
+    // Part way into a later package, so lookups don't hit the first entry.
+    let now = Timestamp::from_second(7 * 24 * 60 * 60 + 12345).unwrap();
+
+    c.bench_function("at", |b| b.iter(|| playlist.at(black_box(now), &takeovers)));
+
+    c.bench_function("render_variant_playlist", |b| {
+        let mut out = String::new();
+        b.iter(|| {
+            out.clear();
+            playlist.streams[0]
+                .render_variant_playlist(&mut out, &playlist, &takeovers, black_box(now))
+                .unwrap();
+        })
+    });
+
+    // As the server renders them, into a buffer per request: one that
+    // grows as it's written, and one sized up front.
+    c.bench_function("render_variant_playlist_fresh", |b| {
+        b.iter(|| {
+            let mut out = String::new();
+            playlist.streams[0]
+                .render_variant_playlist(&mut out, &playlist, &takeovers, black_box(now))
+                .unwrap();
+            out
+        })
+    });
+
+    c.bench_function("render_variant_playlist_presized", |b| {
+        b.iter(|| {
+            let mut out = String::with_capacity(playlist.streams[0].capacity());
+            playlist.streams[0]
+                .render_variant_playlist(&mut out, &playlist, &takeovers, black_box(now))
+                .unwrap();
+            out
+        })
+    });
+
+    c.bench_function("master_playlist", |b| {
+        let mut out = String::new();
+        b.iter(|| {
+            out.clear();
+            playlist.master_playlist(&mut out).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, bench_render);
+criterion_main!(benches);
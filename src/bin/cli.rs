@@ -1,10 +1,22 @@
-use std::fs;
+use std::{fs, path::Path};
 
 use clap::Parser;
-use frameserve::inspect::{Profile, inspect};
-use frameserve::package::package;
-use frameserve::recipe::{Pass, VideoSpec, transcode_video};
+use frameserve::capabilities::Capabilities;
+use frameserve::clean::{Output, clean};
+use frameserve::config::{StoreConfig, get_config, get_tools};
+use frameserve::diff::diff;
+use frameserve::inspect::{Profile, inspect, try_inspect};
+use frameserve::package::{Package, PackageOptions, PackagingMode, ProbeMode, package};
+use frameserve::playout::Playlist;
+use frameserve::recipe::{
+    AudioOutput, Fit, Input, Output as VideoOutput, Pass, PixelFormat, Quality, QualityProfile,
+    SegmentType, TileLayout, Tune, VideoSpec, extract_poster, extract_sprites, render_crossfade,
+    transcode_video,
+};
+use frameserve::store;
 use frameserve::utils::extract_vid;
+use frameserve::verify::verify_encode;
+use jiff::{SignedDuration, Timestamp, Unit};
 
 #[derive(Parser)]
 struct Args {
@@ -19,11 +31,250 @@ enum Command {
         vid: Option<u32>,
         #[clap(default_value = "encodes")]
         out_dir: String,
+        /// Further files appended to `original` to form one video
+        #[clap(long)]
+        concat: Vec<String>,
+        #[clap(flatten)]
+        options: EncodeOptions,
+    },
+    /// Encode every video in a directory, named by vid, as `encode` would
+    EncodeBatch {
+        dir: String,
+        #[clap(default_value = "encodes")]
+        out_dir: String,
+        /// Encode at most this many, not counting those skipped
+        #[clap(long)]
+        limit: Option<usize>,
+        /// Skip videos whose every rung has already been encoded to the end
+        #[clap(long)]
+        skip_existing: bool,
+        #[clap(flatten)]
+        options: EncodeOptions,
+    },
+    /// Check an encode's rungs are complete and sound before packaging it
+    VerifyEncode {
+        /// One video's directory, e.g. `encodes/<vid>`
+        dir: String,
     },
     Package {
         dir: String,
+        /// Store each variant's segments in one file addressed by byte range
+        #[clap(long)]
+        byte_range: bool,
+        /// With --byte-range, put the init segment in the same file
+        #[clap(long, requires = "byte_range")]
+        shared_init: bool,
+        /// Merge a final segment shorter than this many seconds into the previous one
+        #[clap(long, default_value_t = 1.0)]
+        min_final_segment: f64,
+        /// Probe each variant's segments with one ffprobe rather than one per segment
+        #[clap(long)]
+        batch_probe: bool,
+        /// Time each segment from its packets rather than its stream header
+        #[clap(long, conflicts_with = "batch_probe")]
+        packet_probe: bool,
+        /// Take segment timings from the encoder's stream.m3u8, probing only a sample
+        #[clap(long, conflicts_with_all = ["batch_probe", "packet_probe"])]
+        playlist_probe: bool,
+        /// Warn when audio and video lengths differ by more than this many seconds
+        #[clap(long, default_value_t = 0.1)]
+        max_av_drift: f64,
+        /// Name files after this many bytes of their hash, for shorter URIs
+        #[clap(long, default_value_t = 32, value_parser = clap::value_parser!(u8).range(8..=32))]
+        hash_bytes: u8,
+    },
+    /// Render a transition from the end of one video into the start of
+    /// another, to encode and package for `loop_crossfade`
+    Crossfade {
+        from: String,
+        to: String,
+        out_file: String,
+        #[clap(long, default_value_t = 2.0)]
+        seconds: f64,
+    },
+    /// Summarise a file's streams before deciding how to encode it
+    Inspect {
+        file: String,
+        #[clap(long)]
+        json: bool,
     },
-    Clean,
+    /// Compare two packagings of a video, e.g. before and after a re-encode
+    Diff {
+        old: String,
+        new: String,
+        #[clap(long)]
+        json: bool,
+    },
+    /// List what the main channel plays next, without running the server
+    Schedule {
+        /// Defaults to the configured packages directory
+        packages_dir: Option<String>,
+        /// How far ahead to look, e.g. `2h` or `90m`
+        #[clap(long, default_value = "2h")]
+        ahead: SignedDuration,
+        #[clap(long)]
+        json: bool,
+    },
+    /// Delete packaged output from the configured directories
+    Clean {
+        /// Really delete; otherwise nothing is touched
+        #[clap(long)]
+        yes: bool,
+        /// List what would be deleted
+        #[clap(long, conflicts_with = "yes")]
+        dry_run: bool,
+    },
+}
+
+#[derive(clap::Args)]
+struct EncodeOptions {
+    #[clap(long, value_enum, default_value_t)]
+    profile: QualityProfile,
+    /// Overrides the profile's x264 tune for non-film content
+    #[clap(long, value_enum, default_value_t)]
+    tune: Tune,
+    /// How a source of another aspect ratio is fitted to each rung
+    #[clap(long, value_enum, default_value_t)]
+    fit: Fit,
+    /// Encode each rung in one pass at this CRF, capped at its bitrate
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=51))]
+    crf: Option<u8>,
+    /// MPEG-TS segments for devices that can't play fMP4
+    #[clap(long, value_enum, default_value_t)]
+    segment_type: SegmentType,
+    /// Seconds per segment, which is also each rung's GOP
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    segment_duration: u32,
+    /// Seconds between trick-play thumbnails
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    thumbnail_interval: u32,
+}
+
+/// The video ladder, encoded to `out_dir`.
+fn video_outputs(out_dir: &str, options: &EncodeOptions) -> [VideoOutput; 3] {
+    let spec = |width, height, bit_rate, profile| VideoSpec {
+        width,
+        height,
+        bit_rate,
+        profile,
+        pix_fmt: PixelFormat::Yuv420p,
+        crf: options.crf,
+        fit: options.fit,
+        gop_seconds: None,
+    };
+    [
+        spec(1920, 1080, 5000_000, Profile::High),
+        spec(1280, 720, 1500_000, Profile::High),
+        spec(960, 540, 400_000, Profile::Main),
+    ]
+    .map(|spec| {
+        spec.out_dir(out_dir)
+            .segment_type(options.segment_type)
+            .segment_duration(options.segment_duration)
+    })
+}
+
+/// Encodes `files`, joined end to end, as `vid` under `out_dir`.
+fn encode(
+    files: &[String],
+    vid: u32,
+    out_dir: &str,
+    options: &EncodeOptions,
+) -> Result<(), String> {
+    let original = &files[0];
+    let tools = get_tools();
+    let capabilities = Capabilities::probe(tools);
+    if !capabilities.has_encoder("libx264") {
+        return Err(format!("{} has no libx264 encoder", tools.ffmpeg));
+    }
+    let audio_encoder = capabilities.audio_encoder(&tools.audio_encoder);
+
+    let media_info = try_inspect(original)?;
+    let issues = media_info.issues();
+    if !issues.is_empty() {
+        return Err(issues.join("; "));
+    }
+
+    for part in &files[1..] {
+        let part_info = try_inspect(part)?;
+        let issues = [part_info.issues(), media_info.concat_issues(&part_info)].concat();
+        if !issues.is_empty() {
+            return Err(format!("{part}: {}", issues.join("; ")));
+        }
+    }
+
+    let out_dir = format!("{out_dir}/{vid}");
+    let outputs = video_outputs(&out_dir, options);
+
+    let mut audio_outputs = vec![AudioOutput::stereo(format!("{out_dir}/aac_192k"))];
+    if let Some(audio) = media_info.find_audio_stream() {
+        audio_outputs.extend(AudioOutput::surround(
+            audio,
+            format!("{out_dir}/aac_384k_6ch"),
+        ));
+    }
+    let audio_outputs = audio_outputs
+        .into_iter()
+        .map(|output| {
+            output
+                .segment_type(options.segment_type)
+                .segment_duration(options.segment_duration)
+                .encoder(audio_encoder)
+        })
+        .collect::<Vec<_>>();
+
+    let input = Input::new(files, &out_dir);
+    let quality = Quality {
+        profile: options.profile,
+        tune: options.tune,
+    };
+
+    for pass in [Pass::First, Pass::Second] {
+        if pass == Pass::First && !outputs.iter().any(VideoOutput::needs_first_pass) {
+            continue;
+        }
+        transcode_video(&input, &media_info, pass, quality, &outputs, &audio_outputs)
+            .map_err(|err| err.to_string())?
+            .try_execute()
+            .map_err(|err| err.to_string())?;
+    }
+
+    match extract_poster(&input, &media_info, &format!("{out_dir}/poster.jpg")) {
+        Ok(cmd) => {
+            if let Err(err) = cmd.try_execute() {
+                eprintln!("Can't extract a poster from {original}: {err}");
+            }
+        }
+        Err(err) => eprintln!("Can't extract a poster from {original}: {err}"),
+    }
+
+    let layout = TileLayout::new(options.thumbnail_interval);
+    match extract_sprites(&input, &media_info, &out_dir, layout) {
+        Ok(cmd) => match cmd.try_execute() {
+            Ok(()) => {
+                let layout_json = serde_json::to_string_pretty(&layout).unwrap();
+                fs::write(format!("{out_dir}/sprites.json"), layout_json).unwrap();
+            }
+            Err(err) => eprintln!("Can't extract thumbnails from {original}: {err}"),
+        },
+        Err(err) => eprintln!("Can't extract thumbnails from {original}: {err}"),
+    }
+    Ok(())
+}
+
+/// Whether `vid` has every video rung and the stereo rendition encoded to
+/// the end, which the muxer only marks once the encode finishes.
+fn is_encoded(out_dir: &str, vid: u32, options: &EncodeOptions) -> bool {
+    let out_dir = format!("{out_dir}/{vid}");
+    let audio = AudioOutput::stereo(format!("{out_dir}/aac_192k"));
+    video_outputs(&out_dir, options)
+        .iter()
+        .map(VideoOutput::dir)
+        .chain([audio.dir.as_str()])
+        .all(|dir| {
+            fs::read_to_string(format!("{dir}/stream.m3u8"))
+                .is_ok_and(|playlist| playlist.contains("#EXT-X-ENDLIST"))
+        })
 }
 
 fn main() {
@@ -33,51 +284,216 @@ fn main() {
             original,
             out_dir,
             vid,
+            concat,
+            options,
+        } => {
+            let vid = vid.unwrap_or_else(|| extract_vid(&original));
+            let files = [vec![original.clone()], concat].concat();
+            if let Err(err) = encode(&files, vid, &out_dir, &options) {
+                eprintln!("Can't encode {original}: {err}");
+                std::process::exit(1);
+            }
+        }
+        Command::EncodeBatch {
+            dir,
+            out_dir,
+            limit,
+            skip_existing,
+            options,
+        } => {
+            let mut inputs = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>();
+            inputs.sort();
+
+            let (mut succeeded, mut skipped, mut failed) = (0, 0, 0);
+            for path in inputs {
+                if limit.is_some_and(|limit| succeeded + failed >= limit) {
+                    break;
+                }
+                let original = path.to_string_lossy().into_owned();
+                let Some(vid) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse::<u32>().ok())
+                else {
+                    println!("Skipped {original}, which isn't named by vid");
+                    skipped += 1;
+                    continue;
+                };
+                if skip_existing && is_encoded(&out_dir, vid, &options) {
+                    println!("Skipped {original}, already encoded");
+                    skipped += 1;
+                    continue;
+                }
+
+                match encode(std::slice::from_ref(&original), vid, &out_dir, &options) {
+                    Ok(()) => succeeded += 1,
+                    Err(err) => {
+                        eprintln!("Can't encode {original}: {err}");
+                        failed += 1;
+                    }
+                }
+            }
+
+            println!("{succeeded} encoded, {skipped} skipped, {failed} failed");
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::VerifyEncode { dir } => {
+            let reports = verify_encode(Path::new(&dir)).unwrap_or_else(|err| {
+                eprintln!("{err}");
+                std::process::exit(1);
+            });
+            for report in &reports {
+                print!("{report}");
+            }
+            let failed = reports.iter().filter(|report| !report.passed()).count();
+            println!("{} rungs, {failed} failed", reports.len());
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Package {
+            dir,
+            byte_range,
+            shared_init,
+            min_final_segment,
+            batch_probe,
+            packet_probe,
+            playlist_probe,
+            max_av_drift,
+            hash_bytes,
         } => {
-            let media_info = inspect(&original);
-            media_info.check();
-
-            let high_spec = VideoSpec {
-                width: 1920,
-                height: 1080,
-                bit_rate: 5000_000,
-                profile: Profile::High,
+            let config = get_config();
+            fs::create_dir_all(&config.packages_dir).unwrap();
+            let store = store::from_config(&config.store);
+            let mode = if shared_init {
+                PackagingMode::SharedInit
+            } else if byte_range {
+                PackagingMode::ByteRange
+            } else {
+                PackagingMode::Segmented
             };
-            let mid_spec = VideoSpec {
-                width: 1280,
-                height: 720,
-                bit_rate: 1500_000,
-                profile: Profile::High,
+            let probe = if batch_probe {
+                ProbeMode::Batched
+            } else if packet_probe {
+                ProbeMode::Packets
+            } else if playlist_probe {
+                ProbeMode::Playlist
+            } else {
+                ProbeMode::PerSegment
             };
-            let low_spec = VideoSpec {
-                width: 960,
-                height: 540,
-                bit_rate: 400_000,
-                profile: Profile::Main,
+            let options = PackageOptions {
+                mode,
+                probe,
+                max_av_drift,
+                hash_bytes: hash_bytes.into(),
+                min_final_segment,
             };
+            package(&dir, store.as_ref(), &config.packages_dir, &options);
+        }
+        Command::Crossfade {
+            from,
+            to,
+            out_file,
+            seconds,
+        } => {
+            let info = inspect(&from);
+            match render_crossfade(&from, &info, &to, seconds, &out_file) {
+                Ok(cmd) => cmd.execute(),
+                Err(err) => {
+                    eprintln!("Can't crossfade from {from}: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Inspect { file, json } => {
+            let media_info = try_inspect(&file).unwrap_or_else(|err| {
+                eprintln!("{err}");
+                std::process::exit(1);
+            });
+            if json {
+                println!("{}", serde_json::to_string_pretty(&media_info).unwrap());
+                return;
+            }
 
-            let vid = vid.unwrap_or_else(|| extract_vid(&original));
+            print!("{media_info}");
+            for issue in media_info.issues() {
+                println!("! {issue}");
+            }
+        }
+        Command::Diff { old, new, json } => {
+            let diff = diff(&Package::from_file(&old), &Package::from_file(&new));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+                return;
+            }
 
-            let out_dir = format!("{out_dir}/{vid}");
-            let outputs = [
-                high_spec.out_dir(&out_dir),
-                mid_spec.out_dir(&out_dir),
-                low_spec.out_dir(&out_dir),
-            ];
+            print!("{diff}");
+        }
+        Command::Schedule {
+            packages_dir,
+            ahead,
+            json,
+        } => {
+            let config = get_config();
+            let packages_dir = packages_dir.unwrap_or_else(|| config.packages_dir.clone());
+            let packages = Playlist::load_packages(&packages_dir, config);
+            if packages.is_empty() {
+                eprintln!("No packages in {packages_dir}");
+                std::process::exit(1);
+            }
 
-            let audio_dir = format!("{out_dir}/aac_192k");
+            // As the server plays the main channel.
+            let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages, config);
+            let now = Timestamp::now();
+            let airings = playlist.schedule().airings(now, now + ahead, config.speed);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&airings).unwrap());
+                return;
+            }
 
-            transcode_video(&original, &media_info, Pass::First, &outputs, &audio_dir).execute();
-            transcode_video(&original, &media_info, Pass::Second, &outputs, &audio_dir).execute();
+            println!("{:<22}{:<22}VID", "START", "END");
+            for airing in airings {
+                let round = |at: Timestamp| at.round(Unit::Second).unwrap();
+                println!(
+                    "{:<22}{:<22}{}",
+                    round(airing.start).to_string(),
+                    round(airing.end).to_string(),
+                    airing.vid
+                );
+            }
         }
-        Command::Package { dir } => {
-            fs::create_dir_all("segments").unwrap();
-            fs::create_dir_all("packages").unwrap();
-            package(&dir, "segments", "packages");
-        }
-        Command::Clean => {
-            let _ = std::fs::remove_dir_all("segments");
-            let _ = std::fs::remove_dir_all("packages");
+        Command::Clean { yes, dry_run } => {
+            if !yes && !dry_run {
+                eprintln!("Pass --yes to delete, or --dry-run to list what would be deleted");
+                std::process::exit(2);
+            }
+
+            let config = get_config();
+            let mut dirs = vec![(Path::new(&config.packages_dir), Output::Packages)];
+            match &config.store {
+                StoreConfig::Local { dir } => dirs.push((Path::new(dir), Output::Segments)),
+                StoreConfig::S3 { bucket, .. } => {
+                    println!("Leaving bucket {bucket} alone; only local segments are cleaned");
+                }
+            }
+
+            match clean(&dirs, dry_run) {
+                Ok(paths) => {
+                    let verb = if dry_run { "Would delete" } else { "Deleted" };
+                    for path in paths {
+                        println!("{verb} {}", path.display());
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
+            }
         }
     }
 }
@@ -0,0 +1,110 @@
+use std::collections::BTreeSet;
+
+use crate::config::Tools;
+
+/// What the configured ffmpeg build can do, so a missing encoder or filter
+/// is caught before an encode rather than part way through one.
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    encoders: BTreeSet<String>,
+    filters: BTreeSet<String>,
+}
+
+impl Capabilities {
+    pub fn probe(tools: &Tools) -> Self {
+        let list = |flag| {
+            let output = tools
+                .ffmpeg()
+                .args(["-hide_banner", flag])
+                .output()
+                .unwrap();
+            parse_list(&String::from_utf8_lossy(&output.stdout))
+        };
+        Self {
+            encoders: list("-encoders"),
+            filters: list("-filters"),
+        }
+    }
+
+    pub fn has_encoder(&self, name: &str) -> bool {
+        self.encoders.contains(name)
+    }
+
+    pub fn has_filter(&self, name: &str) -> bool {
+        self.filters.contains(name)
+    }
+
+    /// `preferred`, or ffmpeg's native AAC encoder if this build lacks it
+    /// (`aac_at` only exists in macOS builds).
+    pub fn audio_encoder<'a>(&self, preferred: &'a str) -> &'a str {
+        if self.has_encoder(preferred) {
+            return preferred;
+        }
+        eprintln!("WARNING: ffmpeg has no {preferred} encoder, falling back to aac");
+        "aac"
+    }
+}
+
+/// The names from `ffmpeg -encoders` or `-filters`: the second column of
+/// each entry, skipping the heading and the legend of flags.
+fn parse_list(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let (_flags, name) = (columns.next()?, columns.next()?);
+            (name != "=").then(|| name.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODERS: &str = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ .F.... = Frame-level multithreading
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ A....D aac                  AAC (Advanced Audio Coding)
+ A....D libopus              libopus Opus (codec opus)
+";
+
+    const FILTERS: &str = "\
+Filters:
+  T.. = Timeline support
+  | = Source or sink filter
+ ... = ...
+ T.C yadif             V->V       Deinterlace the input image.
+ ..C tile              V->V       Tile several successive frames together.
+";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            encoders: parse_list(ENCODERS),
+            filters: parse_list(FILTERS),
+        }
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.encoders.iter().collect::<Vec<_>>(),
+            ["aac", "libopus", "libx264"]
+        );
+        assert!(capabilities.has_filter("yadif"));
+        assert!(capabilities.has_filter("tile"));
+        assert!(!capabilities.has_filter("="));
+    }
+
+    #[test]
+    fn test_audio_encoder_fallback() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.audio_encoder("aac_at"), "aac");
+        assert_eq!(capabilities.audio_encoder("libopus"), "libopus");
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// What a directory given to [`clean`] is expected to hold, so one that
+/// holds anything else is left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// `playlist.txt`, `<vid>.json` (or a `<vid>.tmp` left by an
+    /// interrupted write) and the inspect cache.
+    Packages,
+    /// A `<vid>` directory per package, as [`crate::store::LocalStore`]
+    /// lays them out.
+    Segments,
+}
+
+impl Output {
+    fn expects(self, name: &str, is_dir: bool) -> bool {
+        let is_vid = |name: &str| name.parse::<u32>().is_ok();
+        match self {
+            Output::Packages => {
+                !is_dir
+                    && (matches!(
+                        name,
+                        "playlist.txt" | "inspect-cache.json" | "inspect-cache.tmp"
+                    ) || name.strip_suffix(".json").is_some_and(is_vid)
+                        || name.strip_suffix(".tmp").is_some_and(is_vid))
+            }
+            Output::Segments => is_dir && is_vid(name),
+        }
+    }
+}
+
+/// The entries of `dir` that [`clean`] would remove. Fails on anything
+/// frameserve doesn't write there, in case `dir` is the wrong directory.
+fn plan(dir: &Path, output: Output) -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("{}: {err}", dir.display())),
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("{}: {err}", dir.display()))?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+        if !name
+            .to_str()
+            .is_some_and(|name| output.expects(name, is_dir))
+        {
+            return Err(format!(
+                "{} doesn't look like frameserve output, refusing to clean {}",
+                path.display(),
+                dir.display()
+            ));
+        }
+        paths.push(path);
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Removes the packaged output in each of `dirs`, leaving the directories
+/// themselves, and returns what was removed. Nothing is removed unless
+/// every directory checks out, and nothing at all with `dry_run`.
+pub fn clean(dirs: &[(&Path, Output)], dry_run: bool) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for (dir, output) in dirs {
+        paths.extend(plan(dir, *output)?);
+    }
+    if dry_run {
+        return Ok(paths);
+    }
+
+    for path in &paths {
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        removed.map_err(|err| format!("{}: {err}", path.display()))?;
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packages and a segments directory, as packaging one video leaves
+    /// them.
+    fn output(name: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let packages = root.join("packages");
+        let segments = root.join("segments");
+        fs::create_dir_all(&packages).unwrap();
+        fs::create_dir_all(segments.join("19001085")).unwrap();
+        fs::write(packages.join("playlist.txt"), "19001085\n").unwrap();
+        fs::write(packages.join("19001085.json"), "{}").unwrap();
+        fs::write(segments.join("19001085/abc.mp4"), "").unwrap();
+        (packages, segments)
+    }
+
+    #[test]
+    fn test_dry_run_deletes_nothing() {
+        let (packages, segments) = output("frameserve-clean-dry-run");
+        let dirs = [
+            (packages.as_path(), Output::Packages),
+            (segments.as_path(), Output::Segments),
+        ];
+
+        let planned = clean(&dirs, true).unwrap();
+        assert_eq!(planned.len(), 3);
+        assert!(planned.iter().all(|path| path.exists()));
+
+        assert_eq!(clean(&dirs, false).unwrap(), planned);
+        assert!(planned.iter().all(|path| !path.exists()));
+        assert!(packages.exists() && segments.exists());
+        fs::remove_dir_all(packages.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_unexpected_files() {
+        let (packages, segments) = output("frameserve-clean-unexpected");
+        fs::write(packages.join("notes.txt"), "keep me").unwrap();
+        let dirs = [
+            (packages.as_path(), Output::Packages),
+            (segments.as_path(), Output::Segments),
+        ];
+
+        let err = clean(&dirs, false).unwrap_err();
+        assert!(err.contains("notes.txt"), "{err}");
+        assert!(packages.join("19001085.json").exists());
+        assert!(segments.join("19001085").exists());
+        fs::remove_dir_all(packages.parent().unwrap()).unwrap();
+    }
+}
@@ -1,37 +1,537 @@
-use std::sync::LazyLock;
+use std::{collections::BTreeMap, process::Command, sync::LazyLock};
 
 use figment::{
     Figment,
     providers::{Env, Format, Toml},
 };
+use jiff::Timestamp;
 use serde::Deserialize;
 
-static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    let config = Figment::new()
+use crate::utils::glob_match;
+
+fn figment() -> Figment {
+    Figment::new()
         .merge(Toml::file("fserve.toml"))
         .merge(Env::prefixed("FSERVE_"))
-        .extract::<Config>();
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(|| {
+    let config = figment().extract::<Config>();
     match config {
-        Ok(config) => config,
+        Ok(config) => {
+            if let Err(err) = config.validate() {
+                panic!("CONFIG ERROR: {err}");
+            }
+            config
+        }
         Err(err) => {
             panic!("CONFIG ERROR: {err}");
         }
     }
 });
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Config {
     pub bind_address: String,
     pub base: String,
     pub media_base: String,
     #[serde(default = "default_speed")]
     pub speed: u64,
+    #[serde(default)]
+    pub master_order: MasterOrder,
+    /// Emit segment and init URIs as paths under this server's `/media`
+    /// rather than `media_base`, for deployments where the playlists and
+    /// media share an origin.
+    #[serde(default)]
+    pub relative_segment_uris: bool,
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    #[serde(default = "default_media_timeout_secs")]
+    pub media_timeout_secs: u64,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub store: StoreConfig,
+    /// Where `package` writes package JSON, and the server reads it.
+    #[serde(default = "default_packages_dir")]
+    pub packages_dir: String,
+    /// Where the last-served sequence numbers are kept between restarts.
+    pub sequence_state: Option<String>,
+    /// Start with no packages, answering 503 until some are packaged,
+    /// rather than refusing to start.
+    #[serde(default)]
+    pub allow_empty: bool,
+    #[serde(default)]
+    pub packages: PackageFilter,
+    /// The order packages play in the loop.
+    #[serde(default)]
+    pub package_order: PackageOrder,
+    /// Advertise `CAN-SKIP-UNTIL` and answer `_HLS_skip=YES` with delta
+    /// playlists.
+    #[serde(default)]
+    pub delta_playlists: bool,
+    /// Advertise `HOLD-BACK`, how far behind the live edge players should
+    /// start.
+    #[serde(default)]
+    pub server_hold_back: bool,
+    /// How many seconds behind the live edge players start; the spec's
+    /// minimum of three target durations when unset.
+    pub hold_back_seconds: Option<f64>,
+    /// How many seconds of segments a variant playlist lists, for libraries
+    /// whose segment lengths vary; a fixed count of segments when unset.
+    /// Has to cover every stream's hold-back, three target durations unless
+    /// `server_hold_back` asks for more.
+    pub window_seconds: Option<u64>,
+    /// `EXT-X-TARGETDURATION` for video and audio streams, when the
+    /// segments were cut to a fixed length rather than the longest one
+    /// found. One must be a whole multiple of the other so their segment
+    /// boundaries line up.
+    pub target_durations: Option<TargetDurations>,
+    /// Accept operator-only query parameters, such as `source_offset` on
+    /// variant playlists.
+    #[serde(default)]
+    pub debug: bool,
+    /// Play packages back to back without `EXT-X-DISCONTINUITY`, for
+    /// libraries encoded alike with timestamps that carry on from one
+    /// package to the next. Only honoured when every stream's packages share
+    /// a time base and codecs, and each segment starts where the one before
+    /// ends; the loop starting over, and a takeover, are still marked.
+    #[serde(default)]
+    pub continuous: bool,
+    /// Serve variant playlists as `EXT-X-PLAYLIST-TYPE:EVENT`, listing
+    /// every segment since the playlist started rather than a sliding
+    /// window, for a channel that is only ever appended to. Each loop is
+    /// appended after the last, so only a channel with a recent `start` can
+    /// have them, and the event ends after about a day of segments.
+    /// Takeovers aren't listed.
+    #[serde(default)]
+    pub event_playlists: bool,
+    /// The vid of a transition that crossfades the end of the loop into its
+    /// start, rendered with `cli crossfade` then encoded and packaged like
+    /// any other. Its length is cut from the end of the last package and
+    /// the start of the first, and it plays in their place.
+    pub loop_crossfade: Option<u32>,
+    /// Send preview playlists, which list a whole package, as they're
+    /// rendered rather than all at once.
+    #[serde(default)]
+    pub stream_previews: bool,
+    /// Serve init segments, the targets of `EXT-X-MAP`, as immutable for a
+    /// year. They're named by their hash, so never change, yet players
+    /// fetch them again after every discontinuity.
+    #[serde(default)]
+    pub immutable_inits: bool,
+    /// Emit `EXT-X-BITRATE` for segments whose size is known.
+    #[serde(default)]
+    pub segment_bitrates: bool,
+    /// Which of `player_profiles` to render playlists for; the default
+    /// profile when unset.
+    pub player_profile: Option<String>,
+    #[serde(default)]
+    pub player_profiles: BTreeMap<String, PlayerProfile>,
+    /// The channel's audio renditions. Unset, it has a stereo one, plus 5.1
+    /// when every package has it.
+    pub audio_rungs: Option<Vec<AudioRung>>,
+    /// Bearer token for `POST /override`, which is disabled when unset.
+    pub override_token: Option<String>,
+    /// Further channels, each served under `/ch/<name>` alongside this one.
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+}
+
+/// A channel of its own, looping its own packages. Everything not set here
+/// is shared with the main channel.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelConfig {
+    pub name: String,
+    pub packages_dir: String,
+    /// When the loop began; the epoch when unset, as for the main channel.
+    pub start: Option<Timestamp>,
+    pub sequence_state: Option<String>,
+    /// The channel's audio renditions, as `audio_rungs`.
+    pub audio_rungs: Option<Vec<AudioRung>>,
+}
+
+/// One audio rendition of the channel.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AudioRung {
+    pub bitrate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u8,
+    #[serde(default = "default_language")]
+    pub language: String,
+    pub name: String,
+    /// Renditions in a group are alternatives to each other, e.g. languages
+    /// or channel layouts. Each group gets its own copy of every video
+    /// variant, so put other bitrates in another group.
+    #[serde(default = "default_audio_group")]
+    pub group: String,
+}
+
+impl AudioRung {
+    pub fn stereo() -> Self {
+        Self {
+            bitrate: 192000,
+            channels: 2,
+            language: default_language(),
+            name: "aac_192".to_string(),
+            group: default_audio_group(),
+        }
+    }
+
+    pub fn surround() -> Self {
+        Self {
+            bitrate: 384000,
+            channels: 6,
+            language: default_language(),
+            name: "surround".to_string(),
+            group: default_audio_group(),
+        }
+    }
+}
+
+/// Loaded apart from [`Config`], so encoding and packaging work without a
+/// server config.
+static TOOLS: LazyLock<Tools> = LazyLock::new(|| match figment().extract::<Tools>() {
+    Ok(tools) => tools,
+    Err(err) => panic!("CONFIG ERROR: {err}"),
+});
+
+/// The external programs run for encoding and probing. Set `ffmpeg` and
+/// `ffprobe` (or `FSERVE_FFMPEG` and `FSERVE_FFPROBE`) to pin a particular
+/// build rather than whichever is first on `PATH`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tools {
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+    #[serde(default = "default_ffprobe")]
+    pub ffprobe: String,
+    /// The AAC encoder, or `aac` when the ffmpeg build lacks it.
+    #[serde(default = "default_audio_encoder")]
+    pub audio_encoder: String,
+}
+
+impl Tools {
+    pub fn ffmpeg(&self) -> Command {
+        Command::new(&self.ffmpeg)
+    }
+
+    pub fn ffprobe(&self) -> Command {
+        Command::new(&self.ffprobe)
+    }
+}
+
+/// The HLS version and optional tags a class of players copes with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PlayerProfile {
+    pub version: u8,
+    /// `EXT-X-MAP`, for segments that need a separate init segment; only
+    /// TS packages can be played without it.
+    pub map: bool,
+    /// `EXT-X-BYTERANGE`, for byte-range packages.
+    pub byte_range: bool,
+    pub program_date_time: bool,
+    /// Decimal places in `EXTINF` durations; 0 for the integer durations
+    /// of versions before 3.
+    pub extinf_precision: usize,
+}
+
+impl Default for PlayerProfile {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl PlayerProfile {
+    const DEFAULT: Self = Self {
+        version: 7,
+        map: true,
+        byte_range: true,
+        program_date_time: false,
+        extinf_precision: 6,
+    };
+
+    /// Checks that `version` is high enough for every tag this profile emits.
+    pub fn validate(&self) -> Result<(), String> {
+        let required = [
+            (self.map, 6, "EXT-X-MAP"),
+            (self.byte_range, 4, "EXT-X-BYTERANGE"),
+            (self.extinf_precision > 0, 3, "Decimal EXTINF"),
+        ];
+        for (enabled, min_version, tag) in required {
+            if enabled && self.version < min_version {
+                return Err(format!(
+                    "{tag} needs EXT-X-VERSION {min_version} or later, but the profile is version {}",
+                    self.version
+                ));
+            }
+        }
+        if self.extinf_precision > 9 {
+            return Err(format!(
+                "extinf_precision of {} is finer than a nanosecond",
+                self.extinf_precision
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TargetDurations {
+    pub video: u64,
+    pub audio: u64,
+}
+
+impl TargetDurations {
+    pub fn validate(&self) -> Result<(), String> {
+        let Self { video, audio } = *self;
+        if video == 0 || audio == 0 {
+            return Err("target durations must be at least a second".to_string());
+        }
+        if video.max(audio) % video.min(audio) != 0 {
+            return Err(format!(
+                "target durations of {video}s for video and {audio}s for audio aren't multiples of one another"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Restricts which vids from `playlist.txt` are loaded, e.g. to stage a new
+/// video on one server. Entries are vids or `*` globs.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct PackageFilter {
+    /// When set, only matching vids are loaded.
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl PackageFilter {
+    pub fn allows(&self, vid: u32) -> bool {
+        let vid = vid.to_string();
+        let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, &vid));
+
+        let allowed = self.allow.as_deref().is_none_or(matches);
+        allowed && !matches(&self.deny)
+    }
+}
+
+/// Backend for packaged segments, see [`crate::store`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum StoreConfig {
+    Local {
+        dir: String,
+    },
+    S3 {
+        bucket: String,
+        endpoint: Option<String>,
+        public_base: String,
+    },
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Local {
+            dir: "segments".to_string(),
+        }
+    }
+}
+
+/// How packages are ordered in the loop.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageOrder {
+    /// As `playlist.txt` lists them.
+    #[default]
+    Listed,
+    Vid,
+    /// Oldest first, so newly packaged videos join the end of the loop.
+    PackagedAt,
+}
+
+/// How rungs are ordered in the master playlist.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MasterOrder {
+    /// Streams in ladder order, as configured.
+    #[default]
+    Ladder,
+    /// Apple's authoring guidelines: audio renditions first, then video
+    /// rungs from lowest to highest bandwidth.
+    Apple,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.player_profile
+            && !self.player_profiles.contains_key(name)
+        {
+            return Err(format!("unknown player profile {name:?}"));
+        }
+        for (name, profile) in &self.player_profiles {
+            profile
+                .validate()
+                .map_err(|err| format!("player profile {name:?}: {err}"))?;
+        }
+        if let Some(hold_back) = self.hold_back_seconds
+            && (hold_back.is_nan() || hold_back <= 0.0)
+        {
+            return Err(format!("hold_back_seconds of {hold_back}s is not positive"));
+        }
+        if self.window_seconds == Some(0) {
+            return Err("window_seconds must be at least a second".to_string());
+        }
+        if let Some(target_durations) = &self.target_durations {
+            target_durations.validate()?;
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if channel.name.is_empty() || !channel.name.chars().all(valid) {
+                return Err(format!("channel name {:?} isn't URL-safe", channel.name));
+            }
+            if self.channels[..i]
+                .iter()
+                .any(|other| other.name == channel.name)
+            {
+                return Err(format!("channel {:?} appears twice", channel.name));
+            }
+            self.for_channel(channel)
+                .validate()
+                .map_err(|err| format!("channel {:?}: {err}", channel.name))?;
+        }
+        if let Some(rungs) = &self.audio_rungs {
+            if rungs.is_empty() {
+                return Err("audio_rungs is empty".to_string());
+            }
+            for (i, rung) in rungs.iter().enumerate() {
+                if rungs[..i]
+                    .iter()
+                    .any(|other| (&other.group, &other.name) == (&rung.group, &rung.name))
+                {
+                    return Err(format!(
+                        "audio rung {:?} appears twice in group {:?}",
+                        rung.name, rung.group
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// This config as `channel` sees it, with its URIs under `/ch/<name>`.
+    pub fn for_channel(&self, channel: &ChannelConfig) -> Config {
+        let mut config = self.clone();
+        config.base = format!("{}/ch/{}", self.base, channel.name);
+        config.packages_dir = channel.packages_dir.clone();
+        config.sequence_state = channel.sequence_state.clone();
+        if channel.audio_rungs.is_some() {
+            config.audio_rungs = channel.audio_rungs.clone();
+        }
+        config.channels = Vec::new();
+        config
+    }
+
+    /// The player profile playlists are rendered for.
+    pub fn player(&self) -> &PlayerProfile {
+        match &self.player_profile {
+            Some(name) => &self.player_profiles[name],
+            None => &PlayerProfile::DEFAULT,
+        }
+    }
+
+    pub fn segment_base(&self) -> &str {
+        if self.relative_segment_uris {
+            "/media"
+        } else {
+            &self.media_base
+        }
+    }
+}
+
+fn default_ffmpeg() -> String {
+    "ffmpeg".to_string()
+}
+
+fn default_ffprobe() -> String {
+    "ffprobe".to_string()
+}
+
+fn default_audio_encoder() -> String {
+    "aac_at".to_string()
+}
+
+fn default_channels() -> u8 {
+    2
+}
+
+fn default_language() -> String {
+    "en".to_string()
+}
+
+fn default_audio_group() -> String {
+    "audio".to_string()
+}
+
+fn default_packages_dir() -> String {
+    "packages".to_string()
 }
 
 fn default_speed() -> u64 {
     1
 }
 
+fn default_request_timeout_secs() -> u64 {
+    10
+}
+
+fn default_media_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_body_bytes() -> usize {
+    16 * 1024
+}
+
 pub fn get_config() -> &'static Config {
     &*CONFIG
 }
+
+pub fn get_tools() -> &'static Tools {
+    &TOOLS
+}
+
+/// Writes `script` to `dir/name` as an executable stand-in for ffmpeg or
+/// ffprobe. A shell writes it rather than this process, where another
+/// test's spawn could inherit the open file and have running it fail as
+/// busy (ETXTBSY).
+#[cfg(all(test, unix))]
+pub(crate) fn test_tool(dir: &std::path::Path, name: &str, script: &str) -> String {
+    let path = dir.join(name);
+    let status = Command::new("sh")
+        .args(["-c", r#"printf '%s' "$1" > "$0" && chmod 755 "$0""#])
+        .arg(&path)
+        .arg(script)
+        .status()
+        .unwrap();
+    assert!(status.success(), "couldn't write {}", path.display());
+    path.to_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn test_config(overrides: &str) -> &'static Config {
+    let config = Figment::new()
+        .merge(Toml::string(
+            r#"
+            bind_address = "127.0.0.1:0"
+            base = "http://localhost"
+            media_base = "http://localhost/media"
+            "#,
+        ))
+        .merge(Toml::string(overrides))
+        .extract::<Config>()
+        .unwrap();
+    config.validate().unwrap();
+    Box::leak(Box::new(config))
+}
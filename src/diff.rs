@@ -0,0 +1,355 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+use crate::package::{MeasuredBitrate, Package, RemoteResource, Variant, VariantKind};
+
+/// What changed between two packagings of a video, such as before and after
+/// a re-encode.
+#[derive(Debug, Serialize)]
+pub struct PackageDiff {
+    pub variants: Vec<VariantDiff>,
+    /// Files the new package refers to that the old one doesn't, and so
+    /// must be uploaded before it is served.
+    pub upload: Vec<RemoteResource>,
+}
+
+/// One variant on either side, matched by [`variant_label`]. `old` or `new`
+/// is unset when the variant was added or removed.
+#[derive(Debug, Serialize)]
+pub struct VariantDiff {
+    pub label: String,
+    pub old: Option<VariantSummary>,
+    pub new: Option<VariantSummary>,
+    /// Indices of the new variant's segments whose file differs from the
+    /// old variant's segment at the same position.
+    pub changed_segments: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VariantSummary {
+    pub bitrate: u32,
+    pub segments: usize,
+    pub seconds: f64,
+    /// See [`Variant::measured_bitrate`].
+    pub measured: Option<MeasuredBitrate>,
+}
+
+impl VariantSummary {
+    fn new(variant: &Variant) -> Self {
+        Self {
+            bitrate: variant.bitrate,
+            segments: variant.segments.len(),
+            seconds: variant.seconds(),
+            measured: variant.measured_bitrate(),
+        }
+    }
+
+    /// The requested bitrate, and the measured peak where it's known.
+    fn kbps(&self) -> String {
+        match self.measured {
+            Some(measured) => format!(
+                "{}kbps (peak {}kbps)",
+                self.bitrate / 1000,
+                measured.peak / 1000
+            ),
+            None => format!("{}kbps", self.bitrate / 1000),
+        }
+    }
+}
+
+impl VariantDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.old == self.new && self.changed_segments.is_empty()
+    }
+}
+
+/// Names a variant by what it carries rather than its bitrate, which a
+/// re-encode may change. Repeats get a `#n` suffix in bitrate order.
+fn variant_label(variant: &Variant) -> String {
+    match variant.kind {
+        VariantKind::Video { width, height } => format!("{width}x{height}"),
+        VariantKind::Audio => match &variant.language {
+            Some(language) => format!("audio {}ch {language}", variant.channels()),
+            None => format!("audio {}ch", variant.channels()),
+        },
+    }
+}
+
+fn labelled(package: &Package) -> Vec<(String, &Variant)> {
+    let mut variants = package.variants.iter().collect::<Vec<_>>();
+    variants.sort_by_key(|variant| std::cmp::Reverse(variant.bitrate));
+
+    let mut labelled = Vec::<(String, &Variant)>::new();
+    for variant in variants {
+        let base = variant_label(variant);
+        let repeats = labelled
+            .iter()
+            .filter(|(_, other)| variant_label(other) == base)
+            .count();
+        let label = match repeats {
+            0 => base,
+            n => format!("{base} #{}", n + 1),
+        };
+        labelled.push((label, variant));
+    }
+    labelled
+}
+
+/// Every file a package refers to, once each: its variants' and the
+/// poster and thumbnails.
+fn resources(package: &Package) -> Vec<&RemoteResource> {
+    let mut resources = Vec::new();
+    let variants = package.variants.iter().flat_map(|variant| {
+        variant
+            .init_src
+            .iter()
+            .chain(variant.segments.iter().map(|segment| &segment.src))
+    });
+    let thumbnails = package
+        .thumbnails
+        .iter()
+        .flat_map(|thumbnails| thumbnails.sheets.iter().chain([&thumbnails.vtt]));
+    for resource in variants.chain(&package.poster).chain(thumbnails) {
+        if !resources.contains(&resource) {
+            resources.push(resource);
+        }
+    }
+    resources
+}
+
+pub fn diff(old: &Package, new: &Package) -> PackageDiff {
+    let old_variants = labelled(old);
+    let new_variants = labelled(new);
+
+    let mut variants = Vec::new();
+    for (label, new_variant) in &new_variants {
+        let old_variant = old_variants
+            .iter()
+            .find(|(old_label, _)| old_label == label)
+            .map(|(_, variant)| *variant);
+        let changed_segments = new_variant
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|(i, segment)| {
+                old_variant
+                    .and_then(|old_variant| old_variant.segments.get(*i))
+                    .is_none_or(|old_segment| {
+                        (&old_segment.src, old_segment.range) != (&segment.src, segment.range)
+                    })
+            })
+            .map(|(i, _)| i)
+            .collect();
+        variants.push(VariantDiff {
+            label: label.clone(),
+            old: old_variant.map(VariantSummary::new),
+            new: Some(VariantSummary::new(new_variant)),
+            changed_segments,
+        });
+    }
+    for (label, old_variant) in &old_variants {
+        if !new_variants.iter().any(|(new_label, _)| new_label == label) {
+            variants.push(VariantDiff {
+                label: label.clone(),
+                old: Some(VariantSummary::new(old_variant)),
+                new: None,
+                changed_segments: Vec::new(),
+            });
+        }
+    }
+
+    let old_resources = resources(old);
+    let upload = resources(new)
+        .into_iter()
+        .filter(|resource| !old_resources.contains(resource))
+        .cloned()
+        .collect();
+
+    PackageDiff { variants, upload }
+}
+
+/// One line per variant, then the upload count.
+impl Display for PackageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for variant in &self.variants {
+            match (&variant.old, &variant.new) {
+                (Some(old), Some(new)) => {
+                    write!(f, "  {:<16}", variant.label)?;
+                    if variant.is_unchanged() {
+                        writeln!(f, "unchanged")?;
+                        continue;
+                    }
+                    writeln!(
+                        f,
+                        "{} -> {} segments, {:.3}s -> {:.3}s, {} -> {}, {} segments changed",
+                        old.segments,
+                        new.segments,
+                        old.seconds,
+                        new.seconds,
+                        old.kbps(),
+                        new.kbps(),
+                        variant.changed_segments.len()
+                    )?;
+                }
+                (None, Some(summary)) | (Some(summary), None) => {
+                    let sign = if variant.new.is_some() { '+' } else { '-' };
+                    writeln!(
+                        f,
+                        "{sign} {:<16}{} segments, {:.3}s, {}",
+                        variant.label,
+                        summary.segments,
+                        summary.seconds,
+                        summary.kbps()
+                    )?;
+                }
+                (None, None) => {}
+            }
+        }
+        writeln!(f, "{} files to upload", self.upload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+    use num::rational::Ratio;
+
+    use super::*;
+    use crate::{
+        package::{Segment, Thumbnails},
+        recipe::TileLayout,
+    };
+
+    fn variant(kind: VariantKind, bitrate: u32, srcs: &[&str]) -> Variant {
+        Variant {
+            init_src: Some(RemoteResource(format!("init{bitrate}.mp4"))),
+            init_range: None,
+            time_base: Ratio::new(1, 1000),
+            bitrate,
+            kind,
+            channels: (kind == VariantKind::Audio).then_some(2),
+            language: None,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs: None,
+            segments: srcs
+                .iter()
+                .enumerate()
+                .map(|(i, src)| Segment {
+                    src: RemoteResource(src.to_string()),
+                    start: i as u64 * 10000,
+                    duration: 10000,
+                    range: None,
+                    size: None,
+                    keyframe: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn package(variants: Vec<Variant>) -> Package {
+        Package {
+            vid: 1,
+            packaged_at: Timestamp::UNIX_EPOCH,
+            variants,
+            poster: None,
+            thumbnails: None,
+            repeats: None,
+        }
+    }
+
+    const HD: VariantKind = VariantKind::Video {
+        width: 1920,
+        height: 1080,
+    };
+
+    #[test]
+    fn test_diff() {
+        let old = package(vec![
+            variant(HD, 5000000, &["a.mp4", "b.mp4"]),
+            variant(VariantKind::Audio, 192000, &["x.mp4", "y.mp4"]),
+        ]);
+        let mut surround = variant(VariantKind::Audio, 384000, &["s.mp4", "t.mp4"]);
+        surround.channels = Some(6);
+        surround.segments[0].size = Some(480000);
+        surround.segments[1].size = Some(500000);
+        let new = package(vec![
+            variant(HD, 4500000, &["a.mp4", "c.mp4", "d.mp4"]),
+            variant(VariantKind::Audio, 192000, &["x.mp4", "y.mp4"]),
+            surround,
+        ]);
+
+        let diff = diff(&old, &new);
+        let labels = diff
+            .variants
+            .iter()
+            .map(|variant| variant.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, ["1920x1080", "audio 6ch", "audio 2ch"]);
+        assert_eq!(diff.variants[0].changed_segments, [1, 2]);
+        assert_eq!(diff.variants[0].old.as_ref().unwrap().bitrate, 5000000);
+        assert!(diff.variants[1].old.is_none());
+        let measured = diff.variants[1].new.as_ref().unwrap().measured.unwrap();
+        assert_eq!((measured.average, measured.peak), (392000, 400000));
+        assert!(diff.variants[2].is_unchanged());
+
+        let upload = diff
+            .upload
+            .iter()
+            .map(|resource| resource.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            upload,
+            [
+                "init4500000.mp4",
+                "c.mp4",
+                "d.mp4",
+                "init384000.mp4",
+                "s.mp4",
+                "t.mp4"
+            ]
+        );
+
+        let text = diff.to_string();
+        assert!(text.contains("  1920x1080       2 -> 3 segments, 20.000s -> 30.000s, 5000kbps -> 4500kbps, 2 segments changed\n"));
+        assert!(text.contains("+ audio 6ch       2 segments, 20.000s, 384kbps (peak 400kbps)\n"));
+        assert!(text.contains("  audio 2ch       unchanged\n"));
+        assert!(text.ends_with("6 files to upload\n"));
+    }
+
+    #[test]
+    fn test_removed_and_repeated_variants() {
+        let old = package(vec![
+            variant(VariantKind::Audio, 192000, &["x.mp4"]),
+            variant(VariantKind::Audio, 64000, &["l.mp4"]),
+        ]);
+        let new = package(vec![variant(VariantKind::Audio, 192000, &["x.mp4"])]);
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.variants[1].label, "audio 2ch #2");
+        assert!(diff.variants[1].new.is_none());
+        assert!(diff.upload.is_empty());
+        assert!(diff.to_string().contains("- audio 2ch #2    1 segments"));
+    }
+
+    #[test]
+    fn test_poster_and_thumbnails_to_upload() {
+        let old = package(vec![variant(HD, 5000000, &["a.mp4"])]);
+        let mut new = package(vec![variant(HD, 5000000, &["a.mp4"])]);
+        new.poster = Some(RemoteResource("poster.jpg".to_string()));
+        new.thumbnails = Some(Thumbnails {
+            layout: TileLayout::new(5),
+            bandwidth: 20000,
+            sheets: vec![RemoteResource("sheet0.jpg".to_string())],
+            vtt: RemoteResource("thumbs.vtt".to_string()),
+        });
+
+        let upload = diff(&old, &new)
+            .upload
+            .iter()
+            .map(|resource| resource.0.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(upload, ["poster.jpg", "sheet0.jpg", "thumbs.vtt"]);
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use num::{Integer, integer::lcm, rational::Ratio};
 use serde::Serialize;
 
@@ -14,9 +16,28 @@ impl StepSize {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct Duration(u64);
 
+impl fmt::Debug for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Duration({} steps)", self.0)
+    }
+}
+
+/// Shows a [`Duration`] in seconds, see [`Duration::display`].
+pub struct DurationDisplay {
+    duration: Duration,
+    step_size: StepSize,
+}
+
+impl fmt::Display for DurationDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.duration.to_seconds(self.step_size);
+        write!(f, "{seconds:.3}s ({} steps)", self.duration.0)
+    }
+}
+
 impl Duration {
     pub fn new(duration_in_time_base: u64, time_base: Ratio<u32>, step_size: StepSize) -> Self {
         let duration_in_steps = duration_in_time_base * (step_size.0 / *time_base.denom() as u64);
@@ -35,6 +56,24 @@ impl Duration {
         (self.0 as f64) / (step_size.0 as f64)
     }
 
+    /// The nearest step to `seconds`.
+    pub fn from_seconds(seconds: f64, step_size: StepSize) -> Self {
+        Self((seconds * step_size.0 as f64).round() as u64)
+    }
+
+    /// A duration has no idea of its own step size, so it can only be shown
+    /// in seconds given one.
+    pub fn display(self, step_size: StepSize) -> DurationDisplay {
+        DurationDisplay {
+            duration: self,
+            step_size,
+        }
+    }
+
+    pub fn describe(self, step_size: StepSize) -> String {
+        self.display(step_size).to_string()
+    }
+
     #[must_use]
     pub fn modulo(self, base: Duration) -> (u64, Duration) {
         let (quotient, remainder) = self.0.div_mod_floor(&base.0);
@@ -95,4 +134,12 @@ mod tests {
         let c = Duration::new(36, Ratio::new(1, 48), step_size);
         assert_eq!(sum(&[a, b, c], step_size), 1.5);
     }
+
+    #[test]
+    fn test_duration_formatting() {
+        let step_size = StepSize::calculate([Ratio::new(1, 24), Ratio::new(1, 48)].into_iter());
+        let duration = Duration::new(60, Ratio::new(1, 24), step_size);
+        assert_eq!(duration.describe(step_size), "2.500s (120 steps)");
+        assert_eq!(format!("{duration:?}"), "Duration(120 steps)");
+    }
 }
@@ -1,149 +1,688 @@
-use num::rational::Ratio;
-use serde::{Deserialize, Deserializer, Serialize};
-use std::fmt::Display;
+use num::{ToPrimitive, rational::Ratio};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display};
 use std::fs::File;
 use std::io;
 use std::io::Read;
-use std::process::{Command, Stdio};
+use std::process::Stdio;
 use std::str::FromStr;
 
+use crate::config::{Tools, get_tools};
+
 pub fn inspect(input: &str) -> Info {
-    let output = Command::new("ffprobe")
+    inspect_with(get_tools(), input)
+}
+
+pub fn inspect_with(tools: &Tools, input: &str) -> Info {
+    try_inspect_with(tools, input).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Like [`inspect`], but an error rather than a panic when ffprobe can't be
+/// run or makes nothing of `input`.
+pub fn try_inspect(input: &str) -> Result<Info, String> {
+    try_inspect_with(get_tools(), input)
+}
+
+fn try_inspect_with(tools: &Tools, input: &str) -> Result<Info, String> {
+    let output = tools
+        .ffprobe()
         .args([
             "-v",
             "quiet",
             "-print_format",
             "json",
             "-show_streams",
+            "-show_format",
             input,
         ])
         .output()
-        .unwrap();
-    serde_json::from_slice(&output.stdout).unwrap()
+        .map_err(|err| format!("can't run ffprobe on {input}: {err}"))?;
+    let mut info: Info = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("ffprobe made nothing of {input}: {err}"))?;
+    info.fill_missing_timestamps();
+    info.estimate_bit_rates();
+    Ok(info)
 }
 
 pub fn combine_inspect(header: &str, segment: &str) -> Info {
-    let mut cmd = Command::new("ffprobe")
+    try_combine_inspect(header, segment).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Like [`combine_inspect`], but an error rather than a panic when either
+/// file can't be read or ffprobe can't be run or makes nothing of them.
+pub fn try_combine_inspect(header: &str, segment: &str) -> Result<Info, String> {
+    let header_file = File::open(header).map_err(|err| format!("{header}: {err}"))?;
+    let segment_file = File::open(segment).map_err(|err| format!("{segment}: {err}"))?;
+
+    let mut cmd = get_tools()
+        .ffprobe()
         .args(["-v", "quiet", "-print_format", "json", "-show_streams", "-"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
-        .unwrap();
+        .map_err(|err| format!("can't run ffprobe on {segment}: {err}"))?;
 
     let mut stdin = cmd.stdin.as_mut().unwrap();
+    let mut handle = header_file.chain(segment_file);
+    let _ = io::copy(&mut handle, &mut stdin);
 
-    let header = File::open(header).unwrap();
-    let segment = File::open(segment).unwrap();
-    let mut handle = header.chain(segment);
+    let output = cmd
+        .wait_with_output()
+        .map_err(|err| format!("ffprobe on {segment} failed: {err}"))?;
+    let mut info: Info = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("ffprobe made nothing of {segment}: {err}"))?;
+    info.fill_missing_timestamps();
+    Ok(info)
+}
 
-    let _ = io::copy(&mut handle, &mut stdin);
+/// Probes the packets of `stream` (e.g. `v:0`) across all `segments` in a
+/// single ffprobe run, with `header` (the init segment, for fMP4) sent once
+/// up front. Packet positions are byte offsets into that concatenation.
+pub fn inspect_packets(header: Option<&str>, segments: &[&str], stream: &str) -> Vec<Packet> {
+    let mut cmd = get_tools()
+        .ffprobe()
+        .args([
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-select_streams",
+            stream,
+            "-show_entries",
+            "packet=pts,duration,pos,flags",
+            "-",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Fed from another thread: ffprobe's output for a whole variant easily
+    // fills the stdout pipe before it has read all of its input.
+    let mut stdin = cmd.stdin.take().unwrap();
+    let files = header
+        .into_iter()
+        .chain(segments.iter().copied())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let writer = std::thread::spawn(move || {
+        for file in files {
+            io::copy(&mut File::open(file)?, &mut stdin)?;
+        }
+        io::Result::Ok(())
+    });
 
     let output = cmd.wait_with_output().unwrap();
-    serde_json::from_slice(&output.stdout).unwrap()
+    let _ = writer.join().unwrap();
+
+    #[derive(Deserialize)]
+    struct Packets {
+        packets: Vec<Packet>,
+    }
+    serde_json::from_slice::<Packets>(&output.stdout)
+        .unwrap()
+        .packets
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Packet {
+    #[serde(default)]
+    pub pts: Option<u64>,
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub pos: Option<u64>,
+    /// e.g. `K__` for a keyframe.
+    #[serde(default)]
+    pub flags: Option<String>,
+}
+
+impl Packet {
+    pub fn is_keyframe(&self) -> bool {
+        self.flags
+            .as_deref()
+            .is_some_and(|flags| flags.contains('K'))
+    }
+}
+
+/// Whether the first video frame of `segment` is a keyframe, i.e. whether
+/// the segment can be decoded without the one before it. `header` is the
+/// init segment, for fMP4.
+pub fn starts_with_keyframe(header: Option<&str>, segment: &str) -> bool {
+    let mut cmd = get_tools()
+        .ffprobe()
+        .args([
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-select_streams",
+            "v:0",
+            "-read_intervals",
+            "%+#1",
+            "-show_entries",
+            "frame=key_frame",
+            "-",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = cmd.stdin.take().unwrap();
+    for file in header.into_iter().chain([segment]) {
+        // ffprobe stops reading after the first frame, closing the pipe.
+        if io::copy(&mut File::open(file).unwrap(), &mut stdin).is_err() {
+            break;
+        }
+    }
+    drop(stdin);
+
+    #[derive(Deserialize)]
+    struct Frame {
+        key_frame: u8,
+    }
+    #[derive(Deserialize)]
+    struct Frames {
+        frames: Vec<Frame>,
+    }
+    let output = cmd.wait_with_output().unwrap();
+    serde_json::from_slice::<Frames>(&output.stdout)
+        .unwrap()
+        .frames
+        .first()
+        .is_some_and(|frame| frame.key_frame == 1)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Info {
     pub streams: Vec<StreamInfo>,
+    /// Only present for `-show_format` probes.
+    #[serde(default)]
+    pub format: Option<FormatInfo>,
+}
+
+/// Container-level details, used where a stream doesn't report its own.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FormatInfo {
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub bit_rate: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub size: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub duration: Option<f64>,
+}
+
+impl FormatInfo {
+    fn bit_rate(&self) -> Option<u32> {
+        self.bit_rate.or_else(|| {
+            let (size, duration) = (self.size?, self.duration?);
+            (duration > 0.0).then(|| (size as f64 * 8.0 / duration) as u32)
+        })
+    }
 }
 
 impl Info {
+    /// Some containers (notably MKV and WebM) don't record per-stream
+    /// bitrates. When only one stream is missing one, it is given whatever
+    /// the container's bitrate leaves after the others.
+    pub fn estimate_bit_rates(&mut self) {
+        let Some(total) = self.format.as_ref().and_then(FormatInfo::bit_rate) else {
+            return;
+        };
+
+        let mut known = 0;
+        let mut missing = Vec::new();
+        let media = self
+            .streams
+            .iter_mut()
+            .filter(|stream| !stream.is_attached_picture());
+        for stream in media {
+            match &mut stream.kind {
+                StreamKind::Video(VideoStreamInfo { bit_rate, .. })
+                | StreamKind::Audio(AudioStreamInfo { bit_rate, .. }) => match bit_rate {
+                    Some(rate) => known += *rate,
+                    None => missing.push(bit_rate),
+                },
+                StreamKind::Data => {}
+            }
+        }
+
+        if let [bit_rate] = missing.as_mut_slice() {
+            **bit_rate = Some(total.saturating_sub(known)).filter(|rate| *rate > 0);
+        }
+    }
+
+    /// Some containers and ffprobe versions give a stream's start and
+    /// length only in seconds. Where the ticks are missing (or zero), they
+    /// are worked out from those in the stream's time base.
+    pub fn fill_missing_timestamps(&mut self) {
+        for stream in &mut self.streams {
+            let (start_pts, duration_ts, start_time, duration, time_base) = match &mut stream.kind {
+                StreamKind::Video(v) => (
+                    &mut v.start_pts,
+                    &mut v.duration_ts,
+                    v.start_time,
+                    v.duration,
+                    v.time_base,
+                ),
+                StreamKind::Audio(a) => (
+                    &mut a.start_pts,
+                    &mut a.duration_ts,
+                    a.start_time,
+                    a.duration,
+                    a.time_base,
+                ),
+                StreamKind::Data => continue,
+            };
+            let Some(time_base) = time_base.to_f64().filter(|base| *base > 0.0) else {
+                continue;
+            };
+            let ticks = |seconds: Option<f64>| {
+                seconds
+                    .filter(|seconds| *seconds > 0.0)
+                    .map(|seconds| (seconds / time_base).round() as u64)
+            };
+            if *start_pts == 0
+                && let Some(ticks) = ticks(start_time)
+            {
+                *start_pts = ticks;
+            }
+            if *duration_ts == 0
+                && let Some(ticks) = ticks(duration)
+            {
+                *duration_ts = ticks;
+            }
+        }
+    }
+
     pub fn check(&self) {
-        for stream in &self.streams {
-            stream.check();
+        let issues = self.issues();
+        assert!(issues.is_empty(), "{}", issues.join("; "));
+    }
+
+    /// Everything [`Self::check`] would reject, without panicking.
+    pub fn issues(&self) -> Vec<String> {
+        let mut issues = self
+            .media_streams()
+            .flat_map(|stream| {
+                stream
+                    .issues()
+                    .into_iter()
+                    .map(|issue| format!("stream {}: {issue}", stream.index))
+            })
+            .collect::<Vec<_>>();
+
+        // Picking one of several would be a guess at which is the programme.
+        let videos = self
+            .media_streams()
+            .filter(|stream| matches!(stream.kind, StreamKind::Video(_)))
+            .map(|stream| stream.index.to_string())
+            .collect::<Vec<_>>();
+        if videos.len() > 1 {
+            issues.push(format!(
+                "{} video streams ({}), expected one",
+                videos.len(),
+                videos.join(", ")
+            ));
         }
+        issues
     }
 
-    pub fn video_stream(&self) -> &VideoStreamInfo {
-        for stream in &self.streams {
-            if let StreamKind::Video(video) = &stream.kind {
-                return video;
+    /// The audio and video streams, leaving out cover art and the like.
+    pub fn media_streams(&self) -> impl Iterator<Item = &StreamInfo> {
+        self.streams.iter().filter(|stream| {
+            !stream.is_attached_picture() && !matches!(stream.kind, StreamKind::Data)
+        })
+    }
+
+    /// Panics if `other` can't be joined onto this file by the concat
+    /// demuxer without re-normalising its streams.
+    pub fn check_concat_compatible(&self, other: &Info) {
+        let issues = self.concat_issues(other);
+        assert!(issues.is_empty(), "{}", issues.join("; "));
+    }
+
+    /// Everything [`Self::check_concat_compatible`] would reject, without
+    /// panicking.
+    pub fn concat_issues(&self, other: &Info) -> Vec<String> {
+        let mut issues = Vec::new();
+        let mut differ = |same: bool, what: &str| {
+            if !same {
+                issues.push(format!("concat inputs differ in {what}"));
+            }
+        };
+
+        match (self.find_video_stream(), other.find_video_stream()) {
+            (Some(a), Some(b)) => {
+                differ(a.codec == b.codec, "video codec");
+                differ((a.width, a.height) == (b.width, b.height), "resolution");
+                differ(a.pix_fmt == b.pix_fmt, "pixel format");
+                differ(a.avg_frame_rate == b.avg_frame_rate, "frame rate");
+                differ(a.time_base == b.time_base, "time base");
             }
+            (a, b) => differ(a.is_some() == b.is_some(), "whether they have video"),
         }
-        panic!("no video stream found");
+
+        match (self.find_audio_stream(), other.find_audio_stream()) {
+            (Some(a), Some(b)) => {
+                differ(a.codec_name == b.codec_name, "audio codec");
+                differ(
+                    (a.sample_rate, a.channels) == (b.sample_rate, b.channels),
+                    "audio layout",
+                );
+            }
+            (a, b) => differ(a.is_some() == b.is_some(), "whether they have audio"),
+        }
+        issues
+    }
+
+    /// Panics unless there is exactly one video stream; see [`Self::issues`].
+    pub fn video_stream(&self) -> &VideoStreamInfo {
+        let count = self.video_streams().count();
+        assert!(count <= 1, "{count} video streams found, expected one");
+        self.find_video_stream().expect("no video stream found")
     }
 
     pub fn audio_stream(&self) -> &AudioStreamInfo {
+        self.find_audio_stream().expect("no audio stream found")
+    }
+
+    /// The first video stream, warning if it isn't the only one.
+    pub fn find_video_stream(&self) -> Option<&VideoStreamInfo> {
+        let count = self.video_streams().count();
+        if count > 1 {
+            eprintln!("WARNING: {count} video streams found; using the first");
+        }
+        self.video_streams().next()
+    }
+
+    fn video_streams(&self) -> impl Iterator<Item = &VideoStreamInfo> {
+        self.media_streams()
+            .filter_map(|stream| match &stream.kind {
+                StreamKind::Video(video) => Some(video),
+                _ => None,
+            })
+    }
+
+    pub fn find_audio_stream(&self) -> Option<&AudioStreamInfo> {
+        self.media_streams().find_map(|stream| match &stream.kind {
+            StreamKind::Audio(audio) => Some(audio),
+            _ => None,
+        })
+    }
+}
+
+/// A per-stream summary, one line each.
+impl Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for stream in &self.streams {
-            if let StreamKind::Audio(audio) = &stream.kind {
-                return audio;
+            write!(f, "#{:<3}", stream.index)?;
+            match &stream.kind {
+                StreamKind::Video(v) => {
+                    let codec = match &v.codec {
+                        Codec::H264 { profile } => format!("h264 ({profile:?})"),
+                        Codec::Other(name) => name.clone(),
+                    };
+                    write!(
+                        f,
+                        "video  {codec:<16} {}x{} {:.3}fps {:?} {}",
+                        v.width,
+                        v.height,
+                        v.avg_frame_rate.to_f64().unwrap_or(0.0),
+                        v.field_order,
+                        v.pix_fmt
+                    )?;
+                    write_bit_rate(f, v.bit_rate)?;
+                    if v.rotation() != 0 {
+                        write!(f, " rotated {}°", v.rotation())?;
+                    }
+                }
+                StreamKind::Audio(a) => {
+                    write!(
+                        f,
+                        "audio  {:<16} {}ch {}Hz",
+                        a.codec_name, a.channels, a.sample_rate
+                    )?;
+                    write_bit_rate(f, a.bit_rate)?;
+                }
+                StreamKind::Data => write!(f, "data")?,
             }
+            if stream.is_attached_picture() {
+                write!(f, " (attached picture)")?;
+            }
+            writeln!(f)?;
         }
-        panic!("no video stream found");
+        Ok(())
+    }
+}
+
+fn write_bit_rate(f: &mut fmt::Formatter<'_>, bit_rate: Option<u32>) -> fmt::Result {
+    match bit_rate {
+        Some(bit_rate) => write!(f, " {}kbps", bit_rate / 1000),
+        None => write!(f, " ?kbps"),
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct StreamInfo {
     pub index: usize,
+    #[serde(default)]
+    pub disposition: Disposition,
     #[serde(flatten)]
     pub kind: StreamKind,
 }
 
+/// ffprobe's per-stream flags, as 0 or 1.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Disposition {
+    #[serde(default)]
+    pub attached_pic: u8,
+}
+
 impl StreamInfo {
-    fn check(&self) {
-        self.kind.check();
+    /// Cover art and the like: a still image that ffprobe reports as a
+    /// video stream.
+    pub fn is_attached_picture(&self) -> bool {
+        self.disposition.attached_pic != 0
+    }
+
+    fn issues(&self) -> Vec<String> {
+        match &self.kind {
+            StreamKind::Video(video) => video.issues(),
+            _ => Vec::new(),
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "codec_type", rename_all = "snake_case")]
 pub enum StreamKind {
     Video(VideoStreamInfo),
     Audio(AudioStreamInfo),
+    /// Anything else: data, subtitles, attachments.
+    #[serde(other)]
     Data,
 }
 
-impl StreamKind {
-    fn check(&self) {
-        match self {
-            StreamKind::Video(video) => {
-                video.check();
-            }
-            _ => {}
-        };
-    }
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct VideoStreamInfo {
     #[serde(flatten)]
     pub codec: Codec,
     pub width: u16,
     pub height: u16,
+    /// `level_idc`, e.g. 31 for level 3.1.
+    #[serde(default)]
+    pub level: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_ticks")]
     pub start_pts: u64,
+    #[serde(default, deserialize_with = "deserialize_ticks")]
     pub duration_ts: u64,
+    /// The same again in seconds, which stand in for `start_pts` and
+    /// `duration_ts` where a container leaves those out; see
+    /// [`Info::fill_missing_timestamps`].
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub start_time: Option<f64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<f64>,
+    /// Absent for still images.
+    #[serde(default)]
     pub field_order: FieldOrder,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub bit_rate: u32,
+    /// Not every container reports this, see [`Info::estimate_bit_rates`].
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub bit_rate: Option<u32>,
     // #[serde(deserialize_with = "deserialize_ratio_from_string")]
     // time_base: Ratio,
-    #[serde(deserialize_with = "deserialize_ratio_from_string")]
+    #[serde(
+        deserialize_with = "deserialize_ratio_from_string",
+        serialize_with = "serialize_ratio"
+    )]
     pub r_frame_rate: Ratio<u32>,
-    #[serde(deserialize_with = "deserialize_ratio_from_string")]
+    #[serde(
+        deserialize_with = "deserialize_ratio_from_string",
+        serialize_with = "serialize_ratio"
+    )]
     pub avg_frame_rate: Ratio<u32>,
     pub pix_fmt: String,
-    #[serde(deserialize_with = "deserialize_ratio_from_string")]
+    #[serde(
+        deserialize_with = "deserialize_ratio_from_string",
+        serialize_with = "serialize_ratio"
+    )]
     pub time_base: Ratio<u32>,
+    /// Where newer ffprobes report a display matrix; see [`Self::rotation`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub side_data_list: Vec<SideData>,
+    /// Where older ffprobes report rotation, as `rotate`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<StreamTags>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SideData {
+    /// Counterclockwise degrees, e.g. -90 for a phone held upright.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamTags {
+    /// Clockwise degrees.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rotate: Option<i32>,
 }
 
 impl VideoStreamInfo {
-    fn check(&self) {
-        assert!(self.width > 0);
-        assert!(self.height > 0);
-        assert!(self.bit_rate > 0);
+    /// How far the frames are turned for display, in clockwise degrees: 0,
+    /// 90, 180 or 270. ffmpeg applies this when decoding.
+    pub fn rotation(&self) -> u16 {
+        let clockwise = self
+            .side_data_list
+            .iter()
+            .find_map(|side_data| side_data.rotation)
+            .map(|rotation| -rotation.round() as i32)
+            .or_else(|| self.tags.as_ref()?.rotate)
+            .unwrap_or(0);
+        // Snapped to a quarter turn, as nothing else is filmed.
+        ((clockwise + 45).div_euclid(90).rem_euclid(4) * 90) as u16
+    }
+
+    /// The frame size once rotated for display, which is what rungs are
+    /// fitted to.
+    pub fn display_size(&self) -> (u16, u16) {
+        match self.rotation() {
+            90 | 270 => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// The RFC 6381 codec string, e.g. `avc1.640028` for High at level 4.0,
+    /// as a master playlist's `CODECS` wants it. `None` for codecs other
+    /// than H.264 and unknown levels.
+    pub fn codecs(&self) -> Option<String> {
+        let Codec::H264 { profile } = self.codec else {
+            return None;
+        };
+        let level = u8::try_from(self.level?).ok()?;
+        Some(format!("avc1.{}{level:02x}", profile.avc_prefix()))
+    }
+
+    fn issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        if self.width == 0 || self.height == 0 {
+            issues.push(format!("empty frame size {}x{}", self.width, self.height));
+        }
         // Fails on vid:24849730
         // assert_eq!(self.r_frame_rate, self.avg_frame_rate);
+        issues
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "codec_name")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawCodec", into = "RawCodec")]
 pub enum Codec {
-    #[serde(rename = "h264")]
-    H264 { profile: Profile },
-    #[serde(other)]
-    Other,
+    H264 {
+        profile: Profile,
+    },
+    /// Anything else, by ffprobe's `codec_name`, e.g. `hevc`.
+    Other(String),
+}
+
+/// [`Codec`] as ffprobe reports it, where any codec may have a profile
+/// but only H.264's matter.
+#[derive(Serialize, Deserialize)]
+struct RawCodec {
+    codec_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<RawProfile>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawProfile {
+    Known(Profile),
+    Unknown(String),
+}
+
+impl TryFrom<RawCodec> for Codec {
+    type Error = String;
+
+    fn try_from(raw: RawCodec) -> Result<Self, Self::Error> {
+        match (raw.codec_name.as_str(), raw.profile) {
+            ("h264", Some(RawProfile::Known(profile))) => Ok(Codec::H264 { profile }),
+            ("h264", Some(RawProfile::Unknown(profile))) => {
+                Err(format!("unsupported h264 profile {profile:?}"))
+            }
+            ("h264", None) => Err("h264 stream without a profile".to_string()),
+            _ => Ok(Codec::Other(raw.codec_name)),
+        }
+    }
+}
+
+impl From<Codec> for RawCodec {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 { profile } => RawCodec {
+                codec_name: "h264".to_string(),
+                profile: Some(RawProfile::Known(profile)),
+            },
+            Codec::Other(codec_name) => RawCodec {
+                codec_name,
+                profile: None,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
@@ -154,6 +693,8 @@ pub enum Profile {
     Main,
     #[serde(rename = "High")]
     High,
+    #[serde(rename = "High 10")]
+    High10,
 }
 
 impl Profile {
@@ -162,32 +703,89 @@ impl Profile {
             Profile::Baseline => "baseline",
             Profile::Main => "main",
             Profile::High => "high",
+            Profile::High10 => "high10",
+        }
+    }
+
+    /// `profile_idc` and the constraint flags, in hex.
+    fn avc_prefix(self) -> &'static str {
+        match self {
+            Profile::Baseline => "42e0",
+            Profile::Main => "4d40",
+            Profile::High => "6400",
+            Profile::High10 => "6e00",
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum FieldOrder {
     #[serde(rename = "progressive")]
     Progressive,
     #[serde(rename = "bt")]
     BottomFirst,
+    #[serde(rename = "tt")]
+    TopFirst,
+    /// ffprobe's `unknown`, or an order we don't distinguish.
+    #[default]
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AudioStreamInfo {
     pub codec_name: String,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub bit_rate: u32,
+    /// The AAC object type, e.g. `LC`.
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    pub bit_rate: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_ticks")]
     pub start_pts: u64,
+    #[serde(default, deserialize_with = "deserialize_ticks")]
     pub duration_ts: u64,
+    /// The same again in seconds, which stand in for `start_pts` and
+    /// `duration_ts` where a container leaves those out; see
+    /// [`Info::fill_missing_timestamps`].
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub start_time: Option<f64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<f64>,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub sample_rate: u32,
     pub channels: u8,
-    #[serde(deserialize_with = "deserialize_ratio_from_string")]
+    #[serde(
+        deserialize_with = "deserialize_ratio_from_string",
+        serialize_with = "serialize_ratio"
+    )]
     pub time_base: Ratio<u32>,
 }
 
+impl AudioStreamInfo {
+    /// Like [`VideoStreamInfo::codecs`]; AAC without a reported profile is
+    /// taken to be LC, which is all the encoders here produce.
+    pub fn codecs(&self) -> Option<String> {
+        if self.codec_name != "aac" {
+            return None;
+        }
+        let object_type = match self.profile.as_deref() {
+            None | Some("LC") => 2,
+            Some("HE-AAC") => 5,
+            Some("HE-AACv2") => 29,
+            Some(_) => return None,
+        };
+        Some(format!("mp4a.40.{object_type}"))
+    }
+}
+
 pub fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     D: Deserializer<'de>,
@@ -207,6 +805,56 @@ where
     }
 }
 
+/// Like [`deserialize_number_from_string`], but ffprobe's `"N/A"` (or a
+/// `null`) reads as `None`. Pair with `#[serde(default)]` for absent fields.
+pub fn deserialize_optional_number_from_string<'de, T, D>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    <T as FromStr>::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber<T> {
+        String(String),
+        Number(T),
+    }
+
+    match Option::<StringOrNumber<T>>::deserialize(deserializer)? {
+        Some(StringOrNumber::String(s)) if s == "N/A" => Ok(None),
+        Some(StringOrNumber::String(s)) => {
+            s.parse::<T>().map(Some).map_err(serde::de::Error::custom)
+        }
+        Some(StringOrNumber::Number(n)) => Ok(Some(n)),
+        None => Ok(None),
+    }
+}
+
+/// A stream's `start_pts` or `duration_ts`, which ffprobe may give as a
+/// string, as `"N/A"` (read as zero), or below zero, as for an edit list
+/// that starts before the first frame. Negative values are clamped to zero
+/// with a warning. Pair with `#[serde(default)]` for absent fields.
+pub fn deserialize_ticks<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ticks = deserialize_optional_number_from_string::<i64, D>(deserializer)?.unwrap_or(0);
+    if ticks < 0 {
+        eprintln!("WARNING: clamped a timestamp of {ticks} ticks to zero");
+    }
+    Ok(ticks.max(0) as u64)
+}
+
+/// The inverse of [`deserialize_ratio_from_string`].
+pub fn serialize_ratio<S>(ratio: &Ratio<u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&format_args!("{}/{}", ratio.numer(), ratio.denom()))
+}
+
 pub fn deserialize_ratio_from_string<'de, D>(deserializer: D) -> Result<Ratio<u32>, D::Error>
 where
     D: Deserializer<'de>,
@@ -217,5 +865,231 @@ where
         .ok_or_else(|| serde::de::Error::custom("missing /"))?;
     let num = num.parse().map_err(serde::de::Error::custom)?;
     let den = den.parse().map_err(serde::de::Error::custom)?;
+    // ffprobe reports an unknown rate (e.g. of a still image) as 0/0.
+    if den == 0 {
+        return Ok(Ratio::ZERO);
+    }
     Ok(Ratio::new(num, den))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIDEO_WITHOUT_BIT_RATE: &str = r#"{
+        "index": 0, "codec_type": "video", "codec_name": "h264", "profile": "High",
+        "width": 1920, "height": 1080, "start_pts": 0, "duration_ts": 1000,
+        "field_order": "progressive",
+        "r_frame_rate": "25/1", "avg_frame_rate": "25/1",
+        "pix_fmt": "yuv420p", "time_base": "1/12800"
+    }"#;
+
+    const AUDIO: &str = r#"{
+        "index": 1, "codec_type": "audio", "codec_name": "aac",
+        "bit_rate": "128000", "start_pts": 0, "duration_ts": 1000,
+        "sample_rate": "48000", "channels": 2, "time_base": "1/48000"
+    }"#;
+
+    fn info(format: &str) -> Info {
+        let json =
+            format!(r#"{{"streams": [{VIDEO_WITHOUT_BIT_RATE}, {AUDIO}], "format": {format}}}"#);
+        let mut info: Info = serde_json::from_str(&json).unwrap();
+        info.estimate_bit_rates();
+        info.check();
+        info
+    }
+
+    #[test]
+    fn test_missing_stream_bit_rate() {
+        let info = self::info(r#"{"bit_rate": "3128000", "size": "1000", "duration": "1.0"}"#);
+        assert_eq!(info.video_stream().bit_rate, Some(3_000_000));
+        assert_eq!(info.audio_stream().bit_rate, Some(128_000));
+
+        // No container bitrate either: work it out from the file size.
+        let info = self::info(r#"{"size": "1016000", "duration": "2.000000"}"#);
+        assert_eq!(info.video_stream().bit_rate, Some(3_936_000));
+
+        let info = self::info(r#"{"bit_rate": "N/A", "duration": "N/A"}"#);
+        assert_eq!(info.video_stream().bit_rate, None);
+    }
+
+    #[test]
+    fn test_timestamps_from_seconds() {
+        let json = format!(
+            r#"{{"streams": [{}, {}]}}"#,
+            VIDEO_WITHOUT_BIT_RATE.replace(
+                r#""start_pts": 0, "duration_ts": 1000"#,
+                r#""start_time": "0.080000", "duration": "10.000000""#
+            ),
+            AUDIO.replace(
+                r#""start_pts": 0, "duration_ts": 1000"#,
+                r#""start_pts": -1024, "start_time": "N/A", "duration": "10.005333""#
+            ),
+        );
+        let mut info: Info = serde_json::from_str(&json).unwrap();
+        info.fill_missing_timestamps();
+
+        let video = info.video_stream();
+        assert_eq!((video.start_pts, video.duration_ts), (1024, 128000));
+        let audio = info.audio_stream();
+        assert_eq!((audio.start_pts, audio.duration_ts), (0, 480256));
+    }
+
+    #[test]
+    fn test_missing_timestamps() {
+        let json = format!(
+            r#"{{"streams": [{}]}}"#,
+            VIDEO_WITHOUT_BIT_RATE.replace(
+                r#""start_pts": 0, "duration_ts": 1000"#,
+                r#""start_pts": "N/A""#
+            )
+        );
+        let mut info: Info = serde_json::from_str(&json).unwrap();
+        info.fill_missing_timestamps();
+
+        let video = info.video_stream();
+        assert_eq!((video.start_pts, video.duration_ts), (0, 0));
+    }
+
+    #[test]
+    fn test_codecs() {
+        let info = self::info("{}");
+        // The test stream says High but gives no level.
+        assert_eq!(info.video_stream().codecs(), None);
+        assert_eq!(info.audio_stream().codecs().as_deref(), Some("mp4a.40.2"));
+
+        let json = format!(
+            r#"{{"streams": [{}]}}"#,
+            VIDEO_WITHOUT_BIT_RATE
+                .replace(r#""profile": "High""#, r#""profile": "Main", "level": 31"#)
+        );
+        let info: Info = serde_json::from_str(&json).unwrap();
+        assert_eq!(info.video_stream().codecs().as_deref(), Some("avc1.4d401f"));
+    }
+
+    #[test]
+    fn test_other_codec_keeps_name() {
+        let json = format!(
+            r#"{{"streams": [{}]}}"#,
+            VIDEO_WITHOUT_BIT_RATE
+                .replace(r#""codec_name": "h264""#, r#""codec_name": "hevc""#)
+                .replace(r#""profile": "High""#, r#""profile": "Main 10""#)
+        );
+        let info: Info = serde_json::from_str(&json).unwrap();
+        assert_eq!(info.video_stream().codec, Codec::Other("hevc".to_string()));
+    }
+
+    #[test]
+    fn test_summary_tolerates_unknown_streams() {
+        let json = format!(
+            r#"{{"streams": [{}, {AUDIO}, {{"index": 2, "codec_type": "subtitle"}}]}}"#,
+            VIDEO_WITHOUT_BIT_RATE
+                .replace(r#""width": 1920"#, r#""width": 0"#)
+                .replace("progressive", "unknown")
+        );
+        let info: Info = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(info.issues(), ["stream 0: empty frame size 0x1080"]);
+        assert_eq!(
+            info.to_string(),
+            "\
+#0  video  h264 (High)      0x1080 25.000fps Unknown yuv420p ?kbps
+#1  audio  aac              2ch 48000Hz 128kbps
+#2  data
+"
+        );
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["streams"][0]["avg_frame_rate"], "25/1");
+        assert_eq!(json["streams"][1]["time_base"], "1/48000");
+    }
+
+    const ATTACHED_PICTURE: &str = r#"{
+        "index": 2, "codec_type": "video", "codec_name": "mjpeg",
+        "width": 600, "height": 600, "start_pts": 0, "duration_ts": 1,
+        "r_frame_rate": "90000/1", "avg_frame_rate": "0/0",
+        "pix_fmt": "yuvj420p", "time_base": "1/90000",
+        "disposition": {"default": 0, "attached_pic": 1}
+    }"#;
+
+    #[test]
+    fn test_attached_picture_is_not_a_video_stream() {
+        let json = format!(
+            r#"{{"streams": [{ATTACHED_PICTURE}, {VIDEO_WITHOUT_BIT_RATE}, {AUDIO}],
+                "format": {{"bit_rate": "3128000"}}}}"#
+        );
+        let mut info: Info = serde_json::from_str(&json).unwrap();
+        info.estimate_bit_rates();
+
+        assert!(info.issues().is_empty(), "{:?}", info.issues());
+        assert_eq!(info.media_streams().count(), 2);
+        assert_eq!(info.video_stream().width, 1920);
+        assert_eq!(info.video_stream().bit_rate, Some(3_000_000));
+        assert_eq!(
+            info.to_string().lines().next().unwrap(),
+            "#2  video  mjpeg            600x600 0.000fps Unknown yuvj420p ?kbps (attached picture)"
+        );
+    }
+
+    #[test]
+    fn test_multiple_video_streams() {
+        let second = VIDEO_WITHOUT_BIT_RATE.replace(r#""index": 0"#, r#""index": 2"#);
+        let json = format!(r#"{{"streams": [{VIDEO_WITHOUT_BIT_RATE}, {AUDIO}, {second}]}}"#);
+        let info: Info = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(info.issues(), ["2 video streams (0, 2), expected one"]);
+        assert_eq!(info.find_video_stream().unwrap().width, 1920);
+    }
+
+    #[test]
+    #[should_panic(expected = "2 video streams found, expected one")]
+    fn test_video_stream_of_several() {
+        let second = VIDEO_WITHOUT_BIT_RATE.replace(r#""index": 0"#, r#""index": 2"#);
+        let json = format!(r#"{{"streams": [{VIDEO_WITHOUT_BIT_RATE}, {second}]}}"#);
+        let info: Info = serde_json::from_str(&json).unwrap();
+        info.video_stream();
+    }
+
+    #[test]
+    fn test_concat_issues() {
+        let info = |json: &str| serde_json::from_str::<Info>(json).unwrap();
+        let original = info(&format!(
+            r#"{{"streams": [{VIDEO_WITHOUT_BIT_RATE}, {AUDIO}]}}"#
+        ));
+        let silent = info(&format!(r#"{{"streams": [{VIDEO_WITHOUT_BIT_RATE}]}}"#));
+        let audio_only = info(&format!(r#"{{"streams": [{AUDIO}]}}"#));
+
+        assert!(original.concat_issues(&original).is_empty());
+        assert_eq!(
+            original.concat_issues(&silent),
+            ["concat inputs differ in whether they have audio"]
+        );
+        assert_eq!(
+            original.concat_issues(&audio_only),
+            ["concat inputs differ in whether they have video"]
+        );
+        assert!(try_inspect("/nonexistent/frameserve.mp4").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_configured_ffprobe() {
+        let dir = std::env::temp_dir().join("frameserve-inspect-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let stub = crate::config::test_tool(
+            &dir,
+            "ffprobe",
+            &format!("#!/bin/sh\ncat <<'EOF'\n{{\"streams\": [{AUDIO}]}}\nEOF\n"),
+        );
+
+        let tools = Tools {
+            ffmpeg: "ffmpeg".to_string(),
+            ffprobe: stub,
+            audio_encoder: "aac".to_string(),
+        };
+        let info = inspect_with(&tools, "in.mp4");
+        assert_eq!(info.audio_stream().sample_rate, 48000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
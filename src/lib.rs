@@ -1,8 +1,17 @@
+pub mod capabilities;
+pub mod clean;
 pub mod config;
+pub mod diff;
 pub mod duration;
 pub mod inspect;
 pub mod package;
 pub mod playout;
 pub mod recipe;
 pub mod schedule;
+pub mod scte35;
+pub mod sequence;
+pub mod server;
+pub mod store;
 pub mod utils;
+pub mod validate;
+pub mod verify;
@@ -1,20 +1,49 @@
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use jiff::Timestamp;
-use num::rational::Ratio;
+use num::{ToPrimitive, rational::Ratio};
 use serde::{Deserialize, Serialize};
-use std::{ffi::OsStr, fmt::Display, fs};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fmt::Display,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     duration::{Duration, StepSize},
-    inspect::combine_inspect,
+    inspect::{Packet, combine_inspect, inspect, inspect_packets, starts_with_keyframe},
+    recipe::{SegmentType, TileLayout},
+    store::BlobStore,
     utils::extract_vid,
 };
 
+/// In seconds; see [`fit_final_segment`].
+const MAX_FINAL_SEGMENT_CORRECTION: f64 = 0.5;
+
+/// How many segments of each variant [`ProbeMode::Playlist`] still probes.
+const PLAYLIST_SAMPLES: usize = 3;
+
+/// In seconds, about a frame; how far a sampled segment may be from where
+/// its playlist puts it.
+const PLAYLIST_TOLERANCE: f64 = 0.05;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Package {
     pub vid: u32,
     pub packaged_at: Timestamp,
     pub variants: Vec<Variant>,
+    /// A representative frame for channel listings, served at `/poster/<vid>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<RemoteResource>,
+    /// Sprite sheets for scrubbing, see [`Thumbnails`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnails: Option<Thumbnails>,
+    /// How many times in a row the package plays each loop, so it can be
+    /// weighted over the others. Once when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeats: Option<u32>,
 }
 
 impl Package {
@@ -23,6 +52,21 @@ impl Package {
         serde_json::from_str::<Self>(&src).unwrap()
     }
 
+    /// Writes `<vid>.json` in `packages_dir` by way of `<vid>.tmp`, so the
+    /// server, or anything else reading it meanwhile, sees either the old
+    /// package or the new one, never half of one.
+    pub fn save(&self, packages_dir: &str) -> io::Result<()> {
+        let path = Path::new(packages_dir).join(format!("{}.json", self.vid));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// How many times in a row the package plays each loop.
+    pub fn plays(&self) -> usize {
+        self.repeats.map_or(1, |repeats| repeats.max(1) as usize)
+    }
+
     pub fn base(&self) -> Ratio<u32> {
         self.variants[0].time_base
     }
@@ -30,13 +74,95 @@ impl Package {
     pub fn duration(&self) -> u64 {
         self.variants[0].raw_duration()
     }
+
+    /// How far (in seconds) the audio runs past the video by the end of the
+    /// package, for the audio variant that is furthest out. Negative when
+    /// the audio ends first. `None` without both kinds of variant.
+    pub fn av_drift(&self) -> Option<f64> {
+        let video = self
+            .variants
+            .iter()
+            .filter(|variant| variant.kind != VariantKind::Audio)
+            .max_by_key(|variant| variant.bitrate)?
+            .seconds();
+        self.variants
+            .iter()
+            .filter(|variant| variant.kind == VariantKind::Audio)
+            .map(|variant| variant.seconds() - video)
+            .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+    }
+}
+
+/// How a variant's media segments are stored.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PackagingMode {
+    /// One blob per segment.
+    #[default]
+    Segmented,
+    /// All media segments of a variant in one blob, addressed by byte range.
+    ByteRange,
+    /// Like `ByteRange`, with the init segment at the start of the same
+    /// blob; see [`Variant::init_range`].
+    SharedInit,
+}
+
+/// How segment timings are read.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ProbeMode {
+    /// One ffprobe per segment, on the init segment plus that segment.
+    #[default]
+    PerSegment,
+    /// One ffprobe per variant, over the packets of all its segments.
+    Batched,
+    /// One ffprobe per segment, like `PerSegment`, but timed from its
+    /// packets rather than the stream's `start_pts` and `duration_ts`, which
+    /// some fMP4 gets wrong for a segment on its own. Falls back to those
+    /// when the segment's packets carry no timestamps.
+    Packets,
+    /// Segment timings from the encoder's own `stream.m3u8`, probing only
+    /// the first segment, for the time base and codecs, and a sample of
+    /// the rest to check the playlist against.
+    Playlist,
+}
+
+pub struct PackageOptions {
+    pub mode: PackagingMode,
+    pub probe: ProbeMode,
+    /// Warn when a package's audio and video lengths differ by more than
+    /// this many seconds; the difference builds up over the loop.
+    pub max_av_drift: f64,
+    /// How much of each file's blake3 hash goes into its name, up to the
+    /// full 32 bytes. 16 bytes gives 22-character names.
+    pub hash_bytes: usize,
+    /// A final segment shorter than this (in seconds) is merged into the
+    /// previous one; ffmpeg sometimes leaves a sub-second tail that stutters
+    /// at the loop point.
+    pub min_final_segment: f64,
+}
+
+impl Default for PackageOptions {
+    fn default() -> Self {
+        Self {
+            mode: PackagingMode::default(),
+            probe: ProbeMode::default(),
+            max_av_drift: 0.1,
+            hash_bytes: blake3::OUT_LEN,
+            min_final_segment: 1.0,
+        }
+    }
 }
 
-pub fn package(input_dir: &str, segments_dir: &str, packages_dir: &str) {
+pub fn package(
+    input_dir: &str,
+    store: &dyn BlobStore,
+    packages_dir: &str,
+    options: &PackageOptions,
+) {
     let mut variants = Vec::new();
+    let mut mappings = Vec::new();
 
     let vid = extract_vid(input_dir);
-    fs::create_dir_all(format!("{segments_dir}/{vid}")).unwrap();
+    let mut cache = InspectCache::load(format!("{packages_dir}/inspect-cache.json"), options.probe);
 
     for entry in std::fs::read_dir(input_dir).unwrap() {
         let entry = entry.unwrap();
@@ -44,42 +170,313 @@ pub fn package(input_dir: &str, segments_dir: &str, packages_dir: &str) {
             continue;
         }
 
-        let (variant, mappings) = package_variant(entry.path().to_str().unwrap());
+        let (variant, variant_mappings) =
+            package_variant(entry.path().to_str().unwrap(), options, &mut cache);
         variants.push(variant);
-
-        for Mapping(src, remote) in mappings {
-            let dst = format!("{segments_dir}/{vid}/{}", remote.0);
-            fs::copy(src, dst).unwrap();
-        }
+        mappings.extend(variant_mappings);
     }
 
-    let package = Package {
+    cache.save().unwrap();
+
+    let poster_file = format!("{input_dir}/poster.jpg");
+    let poster = Path::new(&poster_file).exists().then(|| {
+        let (poster, mapping) = RemoteResource::from_file(&poster_file);
+        mappings.push(mapping);
+        poster
+    });
+
+    let video_seconds = variants
+        .iter()
+        .filter(|variant| variant.kind != VariantKind::Audio)
+        .map(Variant::seconds)
+        .fold(0.0, f64::max);
+    let thumbnails = package_thumbnails(input_dir, video_seconds, options, &mut mappings);
+
+    let mut package = Package {
         vid,
         packaged_at: Timestamp::now(),
         variants,
+        poster,
+        thumbnails,
+        repeats: None,
     };
-    let package_json = serde_json::to_string_pretty(&package).unwrap();
-    fs::write(format!("{packages_dir}/{vid}.json"), package_json).unwrap();
+
+    if options.hash_bytes < blake3::OUT_LEN
+        && let Err(err) = shorten_names(&mut package, &mut mappings, options.hash_bytes)
+    {
+        panic!("Can't package {vid}: {err}");
+    }
+    for Mapping(src, remote, _) in mappings {
+        store.put(&src, &format!("{vid}/{}", remote.0)).unwrap();
+    }
+
+    if let Some(drift) = package.av_drift()
+        && drift.abs() > options.max_av_drift
+    {
+        eprintln!(
+            "WARNING: package {vid} has {drift:+.3}s of audio/video drift (limit {}s)",
+            options.max_av_drift
+        );
+    }
+    for variant in &package.variants {
+        if let Some(measured) = variant.measured_bitrate() {
+            println!(
+                "Variant at {}kbps came to {}kbps on average, peaking at {}kbps",
+                variant.bitrate / 1000,
+                measured.average / 1000,
+                measured.peak / 1000
+            );
+        }
+    }
+    package.save(packages_dir).unwrap();
+}
+
+/// Trick-play thumbnails: tiled sprite sheets, plus a WebVTT track mapping
+/// each interval to its tile for players that scrub with one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnails {
+    #[serde(flatten)]
+    pub layout: TileLayout,
+    /// Average over the video, for `EXT-X-IMAGE-STREAM-INF`.
+    pub bandwidth: u32,
+    pub sheets: Vec<RemoteResource>,
+    pub vtt: RemoteResource,
+}
+
+/// Picks up the sprite sheets the encode left beside the variants, if any.
+fn package_thumbnails(
+    input_dir: &str,
+    video_seconds: f64,
+    options: &PackageOptions,
+    mappings: &mut Vec<Mapping>,
+) -> Option<Thumbnails> {
+    let layout_file = format!("{input_dir}/sprites.json");
+    let layout =
+        serde_json::from_str::<TileLayout>(&fs::read_to_string(layout_file).ok()?).unwrap();
+
+    let mut files = fs::read_dir(input_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+            name.starts_with("sprite") && name.ends_with(".jpg")
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    if files.is_empty() {
+        eprintln!("WARNING: {input_dir} has a sprite layout but no sheets");
+        return None;
+    }
+
+    let mut bytes = 0;
+    let mut sheets = Vec::new();
+    // The track refers to sheets by the names they are stored under, so
+    // these must match what any shortening will produce.
+    let mut names = Vec::new();
+    for file in &files {
+        bytes += file.metadata().unwrap().len();
+        let (sheet, mapping) = RemoteResource::from_file(file.to_str().unwrap());
+        names.push(RemoteResource::from_hash_prefix(
+            &mapping.2,
+            options.hash_bytes,
+            "jpg",
+        ));
+        sheets.push(sheet);
+        mappings.push(mapping);
+    }
+
+    let vtt_file = format!("{input_dir}/thumbnails.vtt");
+    fs::write(&vtt_file, thumbnails_vtt(layout, video_seconds, &names)).unwrap();
+    let (vtt, mapping) = RemoteResource::from_file(&vtt_file);
+    mappings.push(mapping);
+
+    Some(Thumbnails {
+        layout,
+        bandwidth: (bytes as f64 * 8.0 / video_seconds.max(1.0)) as u32,
+        sheets,
+        vtt,
+    })
+}
+
+/// A WebVTT cue per tile, addressing it as a spatial fragment of its sheet.
+/// The final cue ends with the video rather than a full interval later.
+fn thumbnails_vtt(layout: TileLayout, seconds: f64, sheets: &[RemoteResource]) -> String {
+    fn timestamp(seconds: f64) -> String {
+        let millis = (seconds * 1000.0).round() as u64;
+        let (secs, millis) = (millis / 1000, millis % 1000);
+        format!(
+            "{:02}:{:02}:{:02}.{millis:03}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+
+    let mut vtt = String::from("WEBVTT\n");
+    let available = sheets.len() as u32 * layout.per_sheet();
+    for tile in 0..layout.tiles(seconds).min(available) {
+        let start = (tile * layout.interval) as f64;
+        let end = (start + layout.interval as f64).min(seconds.max(start + 0.001));
+        let sheet = &sheets[(tile / layout.per_sheet()) as usize];
+        let position = tile % layout.per_sheet();
+        let x = (position % layout.columns as u32) * layout.width as u32;
+        let y = (position / layout.columns as u32) * layout.height as u32;
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}#xywh={x},{y},{},{}\n",
+            timestamp(start),
+            timestamp(end),
+            sheet.0,
+            layout.width,
+            layout.height
+        ));
+    }
+    vtt
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Variant {
-    pub init_src: RemoteResource,
+    /// Absent for MPEG-TS variants, whose segments carry their own headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init_src: Option<RemoteResource>,
+    /// Set when the init segment shares its file with the media segments,
+    /// see [`PackagingMode::SharedInit`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init_range: Option<ByteRange>,
     pub time_base: Ratio<u32>,
     pub bitrate: u32,
     #[serde(flatten)]
     pub kind: VariantKind,
+    /// Audio channel count; absent in packages made before surround
+    /// renditions, which were all stereo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u8>,
+    /// An audio variant's language, from a tag such as `fr` in its
+    /// directory name; unknown when untagged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// In-program points to break for ads at, in `time_base` from the start
+    /// of the first segment; see [`Self::pieces`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cues: Vec<u64>,
+    /// Ads to splice in, each of which cuts the variant as a cue does at
+    /// both ends; see [`Self::ad_break_cuts`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ad_breaks: Vec<AdBreak>,
+    /// What the encoder actually produced, as probed from the first
+    /// segment; see [`crate::inspect::VideoStreamInfo::codecs`]. Absent in older packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codecs: Option<String>,
     pub segments: Vec<Segment>,
 }
 
 impl Variant {
+    pub fn channels(&self) -> u8 {
+        self.channels.unwrap_or(2)
+    }
+
+    /// The total of the segments' durations, in ticks of `time_base`.
     pub fn raw_duration(&self) -> u64 {
         self.segments.iter().map(|segment| segment.duration()).sum()
     }
 
-    pub fn duration(&self, step_size: StepSize) -> Duration {
+    pub fn seconds(&self) -> f64 {
+        self.raw_duration() as f64 * self.time_base.to_f64().unwrap()
+    }
+
+    /// The total of the segments' durations, in the steps of a playlist
+    /// mixing time bases; see [`StepSize`].
+    pub fn duration_steps(&self, step_size: StepSize) -> Duration {
         Duration::new(self.raw_duration(), self.time_base, step_size)
     }
+
+    /// What the segments actually come to, as opposed to the `bitrate`
+    /// asked of the encoder. `None` unless every segment's size is known.
+    pub fn measured_bitrate(&self) -> Option<MeasuredBitrate> {
+        let time_base = self.time_base.to_f64().unwrap();
+        let mut bytes = 0;
+        let mut peak = 0.0_f64;
+        for segment in &self.segments {
+            let size = segment.size?;
+            bytes += size;
+            if segment.duration > 0 {
+                peak = peak.max(size as f64 * 8.0 / (segment.duration as f64 * time_base));
+            }
+        }
+        let seconds = self.seconds();
+        (seconds > 0.0).then(|| MeasuredBitrate {
+            average: (bytes as f64 * 8.0 / seconds).round() as u32,
+            peak: peak.round() as u32,
+        })
+    }
+
+    /// The segments cut at each of `cues`, at the first segment boundary at
+    /// or after it. Cues past the end, or that fall to the same boundary as
+    /// another, make no extra cut.
+    pub fn pieces(&self) -> Vec<&[Segment]> {
+        if self.segments.is_empty() {
+            return vec![&self.segments[..]];
+        }
+        let breaks = self
+            .ad_breaks
+            .iter()
+            .flat_map(|ad_break| [ad_break.out, ad_break.back]);
+        let mut cuts = self
+            .cues
+            .iter()
+            .copied()
+            .chain(breaks)
+            .filter_map(|cue| self.cut(cue))
+            .filter(|cut| *cut > 0)
+            .collect::<Vec<_>>();
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        let mut pieces = Vec::with_capacity(cuts.len() + 1);
+        let mut from = 0;
+        for cut in cuts {
+            pieces.push(&self.segments[from..cut]);
+            from = cut;
+        }
+        pieces.push(&self.segments[from..]);
+        pieces
+    }
+
+    /// The index of the first segment at or after `cue`, if any.
+    fn cut(&self, cue: u64) -> Option<usize> {
+        let first = self.segments.first()?;
+        self.segments
+            .iter()
+            .position(|segment| segment.start >= first.start + cue)
+    }
+
+    /// Where each of `ad_breaks` leaves and returns to the program, as the
+    /// segment indices [`Self::pieces`] cuts at. A break whose in point is
+    /// past the end, or that comes to no segments, is left out.
+    pub fn ad_break_cuts(&self) -> Vec<(usize, usize)> {
+        self.ad_breaks
+            .iter()
+            .filter_map(|ad_break| Some((self.cut(ad_break.out)?, self.cut(ad_break.back)?)))
+            .filter(|(out, back)| out < back)
+            .collect()
+    }
+}
+
+/// An ad break, as SCTE-35 would signal it: the program leaves at `out`
+/// and comes back at `in`, both in the variant's `time_base` from the start
+/// of its first segment, like [`Variant::cues`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdBreak {
+    pub out: u64,
+    #[serde(rename = "in")]
+    pub back: u64,
+}
+
+/// In bits per second; see [`Variant::measured_bitrate`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct MeasuredBitrate {
+    pub average: u32,
+    /// Of the densest single segment.
+    pub peak: u32,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -94,6 +491,23 @@ pub struct Segment {
     pub src: RemoteResource,
     pub start: u64,
     pub duration: u64,
+    /// Set when `src` is shared by the whole variant, see
+    /// [`PackagingMode::ByteRange`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<ByteRange>,
+    /// In bytes; absent in older packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Whether the segment starts with a keyframe, and so can be decoded on
+    /// its own. Unknown for audio and in older packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyframe: Option<bool>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
 }
 
 impl Segment {
@@ -102,7 +516,156 @@ impl Segment {
     }
 }
 
-fn package_variant(variant_dir: &str) -> (Variant, Vec<Mapping>) {
+/// Where a segment sits on its variant's timeline, as reported by ffprobe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Timing {
+    start_pts: u64,
+    duration_ts: u64,
+    /// See [`Segment::keyframe`]; only probed for video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyframe: Option<bool>,
+}
+
+impl Timing {
+    /// Entries cached before keyframes were checked lack one for video.
+    fn is_complete(&self, video: bool) -> bool {
+        !video || self.keyframe.is_some()
+    }
+
+    /// Whether both start and end are within `tolerance` ticks of `other`'s.
+    fn is_near(&self, other: &Timing, tolerance: u64) -> bool {
+        self.start_pts.abs_diff(other.start_pts) <= tolerance
+            && self.duration_ts.abs_diff(other.duration_ts) <= tolerance
+    }
+}
+
+/// Segment timings from an HLS media playlist's `EXTINF`s, keyed by file
+/// name, in the shape ffprobe gives them (see [`normalize_timeline`]) for a
+/// first segment starting at `first_start`. Each is rounded from the exact
+/// total, so they don't drift from the media.
+fn playlist_timings(
+    m3u8: &str,
+    time_base: Ratio<u32>,
+    first_start: u64,
+) -> HashMap<String, Timing> {
+    let ticks = |seconds: f64| (seconds / time_base.to_f64().unwrap()).round() as u64;
+    let mut timings = HashMap::new();
+    let mut elapsed = 0.0;
+    let mut duration = None;
+    for line in m3u8.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let seconds = extinf
+                .split_once(',')
+                .map_or(extinf, |(seconds, _)| seconds);
+            duration = seconds.parse::<f64>().ok();
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let Some(seconds) = duration.take() else {
+                continue;
+            };
+            let name = line.rsplit('/').next().unwrap();
+            let start = ticks(elapsed);
+            elapsed += seconds;
+            let timing = Timing {
+                start_pts: first_start + start,
+                duration_ts: ticks(elapsed),
+                keyframe: None,
+            };
+            timings.insert(name.to_string(), timing);
+        }
+    }
+    timings
+}
+
+/// The segments [`ProbeMode::Playlist`] probes anyway: the first, for the
+/// timeline's start, and a few picked by content hash, which is as good as
+/// at random but the same every run.
+fn playlist_samples(sources: &[RemoteResource]) -> Vec<usize> {
+    let mut order = (1..sources.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| &sources[i].0);
+    order.truncate(PLAYLIST_SAMPLES);
+    order.insert(0, 0);
+    order
+}
+
+/// Remembers segment timings across packaging runs, so unchanged segments
+/// don't need another ffprobe. Entries are keyed by the content hashes of the
+/// init and media segment, which together determine what ffprobe reports,
+/// and by whether `mode` times segments from their packets.
+struct InspectCache {
+    path: PathBuf,
+    mode: ProbeMode,
+    entries: BTreeMap<String, Timing>,
+    probes: usize,
+}
+
+impl InspectCache {
+    fn load(path: impl Into<PathBuf>, mode: ProbeMode) -> Self {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(src) => serde_json::from_str(&src).unwrap(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => panic!("can't read inspect cache {}: {err}", path.display()),
+        };
+        Self {
+            path,
+            mode,
+            entries,
+            probes: 0,
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.entries)?)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    fn contains(
+        &self,
+        init: Option<&RemoteResource>,
+        segment: &RemoteResource,
+        video: bool,
+    ) -> bool {
+        self.entries
+            .get(&self.key(init, segment))
+            .is_some_and(|timing| timing.is_complete(video))
+    }
+
+    fn key(&self, init: Option<&RemoteResource>, segment: &RemoteResource) -> String {
+        let init = init.map_or("", |init| &init.0);
+        // `Playlist` only probes its samples, the same way as `PerSegment`.
+        match self.mode {
+            ProbeMode::PerSegment | ProbeMode::Playlist => format!("{init}+{}", segment.0),
+            ProbeMode::Batched => format!("{init}+{}#batched", segment.0),
+            ProbeMode::Packets => format!("{init}+{}#packets", segment.0),
+        }
+    }
+
+    fn timing(
+        &mut self,
+        init: Option<&RemoteResource>,
+        segment: &RemoteResource,
+        video: bool,
+        probe: impl FnOnce() -> Timing,
+    ) -> Timing {
+        let key = self.key(init, segment);
+        if let Some(timing) = self.entries.get(&key)
+            && timing.is_complete(video)
+        {
+            return *timing;
+        }
+        self.probes += 1;
+        let timing = probe();
+        self.entries.insert(key, timing);
+        timing
+    }
+}
+
+fn package_variant(
+    variant_dir: &str,
+    options: &PackageOptions,
+    cache: &mut InspectCache,
+) -> (Variant, Vec<Mapping>) {
     let base = variant_dir.split("/").last().unwrap();
     let bitrate = base
         .split("_")
@@ -113,12 +676,36 @@ fn package_variant(variant_dir: &str) -> (Variant, Vec<Mapping>) {
         .unwrap();
     let bitrate = bitrate * 1000;
     let is_audio_stream = base.starts_with("aac_");
+    let language = base
+        .split("_")
+        .skip(1)
+        .find(|tag| (2..=3).contains(&tag.len()) && tag.bytes().all(|b| b.is_ascii_lowercase()))
+        .filter(|_| is_audio_stream)
+        .map(str::to_string);
 
     let init_path = format!("{variant_dir}/init.mp4");
-    let init_info = combine_inspect(&init_path, &format!("{variant_dir}/s00000.mp4"));
-    let (time_base, kind) = if is_audio_stream {
+    let segment_type = if Path::new(&init_path).exists() {
+        SegmentType::Fmp4
+    } else {
+        SegmentType::MpegTs
+    };
+    let extension = segment_type.extension();
+    let init_path = (segment_type == SegmentType::Fmp4).then_some(init_path);
+    let probe = |segment: &str| match &init_path {
+        Some(init_path) => combine_inspect(init_path, segment),
+        None => inspect(segment),
+    };
+
+    let init_info = probe(&format!("{variant_dir}/s00000.{extension}"));
+    let (time_base, kind, channels, codecs) = if is_audio_stream {
         let stream = init_info.audio_stream();
-        (stream.time_base, VariantKind::Audio)
+        let kind = VariantKind::Audio;
+        (
+            stream.time_base,
+            kind,
+            Some(stream.channels),
+            stream.codecs(),
+        )
     } else {
         let (width, height) = base
             .split("_")
@@ -130,59 +717,236 @@ fn package_variant(variant_dir: &str) -> (Variant, Vec<Mapping>) {
         let height = height.parse::<u16>().unwrap();
 
         let stream = init_info.video_stream();
-        (stream.time_base, VariantKind::Video { width, height })
+        let kind = VariantKind::Video { width, height };
+        (stream.time_base, kind, None, stream.codecs())
     };
 
     let mut mappings = Vec::new();
-    let (init_src, init_mapping) = RemoteResource::from_file(&init_path);
-    mappings.push(init_mapping);
-
-    let mut segments = Vec::new();
+    let mut init_src = init_path.as_deref().map(|init_path| {
+        let (init_src, init_mapping) = RemoteResource::from_file(init_path);
+        mappings.push(init_mapping);
+        init_src
+    });
 
+    let mut files = Vec::new();
     for entry in std::fs::read_dir(variant_dir).unwrap() {
-        let entry = entry.unwrap();
-        if entry.path().extension() != Some(OsStr::new("mp4")) {
+        let path = entry.unwrap().path();
+        if path.extension() != Some(OsStr::new(extension)) {
             continue;
         }
-        if entry.path().file_name() == Some(OsStr::new("init.mp4")) {
+        if path.file_name() == Some(OsStr::new("init.mp4")) {
             continue;
         }
+        files.push(path.to_str().unwrap().to_string());
+    }
+    // Segment names are zero-padded, so this is timeline order, which the
+    // batched probe relies on.
+    files.sort();
 
-        let path = entry.path();
-        let path = path.to_str().unwrap();
-
-        let (src, mapping) = RemoteResource::from_file(path);
-        mappings.push(mapping);
+    let sources = files
+        .iter()
+        .map(|path| {
+            let (src, mapping) = RemoteResource::from_file(path);
+            mappings.push(mapping);
+            src
+        })
+        .collect::<Vec<_>>();
 
-        let info = combine_inspect(&init_path, path);
-        let (start, duration) = if is_audio_stream {
-            let a = info.audio_stream();
-            (a.start_pts, a.duration_ts)
+    let stream = if is_audio_stream { "a:0" } else { "v:0" };
+    let paths = files.iter().map(String::as_str).collect::<Vec<_>>();
+    let uncached = sources
+        .iter()
+        .any(|src| !cache.contains(init_src.as_ref(), src, !is_audio_stream));
+    let listed = (options.probe == ProbeMode::Playlist).then(|| {
+        let m3u8_path = format!("{variant_dir}/stream.m3u8");
+        let m3u8 =
+            fs::read_to_string(&m3u8_path).unwrap_or_else(|err| panic!("{m3u8_path}: {err}"));
+        let first_start = if is_audio_stream {
+            init_info.audio_stream().start_pts
         } else {
-            let v = info.video_stream();
-            (v.start_pts, v.duration_ts)
+            init_info.video_stream().start_pts
+        };
+        playlist_timings(&m3u8, time_base, first_start)
+    });
+    let samples = playlist_samples(&sources);
+    let tolerance = (PLAYLIST_TOLERANCE / time_base.to_f64().unwrap()) as u64;
+
+    let batched = (options.probe == ProbeMode::Batched && uncached).then(|| {
+        let packets = inspect_packets(init_path.as_deref(), &paths, stream);
+
+        let mut offset = init_path
+            .as_deref()
+            .map_or(0, |init_path| fs::metadata(init_path).unwrap().len());
+        let boundaries = files
+            .iter()
+            .map(|path| {
+                let start = offset;
+                offset += fs::metadata(path).unwrap().len();
+                start
+            })
+            .collect::<Vec<_>>();
+        split_timings(&packets, &boundaries)
+    });
+
+    let mut segments = Vec::new();
+    for (i, (path, src)) in files.iter().zip(sources).enumerate() {
+        let first_start = segments.first().map(|first: &Segment| first.start);
+        let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+        let listed = listed.as_ref().map(|listed| {
+            *listed
+                .get(name)
+                .unwrap_or_else(|| panic!("{name} isn't in {variant_dir}/stream.m3u8"))
+        });
+        if let Some(listed) = listed
+            && !samples.contains(&i)
+        {
+            segments.push(Segment {
+                src,
+                start: listed.start_pts,
+                duration: listed.duration_ts,
+                range: None,
+                size: Some(fs::metadata(path).unwrap().len()),
+                keyframe: None,
+            });
+            continue;
+        }
+
+        let timing = cache.timing(init_src.as_ref(), &src, !is_audio_stream, || {
+            if let Some(batched) = &batched {
+                return batched[i];
+            }
+            if options.probe == ProbeMode::Packets {
+                let packets = inspect_packets(init_path.as_deref(), &[path.as_str()], stream);
+                match packet_timing(&packets, first_start) {
+                    Some(timing) => return timing,
+                    None => {
+                        eprintln!("WARNING: no packet timestamps in {path}; using the stream's")
+                    }
+                }
+            }
+
+            let info = probe(path);
+            let (start_pts, duration_ts, keyframe) = if is_audio_stream {
+                let a = info.audio_stream();
+                (a.start_pts, a.duration_ts, None)
+            } else {
+                let v = info.video_stream();
+                let keyframe = starts_with_keyframe(init_path.as_deref(), path);
+                (v.start_pts, v.duration_ts, Some(keyframe))
+            };
+            Timing {
+                start_pts,
+                duration_ts,
+                keyframe,
+            }
+        });
+
+        let timing = match listed {
+            Some(listed) => sampled_timing(path, listed, timing, tolerance),
+            None => timing,
         };
 
         segments.push(Segment {
             src,
-            start,
-            duration,
+            start: timing.start_pts,
+            duration: timing.duration_ts,
+            range: None,
+            size: Some(fs::metadata(path).unwrap().len()),
+            keyframe: timing.keyframe.filter(|_| !is_audio_stream),
         });
     }
 
-    segments.sort_by_key(|s| s.start);
+    let dependent = segments
+        .iter()
+        .filter(|segment| segment.keyframe == Some(false))
+        .count();
+    if dependent > 0 {
+        eprintln!(
+            "WARNING: {dependent} segments of {base} don't start with a keyframe; \
+             the master playlist won't claim independent segments"
+        );
+    }
 
-    let offset = segments[0].start;
-    segments.iter_mut().for_each(|segment| {
-        segment.duration -= segment.start;
-        segment.duration += offset;
-    });
+    normalize_timeline(&mut segments);
+
+    // Cached timings were checked when they were probed, and the encoder's
+    // playlist is trusted.
+    if uncached && listed.is_none() {
+        let m3u8_path = format!("{variant_dir}/stream.m3u8");
+        let total = Path::new(&m3u8_path)
+            .exists()
+            .then(|| inspect(&m3u8_path))
+            .and_then(|info| info.format?.duration);
+        match total {
+            Some(seconds) => {
+                let time_base = time_base.to_f64().unwrap();
+                let max_correction = (MAX_FINAL_SEGMENT_CORRECTION / time_base) as u64;
+                let total = (seconds / time_base).round() as u64;
+                fit_final_segment(&mut segments, total, max_correction);
+            }
+            None => eprintln!("WARNING: no container duration for {base}; can't check its length"),
+        }
+    }
+
+    let min_final_segment = (options.min_final_segment / time_base.to_f64().unwrap()) as u64;
+    let merged = std::env::temp_dir().join(format!(
+        "frameserve-{}-{base}-tail.{extension}",
+        std::process::id()
+    ));
+    merge_short_tail(
+        &mut segments,
+        &mut mappings,
+        min_final_segment,
+        merged.to_str().unwrap(),
+    )
+    .unwrap();
+
+    let mut init_range = None;
+    if options.mode != PackagingMode::Segmented {
+        let shared_init = init_path
+            .as_deref()
+            .filter(|_| options.mode == PackagingMode::SharedInit);
+        // The init segment's own mapping comes first, unless it is going
+        // into the combined file too.
+        let keep = usize::from(init_path.is_some() && shared_init.is_none());
+        let paths = mappings
+            .drain(keep..)
+            .map(|Mapping(path, remote, _)| (remote.0, path))
+            .collect::<HashMap<_, _>>();
+        let files = segments
+            .iter()
+            .map(|segment| paths[&segment.src.0].clone())
+            .collect::<Vec<_>>();
+
+        let combined = std::env::temp_dir().join(format!(
+            "frameserve-{}-{base}.{extension}",
+            std::process::id()
+        ));
+        let (mapping, range) = pack_byte_ranges(
+            &mut segments,
+            shared_init,
+            &files,
+            combined.to_str().unwrap(),
+        )
+        .unwrap();
+        if range.is_some() {
+            init_src = Some(mapping.1.clone());
+            init_range = range;
+        }
+        mappings.push(mapping);
+    }
 
     (
         Variant {
             init_src,
+            init_range,
             time_base,
             kind,
+            channels,
+            language,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs,
             bitrate,
             segments,
         },
@@ -190,7 +954,206 @@ fn package_variant(variant_dir: &str) -> (Variant, Vec<Mapping>) {
     )
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Splits a batched packet probe back into per-segment timings, in the same
+/// shape the per-segment probe reports: `duration_ts` runs from the first
+/// segment's start to the end of this one. `boundaries` holds the byte
+/// offset at which each segment starts in the probed stream.
+fn split_timings(packets: &[Packet], boundaries: &[u64]) -> Vec<Timing> {
+    let mut segments = vec![Vec::new(); boundaries.len()];
+    for packet in packets {
+        let Some(pos) = packet.pos else {
+            continue;
+        };
+        let Some(segment) = boundaries
+            .partition_point(|start| *start <= pos)
+            .checked_sub(1)
+        else {
+            continue;
+        };
+        segments[segment].push(packet);
+    }
+
+    let mut first_start = None;
+    segments
+        .into_iter()
+        .enumerate()
+        .map(|(i, packets)| {
+            let timing = packet_timing(packets, first_start)
+                .unwrap_or_else(|| panic!("no packets found in segment {i}"));
+            first_start.get_or_insert(timing.start_pts);
+            timing
+        })
+        .collect()
+}
+
+/// One segment's timing from its packets, in the shape the per-segment
+/// probe reports: `duration_ts` runs from `first_start`, the first
+/// segment's start, to the end of this one. `None` if no packet has a
+/// timestamp.
+fn packet_timing<'a>(
+    packets: impl IntoIterator<Item = &'a Packet>,
+    first_start: Option<u64>,
+) -> Option<Timing> {
+    let mut span = None::<(u64, u64)>;
+    let mut keyframe = None;
+    for packet in packets {
+        let Some(pts) = packet.pts else {
+            continue;
+        };
+        // Packets are in decode order, so the first is what a player
+        // joining at this segment must start decoding from.
+        keyframe.get_or_insert(packet.is_keyframe());
+        let end = pts + packet.duration.unwrap_or(0);
+        let span = span.get_or_insert((pts, end));
+        span.0 = span.0.min(pts);
+        span.1 = span.1.max(end);
+    }
+
+    let (start_pts, end) = span?;
+    Some(Timing {
+        start_pts,
+        duration_ts: end - first_start.unwrap_or(start_pts),
+        keyframe,
+    })
+}
+
+/// A sampled segment's timing once its probe agrees with `stream.m3u8`:
+/// the listed one, so it lines up with its unprobed neighbours, with the
+/// probe's keyframe flag.
+fn sampled_timing(path: &str, listed: Timing, probed: Timing, tolerance: u64) -> Timing {
+    if !listed.is_near(&probed, tolerance) {
+        panic!(
+            "{path} is at {}+{} by ffprobe, but {}+{} by stream.m3u8",
+            probed.start_pts, probed.duration_ts, listed.start_pts, listed.duration_ts
+        );
+    }
+    Timing {
+        keyframe: probed.keyframe,
+        ..listed
+    }
+}
+
+/// Orders segments by start time and converts ffprobe's `duration_ts`, which
+/// is measured from the first segment's start, into per-segment durations.
+fn normalize_timeline(segments: &mut [Segment]) {
+    segments.sort_by_key(|s| s.start);
+
+    let offset = segments[0].start;
+    segments.iter_mut().for_each(|segment| {
+        segment.duration -= segment.start;
+        segment.duration += offset;
+    });
+
+    // Concatenated inputs must not leave gaps or overlaps at the joins,
+    // otherwise the start/duration bookkeeping no longer describes the media.
+    for pair in segments.windows(2) {
+        assert_eq!(
+            pair[0].start + pair[0].duration,
+            pair[1].start,
+            "segment timeline is not contiguous at {}",
+            pair[1].src.0
+        );
+    }
+}
+
+/// Makes the segment durations add up to `total`, the stream's length as
+/// ffprobe gives it for the whole of `stream.m3u8`, by correcting the final
+/// segment, whose length the per-segment probes round the most. A difference of more than
+/// `max_correction` is more than rounding, so is only warned about.
+fn fit_final_segment(segments: &mut [Segment], total: u64, max_correction: u64) {
+    let sum = segments.iter().map(|segment| segment.duration).sum::<u64>();
+    let Some(last) = segments.last_mut() else {
+        return;
+    };
+    let correction = total.abs_diff(sum);
+    if correction == 0 {
+        return;
+    }
+    match (last.duration + total).checked_sub(sum) {
+        Some(duration) if correction <= max_correction && duration > 0 => {
+            last.duration = duration;
+        }
+        _ => eprintln!(
+            "WARNING: segments add up to {sum} but the stream is {total} long; leaving {} as probed",
+            last.src.0
+        ),
+    }
+}
+
+/// Folds a final segment shorter than `min_duration` (in time base units)
+/// into the one before it, concatenating the two fragments into `out` so
+/// no media is lost. A tail whose file is empty is simply dropped. Either
+/// way the summed durations still describe the whole stream.
+fn merge_short_tail(
+    segments: &mut Vec<Segment>,
+    mappings: &mut Vec<Mapping>,
+    min_duration: u64,
+    out: &str,
+) -> io::Result<()> {
+    match segments.as_slice() {
+        [.., _, tail] if tail.duration < min_duration => {}
+        _ => return Ok(()),
+    }
+
+    let tail = segments.pop().unwrap();
+    let prev = segments.last_mut().unwrap();
+    prev.duration += tail.duration;
+    prev.size = prev.size.zip(tail.size).map(|(a, b)| a + b);
+
+    let tail_idx = mappings.iter().position(|m| m.1.0 == tail.src.0).unwrap();
+    let Mapping(tail_path, ..) = mappings.remove(tail_idx);
+    if fs::metadata(&tail_path)?.len() == 0 {
+        return Ok(());
+    }
+
+    let prev_idx = mappings.iter().position(|m| m.1.0 == prev.src.0).unwrap();
+    let Mapping(prev_path, ..) = mappings.remove(prev_idx);
+
+    let mut combined = File::create(out)?;
+    io::copy(&mut File::open(prev_path)?, &mut combined)?;
+    io::copy(&mut File::open(tail_path)?, &mut combined)?;
+    drop(combined);
+
+    let (src, mapping) = RemoteResource::from_file(out);
+    prev.src = src;
+    mappings.push(mapping);
+    Ok(())
+}
+
+/// Concatenates the (timeline-ordered) segment files into `out` and points
+/// every segment at its byte range within it. Fragmented MP4 fragments are
+/// self-contained, so the result plays back exactly like the separate files.
+fn pack_byte_ranges(
+    segments: &mut [Segment],
+    init: Option<&str>,
+    files: &[String],
+    out: &str,
+) -> io::Result<(Mapping, Option<ByteRange>)> {
+    let mut combined = File::create(out)?;
+    let mut offset = 0;
+    let init_range = match init {
+        Some(init) => {
+            let length = io::copy(&mut File::open(init)?, &mut combined)?;
+            offset = length;
+            Some(ByteRange { offset: 0, length })
+        }
+        None => None,
+    };
+    for (segment, file) in segments.iter_mut().zip(files) {
+        let length = io::copy(&mut File::open(file)?, &mut combined)?;
+        segment.range = Some(ByteRange { offset, length });
+        offset += length;
+    }
+    drop(combined);
+
+    let (src, mapping) = RemoteResource::from_file(out);
+    for segment in segments {
+        segment.src = src.clone();
+    }
+    Ok((mapping, init_range))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RemoteResource(pub String);
 
 impl RemoteResource {
@@ -213,18 +1176,784 @@ impl<'a> Display for ResourceLocator<'a> {
     }
 }
 
-struct Mapping(String, RemoteResource);
+struct Mapping(String, RemoteResource, blake3::Hash);
+
+/// Renames every resource after the first `bytes` bytes of its hash. Two
+/// different files sharing a prefix would overwrite each other in the
+/// store, so that is an error rather than a silent loss.
+fn shorten_names(
+    package: &mut Package,
+    mappings: &mut [Mapping],
+    bytes: usize,
+) -> Result<(), String> {
+    let mut renames = HashMap::new();
+    let mut owners = HashMap::new();
+    for Mapping(path, remote, hash) in mappings.iter_mut() {
+        let extension = remote.0.rsplit_once('.').map_or("mp4", |(_, ext)| ext);
+        let short = RemoteResource::from_hash_prefix(hash, bytes, extension);
+        if let Some(other) = owners.insert(short.0.clone(), *hash)
+            && other != *hash
+        {
+            return Err(format!(
+                "{path} collides with another file at {bytes}-byte name {}",
+                short.0
+            ));
+        }
+        renames.insert(remote.0.clone(), short.clone());
+        *remote = short;
+    }
+
+    let rename = |resource: &mut RemoteResource| {
+        if let Some(short) = renames.get(&resource.0) {
+            *resource = short.clone();
+        }
+    };
+    if let Some(poster) = &mut package.poster {
+        rename(poster);
+    }
+    if let Some(thumbnails) = &mut package.thumbnails {
+        thumbnails.sheets.iter_mut().for_each(rename);
+        rename(&mut thumbnails.vtt);
+    }
+    for variant in &mut package.variants {
+        if let Some(init) = &mut variant.init_src {
+            rename(init);
+        }
+        for segment in &mut variant.segments {
+            rename(&mut segment.src);
+        }
+    }
+    Ok(())
+}
 
 impl RemoteResource {
+    /// Names the file after its content, keeping its extension so the
+    /// store serves it with the right type.
     fn from_file(file: &str) -> (Self, Mapping) {
         let mut hasher = blake3::Hasher::new();
         hasher.update_mmap(file).unwrap();
-        let resource = Self::from_hash(hasher.finalize());
-        (resource.clone(), Mapping(file.to_string(), resource))
+        let extension = Path::new(file)
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_or("mp4");
+        let hash = hasher.finalize();
+        let resource = Self::from_hash_prefix(&hash, blake3::OUT_LEN, extension);
+        (resource.clone(), Mapping(file.to_string(), resource, hash))
+    }
+
+    fn from_hash_prefix(hash: &blake3::Hash, bytes: usize, extension: &str) -> Self {
+        let b64 = URL_SAFE_NO_PAD.encode(&hash.as_bytes()[..bytes]);
+        Self(format!("{b64}.{extension}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn probed(name: &str, start: u64, duration_ts: u64) -> Segment {
+        Segment {
+            src: RemoteResource(name.to_string()),
+            start,
+            duration: duration_ts,
+            range: None,
+            size: None,
+            keyframe: None,
+        }
+    }
+
+    #[test]
+    fn test_concatenated_clips_are_contiguous() {
+        // Two 2-segment clips joined by the concat demuxer: the second clip's
+        // segments carry on from where the first one ended.
+        let mut segments = vec![
+            probed("c.mp4", 1400, 1400),
+            probed("a.mp4", 400, 400),
+            probed("d.mp4", 1800, 2200),
+            probed("b.mp4", 800, 1000),
+        ];
+        normalize_timeline(&mut segments);
+
+        let names = segments
+            .iter()
+            .map(|s| s.src.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.mp4", "b.mp4", "c.mp4", "d.mp4"]);
+        let durations = segments.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [400, 600, 400, 800]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not contiguous")]
+    fn test_gap_between_clips_is_rejected() {
+        let mut segments = vec![probed("a.mp4", 0, 400), probed("b.mp4", 500, 900)];
+        normalize_timeline(&mut segments);
+    }
+
+    #[test]
+    fn test_pack_byte_ranges() {
+        let dir = std::env::temp_dir().join("frameserve-byte-range-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let files = [(b"first" as &[u8], "s0.mp4"), (b"second!", "s1.mp4")]
+            .iter()
+            .map(|(contents, name)| {
+                let path = dir.join(name);
+                fs::write(&path, contents).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let mut segments = vec![probed("a.mp4", 0, 400), probed("b.mp4", 400, 400)];
+        let out = dir.join("combined.mp4");
+        let (Mapping(path, remote, _), init_range) =
+            pack_byte_ranges(&mut segments, None, &files, out.to_str().unwrap()).unwrap();
+
+        assert_eq!(fs::read(path).unwrap(), b"firstsecond!");
+        assert_eq!(init_range, None);
+        assert!(segments.iter().all(|segment| segment.src.0 == remote.0));
+        assert_eq!(
+            segments[0].range,
+            Some(ByteRange {
+                offset: 0,
+                length: 5
+            })
+        );
+        assert_eq!(
+            segments[1].range,
+            Some(ByteRange {
+                offset: 5,
+                length: 7
+            })
+        );
+
+        // With the init segment in front, the media segments move along.
+        let init = dir.join("init.mp4");
+        fs::write(&init, b"init").unwrap();
+        let (Mapping(path, _, _), init_range) =
+            pack_byte_ranges(&mut segments, init.to_str(), &files, out.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"initfirstsecond!");
+        assert_eq!(
+            init_range,
+            Some(ByteRange {
+                offset: 0,
+                length: 4
+            })
+        );
+        assert_eq!(
+            segments[0].range,
+            Some(ByteRange {
+                offset: 4,
+                length: 5
+            })
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_short_final_segment_is_merged() {
+        let dir = std::env::temp_dir().join("frameserve-merge-tail-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut segments = Vec::new();
+        let mut mappings = Vec::new();
+        for (i, (contents, duration)) in [("aa", 10000), ("bb", 10000), ("c", 200)]
+            .into_iter()
+            .enumerate()
+        {
+            let path = dir.join(format!("s{i}.mp4"));
+            fs::write(&path, contents).unwrap();
+            let (src, mapping) = RemoteResource::from_file(path.to_str().unwrap());
+            mappings.push(mapping);
+            segments.push(Segment {
+                src,
+                start: i as u64 * 10000,
+                duration,
+                range: None,
+                size: Some(contents.len() as u64),
+                keyframe: Some(true),
+            });
+        }
+
+        let out = dir.join("merged.mp4");
+        merge_short_tail(&mut segments, &mut mappings, 1000, out.to_str().unwrap()).unwrap();
+
+        let durations = segments.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [10000, 10200]);
+        assert_eq!(mappings.len(), 2);
+        let Mapping(path, remote, _) = mappings.last().unwrap();
+        assert_eq!(remote.0, segments[1].src.0);
+        assert_eq!(fs::read(path).unwrap(), b"bbc");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_final_segment_fits_stream_length() {
+        let segment = |start, duration| Segment {
+            src: RemoteResource(format!("s{start}.mp4")),
+            start,
+            duration,
+            range: None,
+            size: None,
+            keyframe: None,
+        };
+        let total = |segments: &[Segment]| segments.iter().map(|s| s.duration).sum::<u64>();
+
+        // The last fragment's length probed a couple of ticks short.
+        let mut segments = vec![
+            segment(0, 10000),
+            segment(10000, 10000),
+            segment(20000, 4998),
+        ];
+        fit_final_segment(&mut segments, 25000, 500);
+        assert_eq!(total(&segments), 25000);
+        assert_eq!(segments[2].duration, 5000);
+
+        // And long.
+        segments[2].duration = 5003;
+        fit_final_segment(&mut segments, 25000, 500);
+        assert_eq!(total(&segments), 25000);
+
+        // Too far out to be rounding.
+        fit_final_segment(&mut segments, 30000, 500);
+        assert_eq!(total(&segments), 25000);
+    }
+
+    #[test]
+    fn test_inspect_cache_skips_unchanged_segments() {
+        let path = std::env::temp_dir().join("frameserve-inspect-cache-test.json");
+        let _ = fs::remove_file(&path);
+
+        let init = RemoteResource("init.mp4".to_string());
+        let segments = ["a.mp4", "b.mp4", "c.mp4"].map(|s| RemoteResource(s.to_string()));
+        let run = |cache: &mut InspectCache| {
+            segments
+                .iter()
+                .enumerate()
+                .map(|(i, segment)| {
+                    cache.timing(Some(&init), segment, true, || Timing {
+                        start_pts: i as u64 * 400,
+                        duration_ts: (i as u64 + 1) * 400,
+                        keyframe: Some(true),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut first = InspectCache::load(&path, ProbeMode::PerSegment);
+        let timings = run(&mut first);
+        assert_eq!(first.probes, 3);
+        first.save().unwrap();
+
+        let mut second = InspectCache::load(&path, ProbeMode::PerSegment);
+        assert_eq!(run(&mut second), timings);
+        assert_eq!(second.probes, 0);
+
+        // A re-encoded init segment changes what ffprobe would say.
+        let timing = second.timing(
+            Some(&RemoteResource("init2.mp4".to_string())),
+            &segments[0],
+            true,
+            || Timing {
+                start_pts: 0,
+                duration_ts: 500,
+                keyframe: Some(true),
+            },
+        );
+        assert_eq!(timing.duration_ts, 500);
+        assert_eq!(second.probes, 1);
+
+        // Video timings cached before keyframes were checked are redone.
+        second.entries.get_mut("init.mp4+a.mp4").unwrap().keyframe = None;
+        assert!(!second.contains(Some(&init), &segments[0], true));
+        assert!(second.contains(Some(&init), &segments[0], false));
+        run(&mut second);
+        assert_eq!(second.probes, 2);
+
+        // Timings from packets may not match the stream's, so aren't shared.
+        let mut packets = InspectCache::load(&path, ProbeMode::Packets);
+        run(&mut packets);
+        assert_eq!(packets.probes, 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_split_batched_timings() {
+        let packet = |pts, duration, pos| Packet {
+            pts: Some(pts),
+            duration: Some(duration),
+            pos: Some(pos),
+            flags: Some(
+                if [100, 300].contains(&pos) {
+                    "K__"
+                } else {
+                    "___"
+                }
+                .to_string(),
+            ),
+        };
+        // A 100-byte init segment, then three segments. B-frames put the
+        // packets out of presentation order within each segment.
+        let packets = [
+            packet(0, 100, 100),
+            packet(200, 100, 150),
+            packet(100, 100, 180),
+            packet(300, 100, 300),
+            packet(500, 100, 320),
+            packet(400, 100, 390),
+            Packet {
+                pts: None,
+                duration: None,
+                pos: Some(400),
+                flags: None,
+            },
+            packet(600, 50, 420),
+        ];
+        let timings = split_timings(&packets, &[100, 300, 400]);
+
+        let timing = |start_pts, duration_ts, keyframe| Timing {
+            start_pts,
+            duration_ts,
+            keyframe: Some(keyframe),
+        };
+        // The last segment's first packet in decode order isn't a keyframe.
+        assert_eq!(
+            timings,
+            [
+                timing(0, 300, true),
+                timing(300, 600, true),
+                timing(600, 650, false)
+            ]
+        );
+
+        // The same shape the per-segment probe produces, so the timeline
+        // comes out the same.
+        let mut segments = timings
+            .iter()
+            .enumerate()
+            .map(|(i, t)| probed(&format!("s{i}.mp4"), t.start_pts, t.duration_ts))
+            .collect::<Vec<_>>();
+        normalize_timeline(&mut segments);
+        let durations = segments.iter().map(|s| s.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [300, 300, 50]);
+    }
+
+    /// ffprobe's packets for a 25fps variant of three two-frame segments
+    /// after a 48-byte init segment, and what it reports of each segment's
+    /// stream when probed with the init segment.
+    const KNOWN_PACKETS: &str = r#"[
+        {"pts": 0, "duration": 512, "pos": "48", "flags": "K__"},
+        {"pts": 512, "duration": 512, "pos": "900", "flags": "___"},
+        {"pts": 1024, "duration": 512, "pos": "1048", "flags": "K__"},
+        {"pts": 1536, "duration": 512, "pos": "1700", "flags": "___"},
+        {"pts": 2048, "duration": 512, "pos": "2048", "flags": "K__"},
+        {"pts": 2560, "duration": 512, "pos": "2300", "flags": "___"}
+    ]"#;
+    const KNOWN_STREAMS: [(u64, u64); 3] = [(0, 1024), (1024, 2048), (2048, 3072)];
+
+    #[test]
+    fn test_probe_modes_agree() {
+        let packets = serde_json::from_str::<Vec<Packet>>(KNOWN_PACKETS).unwrap();
+        let per_segment = KNOWN_STREAMS.map(|(start_pts, duration_ts)| Timing {
+            start_pts,
+            duration_ts,
+            keyframe: Some(true),
+        });
+
+        let batched = split_timings(&packets, &[48, 1048, 2048]);
+        assert_eq!(batched, per_segment);
+
+        let mut first_start = None;
+        let by_packets = packets
+            .chunks(2)
+            .map(|segment| {
+                let timing = packet_timing(segment, first_start).unwrap();
+                first_start.get_or_insert(timing.start_pts);
+                timing
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(by_packets, per_segment);
+    }
+
+    #[test]
+    fn test_packet_timing() {
+        let packet = |pts, duration, flags: &str| Packet {
+            pts: Some(pts),
+            duration: Some(duration),
+            pos: None,
+            flags: Some(flags.to_string()),
+        };
+        // The middle of three 300-tick segments starting at 1000, probed on
+        // its own. Its stream header reports start_pts 1300, and
+        // duration_ts 600 from the first segment's start.
+        let stream = Timing {
+            start_pts: 1300,
+            duration_ts: 600,
+            keyframe: Some(true),
+        };
+        let packets = [
+            packet(1300, 100, "K__"),
+            packet(1500, 100, "___"),
+            packet(1400, 100, "___"),
+        ];
+        assert_eq!(packet_timing(&packets, Some(1000)), Some(stream));
+
+        // The first segment has nothing before it to measure from.
+        let first = packet_timing(&packets[..1], None).unwrap();
+        assert_eq!((first.start_pts, first.duration_ts), (1300, 100));
+
+        let untimed = Packet {
+            pts: None,
+            ..packet(0, 0, "K__")
+        };
+        assert_eq!(packet_timing(&[untimed], Some(1000)), None);
+    }
+
+    #[test]
+    fn test_playlist_timings() {
+        let m3u8 = "#EXTM3U\n\
+            #EXT-X-VERSION:7\n\
+            #EXT-X-TARGETDURATION:4\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXTINF:3.336667,\n\
+            s00000.mp4\n\
+            #EXTINF:3.336667,\n\
+            s00001.mp4\n\
+            #EXTINF:1.001000,\n\
+            s00002.mp4\n\
+            #EXT-X-ENDLIST\n";
+        let timings = playlist_timings(m3u8, Ratio::new(1, 90000), 1000);
+        let timing = |name| {
+            let timing = timings[name];
+            (timing.start_pts, timing.duration_ts)
+        };
+        // As ffprobe measures them: ends from the first segment's start.
+        assert_eq!(timing("s00000.mp4"), (1000, 300300));
+        assert_eq!(timing("s00001.mp4"), (301300, 600600));
+        assert_eq!(timing("s00002.mp4"), (601600, 690690));
+
+        // ffprobe puts the second segment a tick out, within a frame.
+        let probed = Timing {
+            start_pts: 301301,
+            duration_ts: 600601,
+            keyframe: Some(true),
+        };
+        let tolerance = (PLAYLIST_TOLERANCE * 90000.0) as u64;
+        assert!(timings["s00001.mp4"].is_near(&probed, tolerance));
+        assert!(!timings["s00002.mp4"].is_near(&probed, tolerance));
+
+        let sources = ["c", "a", "e", "b", "d"].map(|name| RemoteResource(name.to_string()));
+        assert_eq!(playlist_samples(&sources), [0, 1, 3, 4]);
+
+        // The sampled segment keeps its listed timing, so the timeline
+        // stays contiguous with the segments around it.
+        let mut segments = ["s00000.mp4", "s00001.mp4", "s00002.mp4"].map(|name| {
+            let timing = match name {
+                "s00001.mp4" => sampled_timing(name, timings[name], probed, tolerance),
+                _ => timings[name],
+            };
+            Segment {
+                src: RemoteResource(name.to_string()),
+                start: timing.start_pts,
+                duration: timing.duration_ts,
+                range: None,
+                size: None,
+                keyframe: timing.keyframe,
+            }
+        });
+        normalize_timeline(&mut segments);
+        assert_eq!(
+            segments.map(|segment| (segment.start, segment.duration, segment.keyframe)),
+            [
+                (1000, 300300, None),
+                (301300, 300300, Some(true)),
+                (601600, 90090, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_av_drift() {
+        let variant = |kind, bitrate, segments: &[u64]| Variant {
+            init_src: None,
+            init_range: None,
+            time_base: Ratio::new(1, 1000),
+            bitrate,
+            kind,
+            channels: None,
+            language: None,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs: None,
+            segments: segments
+                .iter()
+                .enumerate()
+                .map(|(i, duration)| probed(&format!("s{i}.mp4"), 0, *duration))
+                .collect(),
+        };
+        let video = |bitrate, segments| {
+            let kind = VariantKind::Video {
+                width: 1920,
+                height: 1080,
+            };
+            variant(kind, bitrate, segments)
+        };
+        let package = |variants| Package {
+            vid: 1,
+            packaged_at: Timestamp::UNIX_EPOCH,
+            variants,
+            poster: None,
+            thumbnails: None,
+            repeats: None,
+        };
+
+        // AAC frames don't divide 10s evenly, so the audio runs a little long.
+        let mismatched = package(vec![
+            video(5000000, &[10000, 10000, 4000]),
+            video(400000, &[10000, 10000, 4000]),
+            variant(VariantKind::Audio, 192000, &[10005, 10005, 4011]),
+            variant(VariantKind::Audio, 384000, &[10000, 10000, 3990]),
+        ]);
+        let drift = mismatched.av_drift().unwrap();
+        assert!((drift - 0.021).abs() < 1e-9, "{drift}");
+
+        let aligned = package(vec![
+            video(5000000, &[10000, 4000]),
+            variant(VariantKind::Audio, 192000, &[10000, 4000]),
+        ]);
+        assert_eq!(aligned.av_drift(), Some(0.0));
+
+        let silent = package(vec![video(5000000, &[10000])]);
+        assert_eq!(silent.av_drift(), None);
+    }
+
+    #[test]
+    fn test_measured_bitrate() {
+        let sized = |duration, size| Segment {
+            size: Some(size),
+            ..probed("s.mp4", 0, duration)
+        };
+        let mut variant = Variant {
+            init_src: None,
+            init_range: None,
+            time_base: Ratio::new(1, 1000),
+            bitrate: 5000000,
+            kind: VariantKind::Video {
+                width: 1920,
+                height: 1080,
+            },
+            channels: None,
+            language: None,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs: None,
+            segments: vec![
+                sized(10000, 5000000),
+                sized(10000, 8000000),
+                sized(5000, 1000000),
+            ],
+        };
+        // 14MB over 25s, with the second segment the densest at 6.4Mbps.
+        assert_eq!(
+            variant.measured_bitrate(),
+            Some(MeasuredBitrate {
+                average: 4480000,
+                peak: 6400000
+            })
+        );
+
+        variant.segments[2].size = None;
+        assert_eq!(variant.measured_bitrate(), None);
+    }
+
+    #[test]
+    fn test_duration_steps() {
+        let variant = Variant {
+            init_src: None,
+            init_range: None,
+            time_base: Ratio::new(1, 90000),
+            bitrate: 192000,
+            kind: VariantKind::Audio,
+            channels: None,
+            language: None,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs: None,
+            segments: vec![
+                probed("a.mp4", 0, 300300),
+                probed("b.mp4", 300300, 300300),
+                probed("c.mp4", 600600, 90090),
+            ],
+        };
+        // Alongside 48kHz audio.
+        let step = StepSize::calculate([Ratio::new(1, 90000), Ratio::new(1, 48000)].into_iter());
+        assert_eq!(variant.raw_duration(), 690690);
+        let steps = variant
+            .segments
+            .iter()
+            .map(|segment| Duration::new(segment.duration(), variant.time_base, step))
+            .fold(Duration::zero(), Duration::add);
+        assert_eq!(variant.duration_steps(step), steps);
+        assert_eq!(
+            variant.duration_steps(step).to_seconds(step),
+            7.674333333333333
+        );
+    }
+
+    #[test]
+    fn test_save_is_atomic() {
+        let dir = std::env::temp_dir().join("frameserve-save-package");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let package = |segments: usize| Package {
+            vid: 7,
+            packaged_at: Timestamp::UNIX_EPOCH,
+            variants: vec![Variant {
+                init_src: None,
+                init_range: None,
+                time_base: Ratio::new(1, 1000),
+                bitrate: 192000,
+                kind: VariantKind::Audio,
+                channels: None,
+                language: None,
+                cues: Vec::new(),
+                ad_breaks: Vec::new(),
+                codecs: None,
+                segments: (0..segments)
+                    .map(|i| probed(&format!("s{i}.mp4"), i as u64 * 1000, 1000))
+                    .collect(),
+            }],
+            poster: None,
+            thumbnails: None,
+            repeats: None,
+        };
+        package(1).save(&dir).unwrap();
+
+        // A reader keeps loading the package while it's rewritten, larger
+        // each time, and only ever finds one whole package or another.
+        let path = format!("{dir}/7.json");
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                // Reads at least once, however soon the writer finishes.
+                let mut reads = 0;
+                loop {
+                    let finished = done.load(Ordering::Relaxed);
+                    let src = fs::read_to_string(&path).unwrap();
+                    let read = serde_json::from_str::<Package>(&src).unwrap();
+                    assert!((1..=200).contains(&read.variants[0].segments.len()));
+                    reads += 1;
+                    if finished {
+                        break reads;
+                    }
+                }
+            });
+            for segments in 2..=200 {
+                package(segments).save(&dir).unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            assert!(reader.join().unwrap() > 0);
+        });
+        assert_eq!(Package::from_file(&path).variants[0].segments.len(), 200);
+        assert!(!Path::new(&format!("{dir}/7.tmp")).exists());
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn from_hash(hash: blake3::Hash) -> Self {
-        let b64 = URL_SAFE_NO_PAD.encode(hash.as_bytes());
-        Self(format!("{b64}.mp4"))
+    #[test]
+    fn test_thumbnails_vtt() {
+        let sheets = [
+            RemoteResource("a.jpg".to_string()),
+            RemoteResource("b.jpg".to_string()),
+        ];
+        let layout = TileLayout {
+            columns: 2,
+            rows: 2,
+            ..TileLayout::new(5)
+        };
+
+        let vtt = thumbnails_vtt(layout, 22.5, &sheets);
+        let cues = vtt.split("\n\n").collect::<Vec<_>>();
+        assert_eq!(cues[0], "WEBVTT");
+        assert_eq!(cues.len(), 1 + 5);
+        assert_eq!(
+            cues[1],
+            "00:00:00.000 --> 00:00:05.000\na.jpg#xywh=0,0,160,90"
+        );
+        assert_eq!(
+            cues[4],
+            "00:00:15.000 --> 00:00:20.000\na.jpg#xywh=160,90,160,90"
+        );
+        assert_eq!(
+            cues[5],
+            "00:00:20.000 --> 00:00:22.500\nb.jpg#xywh=0,0,160,90\n"
+        );
+
+        // Shorter than one interval: a single tile for the whole video.
+        let vtt = thumbnails_vtt(layout, 3.0, &sheets);
+        assert!(vtt.ends_with("\n00:00:00.000 --> 00:00:03.000\na.jpg#xywh=0,0,160,90\n"));
+    }
+
+    #[test]
+    fn test_shortened_names_detect_collisions() {
+        let hash = |first, last| {
+            let mut bytes = [7; blake3::OUT_LEN];
+            bytes[0] = first;
+            bytes[blake3::OUT_LEN - 1] = last;
+            blake3::Hash::from_bytes(bytes)
+        };
+        let mapping = |name: &str, hash| {
+            Mapping(
+                format!("encodes/{name}"),
+                RemoteResource::from_hash_prefix(&hash, blake3::OUT_LEN, "mp4"),
+                hash,
+            )
+        };
+        let packaged = |mappings: &[Mapping]| Package {
+            vid: 1,
+            packaged_at: Timestamp::UNIX_EPOCH,
+            variants: vec![Variant {
+                init_src: Some(mappings[0].1.clone()),
+                init_range: None,
+                time_base: Ratio::new(1, 1000),
+                bitrate: 192000,
+                kind: VariantKind::Audio,
+                channels: None,
+                language: None,
+                cues: Vec::new(),
+                ad_breaks: Vec::new(),
+                codecs: None,
+                segments: vec![probed(&mappings[1].1.0, 0, 1000)],
+            }],
+            poster: Some(mappings[2].1.clone()),
+            thumbnails: None,
+            repeats: None,
+        };
+
+        let mut mappings = [
+            mapping("init.mp4", hash(1, 0)),
+            mapping("s0.mp4", hash(2, 0)),
+            mapping("poster.mp4", hash(3, 0)),
+        ];
+        let mut package = packaged(&mappings);
+        shorten_names(&mut package, &mut mappings, 16).unwrap();
+        assert_eq!(mappings[0].1.0.len(), 22 + ".mp4".len());
+        let variant = &package.variants[0];
+        assert_eq!(variant.init_src.as_ref().unwrap().0, mappings[0].1.0);
+        assert_eq!(variant.segments[0].src.0, mappings[1].1.0);
+        assert_eq!(package.poster.unwrap().0, mappings[2].1.0);
+
+        // Distinct files that only differ past the prefix.
+        let mut mappings = [
+            mapping("init.mp4", hash(0, 1)),
+            mapping("s0.mp4", hash(0, 2)),
+            mapping("poster.mp4", hash(3, 0)),
+        ];
+        let mut package = packaged(&mappings);
+        let err = shorten_names(&mut package, &mut mappings, 31).unwrap_err();
+        assert!(err.contains("encodes/s0.mp4 collides"), "{err}");
     }
 }
@@ -1,49 +1,202 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::{self, Write as _},
     fs,
     ops::Range,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
 };
 
-use jiff::{Timestamp, Unit};
-use num::rational::Ratio;
+use jiff::{SignedDuration, Timestamp, Unit};
+use num::{ToPrimitive, rational::Ratio};
+use serde::Serialize;
 
 use crate::{
-    config::get_config,
+    config::{AudioRung, Config, MasterOrder, PackageOrder, StoreConfig, get_config},
     duration::{Duration, StepSize},
-    package::{Package, RemoteResource, Segment, Variant, VariantKind},
+    package::{AdBreak, ByteRange, Package, RemoteResource, Segment, Variant, VariantKind},
+    recipe::TileLayout,
     schedule::{Item, Schedule},
+    scte35,
+    sequence::Sequence,
 };
 
-const N_STREAMS: usize = 4;
+/// How many segments a variant playlist lists, unless `window_seconds` is
+/// set.
 const LOOKAHEAD: usize = 16;
+/// The most an EVENT playlist lists before it ends, about a day of 10s
+/// segments; see `event_playlists` in the config.
+const MAX_EVENT_SEGMENTS: usize = 10_000;
+/// Rough sizes, in bytes, of a variant playlist's header tags and of the
+/// tags ahead of each segment's URI; see [`Stream::capacity`].
+const PLAYLIST_HEADER_BYTES: usize = 256;
+const SEGMENT_TAG_BYTES: usize = 64;
+
+/// `CODECS` for packages that predate probing them.
+const DEFAULT_VIDEO_CODECS: &str = "avc1.64e01f";
+const DEFAULT_AUDIO_CODECS: &str = "mp4a.40.2";
+const MAX_LOOP_AV_DRIFT: f64 = 0.5;
+/// How far, in seconds, a `loop_crossfade` transition may be from whole
+/// segments at either end of the loop.
+const CROSSFADE_TOLERANCE: f64 = 0.1;
 
 pub struct Playlist {
     start: Timestamp,
     sources: BTreeMap<Duration, (Duration, usize)>,
     step: StepSize,
-    pub streams: [Stream; N_STREAMS],
+    pub streams: Vec<Stream>,
+    /// Trick-play sprite sheets, when every package has them.
+    pub images: Option<Stream>,
     duration: Duration,
     items: Vec<Item>,
+    posters: BTreeMap<u32, RemoteResource>,
+    /// Whether every segment is known not to need the one before it; see
+    /// [`MasterPlaylist::independent_segments`].
+    independent_segments: bool,
+    /// Whether sources follow one another without discontinuities, short of
+    /// the loop starting over or a takeover; see `continuous` in the config.
+    continuous: bool,
+    /// The `<vid>/<file>` keys of the files in each package's directory of
+    /// a local store, listed once at load; see [`Self::has_blob`].
+    blobs: Option<BTreeSet<String>>,
+    /// The `<vid>/<file>` media keys of every init segment; see
+    /// [`Self::is_init`].
+    inits: BTreeSet<String>,
+    clock: Clock,
+    /// Set once a request has come in before `start`, which is only warned
+    /// about the first time.
+    early: AtomicBool,
+    config: &'static Config,
+}
+
+/// Wall-clock time as of loading, advanced by a monotonic clock since, so
+/// the system clock being stepped backwards (e.g. by NTP) can't rewind the
+/// stream.
+struct Clock {
+    instant: Instant,
+    timestamp: Timestamp,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            timestamp: Timestamp::now(),
+        }
+    }
+
+    fn now(&self) -> Timestamp {
+        self.timestamp + SignedDuration::try_from(self.instant.elapsed()).unwrap()
+    }
 }
 
 impl Playlist {
     pub fn load(start: Timestamp, packages_dir: &str) -> Self {
+        Self::load_with_config(start, packages_dir, get_config())
+    }
+
+    pub fn load_with_config(start: Timestamp, packages_dir: &str, config: &'static Config) -> Self {
+        Self::with_config(start, Self::load_packages(packages_dir, config), config)
+    }
+
+    /// The packages `playlist.txt` lists that the config allows; none when
+    /// nothing has been packaged yet.
+    pub fn load_packages(packages_dir: &str, config: &Config) -> Vec<Package> {
         let mut packages = Vec::new();
 
-        let playlist = fs::read_to_string(format!("{packages_dir}/playlist.txt")).unwrap();
+        let Ok(playlist) = fs::read_to_string(format!("{packages_dir}/playlist.txt")) else {
+            return packages;
+        };
         for line in playlist.lines() {
             let mut parts = line.split_whitespace();
             let vid = parts.next().unwrap().parse::<u32>().unwrap();
+            if !config.packages.allows(vid) {
+                eprintln!("Skipped package {vid}");
+                continue;
+            }
             let filename = format!("{packages_dir}/{vid}.json");
             packages.push(Package::from_file(&filename));
-            println!("Loaded package {vid} from {filename}");
+            eprintln!("Loaded package {vid} from {filename}");
         }
 
-        Self::new(start, packages)
+        // The transition needn't be listed, as it only plays at the loop.
+        if let Some(vid) = config.loop_crossfade
+            && !packages.is_empty()
+            && !packages.iter().any(|package| package.vid == vid)
+        {
+            let filename = format!("{packages_dir}/{vid}.json");
+            if Path::new(&filename).exists() {
+                packages.push(Package::from_file(&filename));
+                eprintln!("Loaded loop transition {vid} from {filename}");
+            }
+        }
+        packages
     }
 
     pub fn new(start: Timestamp, packages: Vec<Package>) -> Self {
+        Self::with_config(start, packages, get_config())
+    }
+
+    pub fn with_config(start: Timestamp, packages: Vec<Package>, config: &'static Config) -> Self {
+        assert!(
+            !packages.is_empty(),
+            "no packages to play; set allow_empty to wait for some"
+        );
+        let mut packages = dedup_packages(packages);
+        match config.package_order {
+            PackageOrder::Listed => {}
+            PackageOrder::Vid => packages.sort_by_key(|package| package.vid),
+            PackageOrder::PackagedAt => {
+                packages.sort_by_key(|package| (package.packaged_at, package.vid));
+            }
+        }
+        // An empty or corrupt variant would make for a loop with nothing in
+        // it, which the playhead can't be placed in.
+        for package in &packages {
+            assert!(
+                !package.variants.is_empty(),
+                "package {} has no variants",
+                package.vid
+            );
+            if let Some(variant) = package
+                .variants
+                .iter()
+                .find(|variant| variant.raw_duration() == 0)
+            {
+                panic!(
+                    "package {}'s {}bps variant has no duration; re-package it or leave it out",
+                    package.vid, variant.bitrate
+                );
+            }
+            // fMP4 segments can't be played without their init segment.
+            assert!(
+                config.player().map
+                    || package
+                        .variants
+                        .iter()
+                        .all(|variant| variant.init_src.is_none()),
+                "package {} needs EXT-X-MAP, which the player profile leaves out; package it as TS instead",
+                package.vid
+            );
+        }
+        // A package that repeats is laid out again for each play, as a
+        // source and item of its own, so everything after treats each play
+        // like a different package.
+        let packages = packages
+            .into_iter()
+            .flat_map(|package| {
+                let plays = package.plays();
+                std::iter::repeat_n(package, plays)
+            })
+            .collect::<Vec<_>>();
+        let packages = match config.loop_crossfade {
+            Some(vid) => crossfade_loop(packages, vid),
+            None => packages,
+        };
         let step = StepSize::calculate(
             packages
                 .iter()
@@ -51,122 +204,759 @@ impl Playlist {
         );
 
         let mut sources = BTreeMap::default();
-        let mut streams = [
+        let mut streams = vec![
             Stream::new_video(1920, 1080, 5000000),
             Stream::new_video(1280, 720, 1500000),
             Stream::new_video(960, 540, 400000),
-            Stream::new_audio(192000),
         ];
 
+        // Each item's audio and video are cut to the same boundaries, so any
+        // difference in their lengths accumulates over a loop.
+        let loop_drift = packages.iter().filter_map(Package::av_drift).sum::<f64>();
+        if loop_drift.abs() > MAX_LOOP_AV_DRIFT {
+            eprintln!("WARNING: audio/video drift of {loop_drift:+.3}s over the loop");
+        }
+
+        // Every item must supply a segment for every stream, so surround is
+        // only offered when the whole playlist has it.
+        let has_surround = packages.iter().all(|package| {
+            package
+                .variants
+                .iter()
+                .any(|variant| variant.kind == VariantKind::Audio && variant.channels() > 2)
+        });
+        match &config.audio_rungs {
+            Some(rungs) => streams.extend(rungs.iter().cloned().map(Stream::new_audio)),
+            None => {
+                streams.push(Stream::new_audio(AudioRung::stereo()));
+                if has_surround {
+                    streams.push(Stream::new_audio(AudioRung::surround()));
+                }
+            }
+        }
+
+        let independent_segments = packages
+            .iter()
+            .flat_map(|package| &package.variants)
+            .flat_map(|variant| &variant.segments)
+            .all(|segment| segment.keyframe != Some(false));
+        if !independent_segments {
+            eprintln!("WARNING: not every segment starts with a keyframe");
+        }
+
+        let posters = packages
+            .iter()
+            .filter_map(|package| Some((package.vid, package.poster.clone()?)))
+            .collect();
+
         let mut running_playlist_duration = Duration::zero();
         let mut items = Vec::with_capacity(packages.len());
+        let mut lengths = BTreeMap::default();
+        // The time base and codecs of each stream's first variant, which
+        // every other must share for `continuous` to hold.
+        let mut encodings = vec![None; streams.len()];
+        // Where each stream's timestamps have got to, which the next segment
+        // has to start from too.
+        let mut ends = vec![None; streams.len()];
+        let mut compatible = true;
         for (pi, package) in packages.iter().enumerate() {
+            // Each piece between cues is a source of its own, so every
+            // stream has to cut the package the same number of times.
+            let piece_counts = package
+                .variants
+                .iter()
+                .map(|variant| variant.pieces().len())
+                .collect::<Vec<_>>();
+            let use_cues = piece_counts.windows(2).all(|pair| pair[0] == pair[1]);
+            if !use_cues {
+                eprintln!(
+                    "WARNING: cues cut the variants of package {} into {piece_counts:?} pieces; ignoring them",
+                    package.vid
+                );
+            }
+
+            // Sources are looked up by index across streams, so each needs
+            // exactly one variant of every package.
+            let mut filled = vec![0; streams.len()];
             for variant in &package.variants {
-                let stream = streams
+                let fits = |stream: &Stream| stream.kind == variant.kind && stream.carries(variant);
+                // Audio rungs can differ only in bitrate, so take the
+                // closest one that fits.
+                let Some((si, stream)) = streams
                     .iter_mut()
-                    .find(|stream| stream.bitrate >= variant.bitrate && stream.kind == variant.kind)
-                    .unwrap();
+                    .enumerate()
+                    .filter(|(_, stream)| stream.bitrate >= variant.bitrate && fits(stream))
+                    .min_by_key(|(_, stream)| stream.bitrate)
+                else {
+                    // Surround is only offered when every package has it.
+                    if variant.channels() > 2 && !streams.iter().any(fits) {
+                        continue;
+                    }
+                    panic!(
+                        "package {}'s {}bps variant fits none of the streams",
+                        package.vid, variant.bitrate
+                    );
+                };
+                filled[si] += 1;
+
+                // Codecs that weren't probed can't be known to match.
+                let encoding = (variant.time_base, variant.codecs.clone());
+                let first = encodings[si].get_or_insert_with(|| encoding.clone());
+                compatible &= encoding.1.is_some() && *first == encoding;
+                for segment in &variant.segments {
+                    compatible &= ends[si].is_none_or(|end| segment.start == end);
+                    ends[si] = Some(segment.start + segment.duration);
+                }
 
                 let top_stream = VariantKind::Video {
                     width: 1920,
                     height: 1080,
                 };
+                if let Some(codecs) = variant.codecs.as_deref().map(normalize_codecs)
+                    && stream
+                        .codecs
+                        .as_ref()
+                        .is_none_or(|current| codecs > *current)
+                {
+                    stream.codecs = Some(codecs);
+                }
+
+                let pieces = if use_cues {
+                    variant.pieces()
+                } else {
+                    vec![&variant.segments[..]]
+                };
+
                 if stream.kind == top_stream {
-                    let start_duration = running_playlist_duration;
-                    running_playlist_duration =
-                        running_playlist_duration.add(variant.duration(step));
-                    let end_duration = running_playlist_duration;
-                    sources.insert(end_duration, (start_duration, pi));
+                    let item_start = running_playlist_duration;
+                    let mut piece_lengths = Vec::with_capacity(pieces.len());
+                    for piece in &pieces {
+                        let length = Duration::new(
+                            piece.iter().map(Segment::duration).sum(),
+                            variant.time_base,
+                            step,
+                        );
+                        let start_duration = running_playlist_duration;
+                        running_playlist_duration = running_playlist_duration.add(length);
+                        sources.insert(running_playlist_duration, (start_duration, sources.len()));
+                        piece_lengths.push(length);
+                    }
+                    lengths.insert(pi, piece_lengths);
 
                     items.push(Item {
                         vid: package.vid,
-                        start: start_duration,
-                        duration: end_duration,
+                        start: item_start,
+                        duration: running_playlist_duration,
                     });
                 }
 
-                let stream_source =
-                    StreamSource::from_variant(package.vid, variant, stream.segments.len(), step);
-                stream.sources.push(stream_source);
+                let splices = if use_cues {
+                    splices(variant, &pieces, step)
+                } else {
+                    vec![None; pieces.len()]
+                };
+
+                let mut segment_offset = 0;
+                for (k, piece) in pieces.iter().enumerate() {
+                    // A bad segment is left out rather than taking the whole
+                    // stream down; the rest of the item still plays.
+                    let segments = piece
+                        .iter()
+                        .filter_map(|segment| {
+                            let stream_segment =
+                                StreamSegment::new(segment, variant.time_base, step);
+                            if stream_segment.is_none() {
+                                eprintln!(
+                                    "Skipped zero-duration segment {} of package {}",
+                                    segment.src.0, package.vid
+                                );
+                            }
+                            stream_segment
+                        })
+                        .collect::<Vec<_>>();
 
-                stream.segments.reserve(stream.segments.len());
-                for segment in &variant.segments {
-                    let stream_segment = StreamSegment::new(segment, variant.time_base, step);
-                    stream.segments.push(stream_segment);
+                    let stream_source = StreamSource {
+                        init_range: variant.init_range,
+                        cue: k > 0,
+                        splice: splices[k],
+                        segment_offset,
+                        ..StreamSource::new(
+                            package.vid,
+                            variant.init_src.as_ref(),
+                            &segments,
+                            stream.segments.len(),
+                        )
+                    };
+                    segment_offset += piece.len();
+                    stream.sources.push(stream_source);
+                    stream.segments.extend(segments);
                 }
             }
+            for (stream, count) in streams.iter().zip(filled) {
+                assert!(
+                    count == 1,
+                    "package {} has {count} variants for the {}bps {:?} stream, rather than one",
+                    package.vid,
+                    stream.bitrate,
+                    stream.kind
+                );
+            }
         }
 
-        Self {
+        let mut images = Stream::new_images(&packages, &lengths, step);
+
+        let continuous = config.continuous && compatible;
+        if config.continuous && !continuous {
+            eprintln!(
+                "WARNING: packages differ in time base or codecs, or their timestamps don't carry on from one to the next; marking discontinuities between them"
+            );
+        }
+
+        for stream in streams.iter_mut().chain(&mut images) {
+            let mut start = Duration::zero();
+            for segment in &mut stream.segments {
+                segment.start = start;
+                start = start.add(segment.duration);
+            }
+            stream.target_duration = stream.calculate_target_duration(step);
+            stream.capacity = stream.calculate_capacity(config);
+            let configured = config
+                .target_durations
+                .filter(|_| stream.tiles.is_none())
+                .map(|target_durations| match stream.kind {
+                    VariantKind::Video { .. } => target_durations.video,
+                    VariantKind::Audio => target_durations.audio,
+                });
+            if let Some(configured) = configured {
+                assert!(
+                    configured >= stream.target_duration,
+                    "the {} stream has {}s segments, over its target duration of {configured}s",
+                    stream.bitrate,
+                    stream.target_duration
+                );
+                stream.target_duration = configured;
+            }
+            if let Some(hold_back) = config.hold_back_seconds
+                && hold_back < stream.min_hold_back()
+            {
+                eprintln!(
+                    "WARNING: hold_back_seconds of {hold_back}s is under three target durations of the {} stream; using {}s",
+                    stream.bitrate,
+                    stream.min_hold_back()
+                );
+            }
+            // Players start that far back from the end, so a shorter window
+            // leaves them nowhere to start.
+            if let Some(window) = config.window_seconds {
+                let needed = if config.server_hold_back {
+                    stream.hold_back(config)
+                } else {
+                    stream.min_hold_back()
+                };
+                assert!(
+                    window as f64 >= needed,
+                    "window_seconds of {window}s is under the {needed}s hold-back of the {} stream",
+                    stream.bitrate
+                );
+            }
+        }
+
+        let has_byte_ranges = streams
+            .iter()
+            .flat_map(|stream| &stream.segments)
+            .any(|segment| segment.range.is_some());
+        assert!(
+            !has_byte_ranges || config.player().byte_range,
+            "byte-range packages can't be served to a player profile without EXT-X-BYTERANGE"
+        );
+
+        let blobs = index_blobs(&config.store, &packages);
+        let inits = packages
+            .iter()
+            .flat_map(|package| {
+                package.variants.iter().filter_map(|variant| {
+                    let init = variant.init_src.as_ref()?;
+                    Some(format!("{}/{}", package.vid, init.0))
+                })
+            })
+            .collect();
+
+        let playlist = Self {
             start,
             sources,
             step,
             streams,
+            images,
             duration: running_playlist_duration,
             items,
+            posters,
+            independent_segments,
+            continuous,
+            blobs,
+            inits,
+            clock: Clock::new(),
+            early: AtomicBool::new(false),
+            config,
+        };
+
+        // EVENT playlists list every segment since `start`, which for the
+        // main channel is the epoch.
+        if config.event_playlists {
+            let now = playlist.now();
+            let playhead = playlist.at(now, &Takeovers::default());
+            for stream in &playlist.streams {
+                let played = stream.event_played(&playhead);
+                assert!(
+                    played < MAX_EVENT_SEGMENTS,
+                    "event_playlists would list {played} segments since {start}; \
+                     play them from a channel with a recent start"
+                );
+            }
         }
+        playlist
     }
 }
 
-struct Playhead {
+/// Where in the loop a point in time falls.
+pub struct Playhead {
     discontinuity: usize,
+    /// The discontinuity marked before its source; see [`QueueItem::run`].
+    run: usize,
     loop_index: usize,
     source_index: usize,
     offset_in_source: Duration,
+    /// Set while a takeover is to come or playing.
+    cut: Option<Cut>,
+    /// The takeovers played out before this point, which sequence numbers
+    /// have to count too.
+    taken_over: TakenOver,
+}
+
+/// Where a [`Takeover`] leaves the loop and picks it up again.
+#[derive(Debug, Clone)]
+struct Cut {
+    /// The takeover's sources ahead of the current one, when the current
+    /// one is among them.
+    played: Option<Range<usize>>,
+    /// The takeover's sources still to come after the current one.
+    upcoming: Range<usize>,
+    resume_source: usize,
+}
+
+/// A package played once, cutting into the loop at the end of whichever
+/// source is playing when it's asked for. The loop then picks up where it
+/// was cut, so everything after plays that much later than it would have.
+///
+/// Sequence numbers carry on counting through the takeover: each of its
+/// sources is a discontinuity, and its segments add to the media sequence
+/// of every segment after. Takeovers are held in memory only, so a restart
+/// puts the loop back where the clock says, relying on `sequence_state` to
+/// keep sequence numbers from going backwards.
+#[derive(Debug, Clone)]
+struct Takeover {
+    /// Loop time, counted from `start` without any takeovers, when it
+    /// begins.
+    start: Duration,
+    duration: Duration,
+    sources: Range<usize>,
+    /// Of the source it cuts into.
+    discontinuity: usize,
+    /// Of the source it cuts into; see [`QueueItem::run`].
+    run: usize,
+    resume_loop: usize,
+    resume_source: usize,
+}
+
+/// The takeovers asked of a [`Playlist`], which is never changed once
+/// loaded, so they're kept beside it, e.g. in the server's state. Only the
+/// latest is kept whole; the ones before it have played out, so are only
+/// counted in [`TakenOver`].
+#[derive(Debug, Default)]
+pub struct Takeovers(Mutex<TakeoverState>);
+
+#[derive(Debug, Default)]
+struct TakeoverState {
+    played: TakenOver,
+    latest: Option<Takeover>,
+}
+
+/// What the takeovers played out before some point add up to: everything
+/// after plays that much later, and counts its sequence numbers on from
+/// them.
+#[derive(Debug, Clone, Default)]
+struct TakenOver {
+    duration: Duration,
+    /// How many times each source has been played by takeovers.
+    plays: BTreeMap<usize, usize>,
+    /// How many times they've broken the timestamps, going in and coming
+    /// out; see [`QueueItem::run`].
+    breaks: usize,
+}
+
+impl TakenOver {
+    fn and(&self, takeover: &Takeover) -> Self {
+        let mut taken_over = self.clone();
+        taken_over.duration = taken_over.duration.add(takeover.duration);
+        for source in takeover.sources.clone() {
+            *taken_over.plays.entry(source).or_default() += 1;
+        }
+        // Coming out where the loop starts over is the same break as that.
+        taken_over.breaks += 1 + usize::from(takeover.resume_source != 0);
+        taken_over
+    }
+
+    /// Every source played, each a discontinuity of its own.
+    fn sources(&self) -> usize {
+        self.plays.values().sum()
+    }
+}
+
+/// When a takeover plays, as asked for with [`Playlist::take_over`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TakeoverWindow {
+    pub vid: u32,
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeoverError {
+    UnknownVid,
+    /// Another takeover is still to come or playing.
+    Busy,
+}
+
+impl fmt::Display for TakeoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TakeoverError::UnknownVid => write!(f, "no such vid in the playlist"),
+            TakeoverError::Busy => write!(f, "another takeover hasn't finished yet"),
+        }
+    }
 }
 
 impl Playlist {
-    fn at(&self, now: Timestamp) -> Playhead {
-        let config = get_config();
+    /// The time to serve playlists for; see [`Clock`].
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// A time before `start` is held at the start rather than wrapping.
+    /// Takeovers before the latest are only kept as totals, so times before
+    /// the latest was asked for may not be placed as they played.
+    pub fn at(&self, now: Timestamp, takeovers: &Takeovers) -> Playhead {
+        let elapsed = self.elapsed(now);
+
+        let state = takeovers.0.lock().unwrap();
+        let taken_over = state.played.clone();
+        if let Some(takeover) = &state.latest {
+            if elapsed >= takeover.start.add(takeover.duration) {
+                return self.position(elapsed, taken_over.and(takeover));
+            }
+
+            if elapsed < takeover.start {
+                return Playhead {
+                    cut: Some(Cut {
+                        played: None,
+                        upcoming: takeover.sources.clone(),
+                        resume_source: takeover.resume_source,
+                    }),
+                    ..self.position(elapsed, taken_over)
+                };
+            }
+
+            let mut offset = elapsed.subtract(takeover.start);
+            let mut current = takeover.sources.start;
+            loop {
+                let (start, end) = self.source_span(current);
+                if offset < end.subtract(start) || current + 1 == takeover.sources.end {
+                    break;
+                }
+                offset = offset.subtract(end.subtract(start));
+                current += 1;
+            }
+            let played = takeover.sources.start..current;
+            let discontinuity = takeover.discontinuity + 1 + played.len();
+            return Playhead {
+                discontinuity,
+                run: if self.continuous {
+                    takeover.run + 1
+                } else {
+                    discontinuity
+                },
+                loop_index: takeover.resume_loop,
+                source_index: current,
+                offset_in_source: offset,
+                cut: Some(Cut {
+                    played: Some(played),
+                    upcoming: current + 1..takeover.sources.end,
+                    resume_source: takeover.resume_source,
+                }),
+                taken_over,
+            };
+        }
+
+        self.position(elapsed, taken_over)
+    }
+
+    /// Loop time since `start`, at the configured speed.
+    fn elapsed(&self, now: Timestamp) -> Duration {
+        let elapsed = now.since(self.start).unwrap().total(Unit::Second).unwrap();
+        if elapsed < 0.0 && !self.early.swap(true, Ordering::Relaxed) {
+            eprintln!("WARNING: {now} is before the playlist start {}", self.start);
+        }
+        Duration::new(
+            elapsed.max(0.0) as u64 * self.config.speed,
+            Ratio::ONE,
+            self.step,
+        )
+    }
 
-        let now = now.since(self.start).unwrap().total(Unit::Second).unwrap() as u64;
-        let now = Duration::new(now * config.speed, Ratio::ONE, self.step);
-        let (loop_index, offset) = now.modulo(self.duration);
+    /// Where the loop is `elapsed` after `start`, once `taken_over` have
+    /// played.
+    fn position(&self, elapsed: Duration, taken_over: TakenOver) -> Playhead {
+        let loop_time = elapsed.subtract(taken_over.duration.min(elapsed));
+        let (loop_index, offset) = loop_time.modulo(self.duration);
 
         let (_, (start_duration, source_index)) = self.sources.range(offset..).next().unwrap();
         let offset_in_source = offset.subtract(*start_duration);
 
-        let discontinuity = ((loop_index as usize) * self.sources.len()) + source_index;
+        let discontinuity =
+            ((loop_index as usize) * self.sources.len()) + source_index + taken_over.sources();
+        // The loop starting over breaks the run, as do takeovers.
+        let run = if self.continuous {
+            loop_index as usize + taken_over.breaks
+        } else {
+            discontinuity
+        };
 
         Playhead {
             discontinuity,
+            run,
             loop_index: loop_index as usize,
             source_index: *source_index,
             offset_in_source,
+            cut: None,
+            taken_over,
+        }
+    }
+
+    /// Where source `index` starts and ends in the loop.
+    fn source_span(&self, index: usize) -> (Duration, Duration) {
+        self.sources
+            .iter()
+            .find(|(_, (_, i))| *i == index)
+            .map(|(end, (start, _))| (*start, *end))
+            .unwrap()
+    }
+
+    /// Plays `vid` once, straight after the source playing at `now`, then
+    /// picks the loop up where it left off; see [`Takeover`].
+    pub fn take_over(
+        &self,
+        now: Timestamp,
+        takeovers: &Takeovers,
+        vid: u32,
+    ) -> Result<TakeoverWindow, TakeoverError> {
+        let anchor = &self.streams[0];
+        let first = anchor
+            .sources
+            .iter()
+            .position(|source| source.vid == vid)
+            .ok_or(TakeoverError::UnknownVid)?;
+        // One play: the package's first piece and those after its cues,
+        // not the plays that follow it when it repeats.
+        let pieces = anchor.sources[first + 1..]
+            .iter()
+            .take_while(|source| source.cue)
+            .count();
+        let sources = first..first + 1 + pieces;
+
+        let playhead = self.at(now, takeovers);
+        if playhead.cut.is_some() {
+            return Err(TakeoverError::Busy);
+        }
+        let (start, end) = self.source_span(playhead.source_index);
+        let remaining = end.subtract(start).subtract(playhead.offset_in_source);
+        let duration = sources.clone().fold(Duration::zero(), |total, index| {
+            let (start, end) = self.source_span(index);
+            total.add(end.subtract(start))
+        });
+        let (resume_loop, resume_source) = if playhead.source_index + 1 == self.sources.len() {
+            (playhead.loop_index + 1, 0)
+        } else {
+            (playhead.loop_index, playhead.source_index + 1)
+        };
+
+        let takeover = Takeover {
+            start: self.elapsed(now).add(remaining),
+            duration,
+            sources,
+            discontinuity: playhead.discontinuity,
+            run: playhead.run,
+            resume_loop,
+            resume_source,
+        };
+        let window = TakeoverWindow {
+            vid,
+            start: self.timestamp_at(takeover.start),
+            end: self.timestamp_at(takeover.start.add(duration)),
+        };
+        let mut state = takeovers.0.lock().unwrap();
+        // The latest has played out, so only its totals are needed now.
+        if let Some(latest) = state.latest.take() {
+            state.played = state.played.and(&latest);
+        }
+        state.latest = Some(takeover);
+        Ok(window)
+    }
+
+    /// When the clock reaches `loop_time`; the inverse of [`Self::elapsed`].
+    fn timestamp_at(&self, loop_time: Duration) -> Timestamp {
+        let seconds = loop_time.to_seconds(self.step) / self.config.speed as f64;
+        self.start + SignedDuration::from_secs_f64(seconds)
+    }
+
+    /// Like [`Self::at`], but `seconds` into whichever source is playing at
+    /// `now` rather than where the clock has got to. `None` unless that is
+    /// within the source.
+    pub fn at_source_offset(
+        &self,
+        now: Timestamp,
+        takeovers: &Takeovers,
+        seconds: f64,
+    ) -> Option<Playhead> {
+        let playhead = self.at(now, takeovers);
+        let (end, (start, _)) = self
+            .sources
+            .iter()
+            .find(|(_, (_, index))| *index == playhead.source_index)?;
+        let offset_in_source = Duration::from_seconds(seconds, self.step);
+        if !(seconds >= 0.0 && offset_in_source < end.subtract(*start)) {
+            return None;
         }
+        Some(Playhead {
+            offset_in_source,
+            ..playhead
+        })
     }
 }
 
 pub struct Stream {
     bitrate: u32,
     kind: VariantKind,
+    audio: Option<AudioRung>,
+    /// The most demanding of its variants' codec strings, for `CODECS`.
+    codecs: Option<String>,
+    tiles: Option<TileLayout>,
+    target_duration: u64,
+    /// See [`Self::capacity`].
+    capacity: usize,
+    sequence_offset: Sequence,
     sources: Vec<StreamSource>,
     segments: Vec<StreamSegment>,
 }
 
 struct StreamSource {
     vid: u32,
-    init: RemoteResource,
+    init: Option<RemoteResource>,
+    /// Where `init` sits in a file it shares with the media segments.
+    init_range: Option<ByteRange>,
     segment_lookup: BTreeMap<Duration, usize>,
     segments: Range<usize>,
+    /// Whether the source starts at one of its package's cues, rather than
+    /// at the start of the package; see [`Variant::pieces`].
+    cue: bool,
+    /// Set when an ad break leaves or comes back at the source's start.
+    splice: Option<Splice>,
+    /// How many of the package's segments come before the source's first.
+    segment_offset: usize,
+}
+
+/// Which end of one of a package's [`AdBreak`]s a source starts at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Splice {
+    Out {
+        duration: Duration,
+    },
+    /// `pieces` after the source that went out.
+    In {
+        duration: Duration,
+        pieces: usize,
+    },
+}
+
+/// A codec string as [`crate::inspect`] writes it, so ones from older or
+/// hand-edited packages compare with it: lower case, with AVC's legacy
+/// decimal `avc1.<profile>.<level>` as RFC 6381 hex.
+fn normalize_codecs(codecs: &str) -> String {
+    let codecs = codecs.trim().to_ascii_lowercase();
+    if let Some((profile, level)) = codecs
+        .strip_prefix("avc1.")
+        .and_then(|rest| rest.split_once('.'))
+        && let (Ok(profile), Ok(level)) = (profile.parse::<u8>(), level.parse::<u8>())
+    {
+        return format!("avc1.{profile:02x}00{level:02x}");
+    }
+    codecs
+}
+
+/// The [`Splice`] each of a variant's `pieces` starts at, if any.
+fn splices(variant: &Variant, pieces: &[&[Segment]], step: StepSize) -> Vec<Option<Splice>> {
+    let mut splices = vec![None; pieces.len()];
+    let mut starts = Vec::with_capacity(pieces.len());
+    let mut start = 0;
+    for piece in pieces {
+        starts.push(start);
+        start += piece.len();
+    }
+    for (out, back) in variant.ad_break_cuts() {
+        let (Ok(out_piece), Ok(in_piece)) =
+            (starts.binary_search(&out), starts.binary_search(&back))
+        else {
+            continue;
+        };
+        let duration = Duration::new(
+            variant.segments[out..back]
+                .iter()
+                .map(Segment::duration)
+                .sum(),
+            variant.time_base,
+            step,
+        );
+        splices[out_piece] = Some(Splice::Out { duration });
+        splices[in_piece] = Some(Splice::In {
+            duration,
+            pieces: in_piece - out_piece,
+        });
+    }
+    splices
 }
 
 impl StreamSource {
-    fn from_variant(vid: u32, variant: &Variant, start_segment_idx: usize, step: StepSize) -> Self {
+    fn new(
+        vid: u32,
+        init: Option<&RemoteResource>,
+        segments: &[StreamSegment],
+        start_segment_idx: usize,
+    ) -> Self {
         let mut segment_lookup = BTreeMap::default();
 
         let mut running_duration = Duration::zero();
-        for (si, segment) in variant.segments.iter().enumerate() {
-            let duration = Duration::new(segment.duration(), variant.time_base, step);
-            running_duration = running_duration.add(duration);
+        for (si, segment) in segments.iter().enumerate() {
+            running_duration = running_duration.add(segment.duration);
             segment_lookup.insert(running_duration, si);
         }
 
         Self {
             vid,
-            init: variant.init_src.clone(),
+            init: init.cloned(),
+            init_range: None,
             segment_lookup,
-            segments: start_segment_idx..(start_segment_idx + variant.segments.len()),
+            segments: start_segment_idx..(start_segment_idx + segments.len()),
+            cue: false,
+            splice: None,
+            segment_offset: 0,
         }
     }
 }
@@ -174,22 +964,41 @@ impl StreamSource {
 #[derive(Clone)]
 struct StreamSegment {
     duration: Duration,
+    /// Where the segment starts in its stream's loop, for rounding `EXTINF`.
+    start: Duration,
     src: RemoteResource,
+    range: Option<ByteRange>,
+    size: Option<u64>,
 }
 
 impl StreamSegment {
-    fn new(segment: &Segment, time_base: Ratio<u32>, step: StepSize) -> Self {
+    /// `None` for a zero-duration segment, which players would stall on.
+    fn new(segment: &Segment, time_base: Ratio<u32>, step: StepSize) -> Option<Self> {
         let duration = Duration::new(segment.duration, time_base, step);
-        assert_ne!(duration.raw(), 0);
-        Self {
+        (duration.raw() != 0).then(|| Self {
             duration,
+            start: Duration::zero(),
             src: segment.src.clone(),
-        }
+            range: segment.range,
+            size: segment.size,
+        })
+    }
+
+    /// The segment's average bitrate in kbit/s, for `EXT-X-BITRATE`.
+    fn kbps(&self, step: StepSize) -> Option<u64> {
+        let size = self.size?;
+        let seconds = self.duration.to_seconds(step);
+        Some((size as f64 * 8.0 / seconds / 1000.0).round() as u64)
     }
 }
 
 struct QueueItem<'a> {
     discontinuity: usize,
+    /// The number of the `EXT-X-DISCONTINUITY` before it: its
+    /// `discontinuity`, or with `continuous`, a count of the places the
+    /// timestamps break, where the loop starts over or a takeover goes in
+    /// or comes out.
+    run: usize,
     source: &'a StreamSource,
     segment: &'a StreamSegment,
 }
@@ -199,50 +1008,227 @@ impl Stream {
         Self {
             bitrate,
             kind: VariantKind::Video { width, height },
+            audio: None,
+            codecs: None,
+            tiles: None,
+            target_duration: 0,
+            capacity: 0,
+            sequence_offset: Sequence::default(),
             sources: Vec::default(),
             segments: Vec::default(),
         }
     }
 
-    fn new_audio(bitrate: u32) -> Self {
+    fn new_audio(rung: AudioRung) -> Self {
         Self {
-            bitrate,
+            bitrate: rung.bitrate,
             kind: VariantKind::Audio,
+            audio: Some(rung),
+            codecs: None,
+            tiles: None,
+            target_duration: 0,
+            capacity: 0,
+            sequence_offset: Sequence::default(),
+            sources: Vec::default(),
+            segments: Vec::default(),
+        }
+    }
+
+    /// Whether `variant` has this audio stream's channel layout and, when
+    /// tagged, its language. Video streams go by kind alone.
+    fn carries(&self, variant: &Variant) -> bool {
+        self.audio.as_ref().is_none_or(|rung| {
+            rung.channels == variant.channels()
+                && variant
+                    .language
+                    .as_ref()
+                    .is_none_or(|language| *language == rung.language)
+        })
+    }
+
+    /// An image stream of each package's sprite sheets, one segment per
+    /// sheet, cut to the item's length so it keeps pace with the video.
+    /// `lengths` maps package index to the lengths of the item's pieces; a
+    /// sheet that straddles a cue is split between them. Only offered when
+    /// every package has sheets in the same layout.
+    fn new_images(
+        packages: &[Package],
+        lengths: &BTreeMap<usize, Vec<Duration>>,
+        step: StepSize,
+    ) -> Option<Self> {
+        let first = packages.first()?.thumbnails.as_ref()?;
+        let layout = first.layout;
+        if !packages.iter().all(|package| {
+            package
+                .thumbnails
+                .as_ref()
+                .is_some_and(|thumbnails| thumbnails.layout == layout)
+        }) {
+            return None;
+        }
+
+        let mut stream = Self {
+            bitrate: 0,
+            kind: VariantKind::Video {
+                width: layout.width,
+                height: layout.height,
+            },
+            audio: None,
+            codecs: None,
+            tiles: Some(layout),
+            target_duration: 0,
+            capacity: 0,
+            sequence_offset: Sequence::default(),
             sources: Vec::default(),
             segments: Vec::default(),
+        };
+        let sheet = Duration::new(layout.sheet_seconds() as u64, Ratio::ONE, step);
+        for (pi, package) in packages.iter().enumerate() {
+            let thumbnails = package.thumbnails.as_ref().unwrap();
+            stream.bitrate = stream.bitrate.max(thumbnails.bandwidth);
+
+            let Some(pieces) = lengths.get(&pi) else {
+                continue;
+            };
+            let mut sheets = thumbnails.sheets.iter();
+            // The sheet being laid out, and how much of it is left.
+            let mut current = None;
+            let mut segment_offset = 0;
+            for (k, &length) in pieces.iter().enumerate() {
+                let mut remaining = length;
+                let mut segments = Vec::new();
+                while remaining != Duration::zero() {
+                    let (src, left) = match current {
+                        Some((src, left)) if left != Duration::zero() => (src, left),
+                        _ => match sheets.next() {
+                            Some(src) => (src, sheet),
+                            None => break,
+                        },
+                    };
+                    let duration = remaining.min(left);
+                    remaining = remaining.subtract(duration);
+                    current = Some((src, left.subtract(duration)));
+                    segments.push(StreamSegment {
+                        duration,
+                        start: Duration::zero(),
+                        src: src.clone(),
+                        range: None,
+                        size: None,
+                    });
+                }
+                // Too few sheets for the video; stretch the last rather than
+                // let the image timeline fall behind.
+                if let Some(last) = segments.last_mut() {
+                    last.duration = last.duration.add(remaining);
+                }
+
+                let source = StreamSource {
+                    cue: k > 0,
+                    segment_offset,
+                    ..StreamSource::new(package.vid, None, &segments, stream.segments.len())
+                };
+                segment_offset += segments.len();
+                stream.sources.push(source);
+                stream.segments.extend(segments);
+            }
         }
+        Some(stream)
+    }
+
+    /// Room for a window of the stream's longest segment URIs and tags.
+    fn calculate_capacity(&self, config: &Config) -> usize {
+        let longest_uri = self
+            .sources
+            .iter()
+            .flat_map(|source| {
+                let prefix = format!("/{}/", source.vid).len();
+                self.segments[source.segments.clone()]
+                    .iter()
+                    .map(move |segment| prefix + segment.src.0.len())
+            })
+            .max()
+            .unwrap_or(0);
+        let segment = SEGMENT_TAG_BYTES + config.segment_base().len() + longest_uri;
+        // Segments are mostly about as long as the target duration.
+        let window = config.window_seconds.map_or(LOOKAHEAD, |seconds| {
+            seconds.div_ceil(self.target_duration.max(1)) as usize + 1
+        });
+        PLAYLIST_HEADER_BYTES + window * segment
+    }
+
+    /// How much to reserve for rendering a variant playlist into.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The EXTINF of every segment, rounded to the nearest integer, must not
+    /// exceed the target duration, so each media playlist needs its own.
+    fn calculate_target_duration(&self, step: StepSize) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.duration.to_seconds(step).round() as u64)
+            .max()
+            .unwrap_or(0)
     }
 
-    fn queue(&self, playhead: &Playhead) -> impl Iterator<Item = QueueItem> {
+    /// The index, within the playhead's source, of the segment it falls in.
+    fn segment_index(&self, playhead: &Playhead) -> usize {
         let this_source = &self.sources[playhead.source_index];
-        let start_segment_index = this_source
+        this_source
             .segment_lookup
             .range(playhead.offset_in_source..)
             .next()
             .map(|(_, idx)| *idx)
-            .unwrap_or(this_source.segment_lookup.len());
+            .unwrap_or(this_source.segment_lookup.len())
+    }
+
+    fn queue(&self, playhead: &Playhead, continuous: bool) -> impl Iterator<Item = QueueItem<'_>> {
+        let this_source = &self.sources[playhead.source_index];
+        let start_segment_index = self.segment_index(playhead);
 
         let first_source_segments = self.segments
             [this_source.segments.start + start_segment_index..this_source.segments.end]
             .iter()
             .map(|segment| QueueItem {
                 discontinuity: playhead.discontinuity,
+                run: playhead.run,
                 source: this_source,
                 segment,
             });
 
-        let playlist_remainder = self
-            .sources
+        // A takeover's sources come first, then the loop from where it was
+        // cut.
+        let (upcoming, resume_source) = match &playhead.cut {
+            Some(cut) => (cut.upcoming.clone(), cut.resume_source),
+            None => (0..0, playhead.source_index + 1),
+        };
+        let taking_over = playhead
+            .cut
+            .as_ref()
+            .is_some_and(|cut| cut.played.is_none());
+        let resuming = playhead.cut.is_some().then_some(upcoming.len());
+        let playlist_remainder = self.sources[upcoming]
             .iter()
-            .cycle()
-            .skip(playhead.source_index + 1)
+            .chain(self.sources.iter().cycle().skip(resume_source))
             .enumerate()
-            .flat_map(|(source_i, source)| {
+            .scan(playhead.run, move |run, (source_i, source)| {
                 let discontinuity = playhead.discontinuity + source_i + 1;
+                if !continuous {
+                    *run = discontinuity;
+                } else if (taking_over && source_i == 0)
+                    || resuming == Some(source_i)
+                    || std::ptr::eq(source, &self.sources[0])
+                {
+                    *run += 1;
+                }
+                Some((discontinuity, *run, source))
+            })
+            .flat_map(|(discontinuity, run, source)| {
                 self.segments[source.segments.clone()]
                     .iter()
                     .map(move |segment| QueueItem {
                         discontinuity,
+                        run,
                         source,
                         segment,
                     })
@@ -250,105 +1236,886 @@ impl Stream {
 
         first_source_segments.chain(playlist_remainder)
     }
+
+    /// How many segments of every loop since `start` come before the
+    /// playhead's.
+    fn event_played(&self, playhead: &Playhead) -> usize {
+        playhead.loop_index * self.segments.len()
+            + self.sources[playhead.source_index].segments.start
+            + self.segment_index(playhead)
+    }
+
+    /// Every segment from the start of the first loop to a [`window`] past
+    /// the playhead's, every loop since appended to the one before, and how
+    /// many come before the playhead's; see `event_playlists` in the config.
+    /// At `MAX_EVENT_SEGMENTS` the event is over, which the `bool` says.
+    fn event_queue(
+        &self,
+        playlist: &Playlist,
+        playhead: &Playhead,
+    ) -> (Vec<QueueItem<'_>>, usize, bool) {
+        let played = self.event_played(playhead);
+        let mut all = (0..).flat_map(|loop_index| {
+            self.sources
+                .iter()
+                .enumerate()
+                .flat_map(move |(i, source)| {
+                    let discontinuity = loop_index * self.sources.len() + i;
+                    let run = if playlist.continuous {
+                        loop_index
+                    } else {
+                        discontinuity
+                    };
+                    self.segments[source.segments.clone()]
+                        .iter()
+                        .map(move |segment| QueueItem {
+                            discontinuity,
+                            run,
+                            source,
+                            segment,
+                        })
+                })
+        });
+        let mut queue = all.by_ref().take(played).collect::<Vec<_>>();
+        queue.extend(window(all, playlist));
+        let ended = queue.len() >= MAX_EVENT_SEGMENTS;
+        queue.truncate(MAX_EVENT_SEGMENTS);
+        let played = played.min(queue.len() - 1);
+        (queue, played, ended)
+    }
+}
+
+/// The first of `queue` a variant playlist lists: `LOOKAHEAD` segments, or
+/// with `window_seconds`, as many as it takes to last that long.
+fn window<'a>(
+    queue: impl Iterator<Item = QueueItem<'a>>,
+    playlist: &Playlist,
+) -> Vec<QueueItem<'a>> {
+    let Some(seconds) = playlist.config.window_seconds else {
+        return queue.take(LOOKAHEAD).collect();
+    };
+    let window = Duration::from_seconds(seconds as f64, playlist.step);
+    let mut listed = Duration::zero();
+    queue
+        .take_while(|item| {
+            let before = listed;
+            listed = listed.add(item.segment.duration);
+            before < window
+        })
+        .collect()
 }
 
 impl Stream {
     fn media_seq(&self, playhead: &Playhead) -> usize {
         let this_source = &self.sources[playhead.source_index];
-        let start_segment_index = this_source
+        let start_segment_index = self.segment_index(playhead);
+        let segment_count = |sources: &Range<usize>| {
+            self.sources[sources.clone()]
+                .iter()
+                .map(|source| source.segments.len())
+                .sum::<usize>()
+        };
+        let taken_over = playhead
+            .taken_over
+            .plays
+            .iter()
+            .map(|(source, plays)| plays * self.sources[*source].segments.len())
+            .sum::<usize>();
+
+        // A takeover's segments follow on from those of the source it cut.
+        let source_start = match &playhead.cut {
+            Some(Cut {
+                played: Some(played),
+                resume_source,
+                ..
+            }) => self.sources[*resume_source].segments.start + segment_count(played),
+            _ => this_source.segments.start,
+        };
+        (playhead.loop_index * self.segments.len())
+            + source_start
+            + start_segment_index
+            + taken_over
+    }
+
+    /// How far the playhead is into the first segment of the queue.
+    fn offset_in_segment(&self, playhead: &Playhead) -> Duration {
+        let this_source = &self.sources[playhead.source_index];
+        match this_source
             .segment_lookup
             .range(playhead.offset_in_source..)
             .next()
-            .map(|(_, idx)| *idx)
-            .unwrap_or(this_source.segment_lookup.len());
+        {
+            Some((end, idx)) => {
+                let segment = &self.segments[this_source.segments.start + idx];
+                playhead
+                    .offset_in_source
+                    .subtract(end.subtract(segment.duration))
+            }
+            None => Duration::zero(),
+        }
+    }
 
-        (playhead.loop_index * self.segments.len())
-            + this_source.segments.start
-            + start_segment_index
+    fn sequence(&self, playhead: &Playhead) -> Sequence {
+        let naive = Sequence {
+            media: self.media_seq(playhead),
+            discontinuity: playhead.run,
+        };
+        naive.add(self.sequence_offset)
     }
 
     pub fn render_variant_playlist(
         &self,
         r: &mut String,
         playlist: &Playlist,
+        takeovers: &Takeovers,
         now: Timestamp,
     ) -> fmt::Result {
-        let config = get_config();
+        self.render(r, playlist, now, &playlist.at(now, takeovers), false)
+    }
 
-        let playhead = playlist.at(now);
-        let mut current_discontinuity = playhead.discontinuity;
+    /// Like [`Self::render_variant_playlist`], but from `playhead` instead of
+    /// wherever the clock has got to, e.g. from
+    /// [`Playlist::at_source_offset`].
+    pub fn render_variant_playlist_at(
+        &self,
+        r: &mut String,
+        playlist: &Playlist,
+        now: Timestamp,
+        playhead: &Playhead,
+    ) -> fmt::Result {
+        self.render(r, playlist, now, playhead, false)
+    }
 
-        writeln!(r, "#EXTM3U")?;
-        writeln!(
+    /// Like [`Self::render_variant_playlist`], but with the segments the
+    /// client already holds replaced by `#EXT-X-SKIP`. Falls back to the full
+    /// playlist unless `delta_playlists` is enabled.
+    pub fn render_delta_playlist(
+        &self,
+        r: &mut String,
+        playlist: &Playlist,
+        takeovers: &Takeovers,
+        now: Timestamp,
+    ) -> fmt::Result {
+        self.render(
             r,
-            "## DEBUG: loop={};source={};",
-            playhead.loop_index, playhead.source_index
-        )?;
-        writeln!(r, "#EXT-X-VERSION:7")?;
-        writeln!(r, "#EXT-X-TARGETDURATION:10")?;
-        writeln!(r, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_seq(&playhead))?;
-        writeln!(r, "#EXT-X-DISCONTINUITY-SEQUENCE:{current_discontinuity}")?;
-
-        let mut mapped_vid = None;
+            playlist,
+            now,
+            &playlist.at(now, takeovers),
+            playlist.config.delta_playlists,
+        )
+    }
 
-        for (i, this) in self.queue(&playhead).take(LOOKAHEAD).enumerate() {
-            for _ in current_discontinuity..this.discontinuity {
-                writeln!(r, "#EXT-X-DISCONTINUITY")?;
-            }
+    /// The spec's minimum: six target durations.
+    fn can_skip_until(&self) -> u64 {
+        6 * self.target_duration
+    }
 
-            if i == 0 || mapped_vid != Some(this.source.vid) {
-                let uri = this.source.init.uri(this.source.vid);
-                writeln!(r, "#EXT-X-MAP:URI=\"{}{uri}\"", config.media_base)?;
-                mapped_vid = Some(this.source.vid);
-            }
+    /// The spec's minimum: three target durations.
+    fn min_hold_back(&self) -> f64 {
+        3.0 * self.target_duration as f64
+    }
 
-            let duration = this.segment.duration.to_seconds(playlist.step);
-            let uri = this.segment.src.uri(this.source.vid);
-            writeln!(r, "#EXTINF:{duration:.6},")?;
-            writeln!(r, "{}{uri}", config.media_base,)?;
+    /// `hold_back_seconds`, raised to the spec's minimum when set too low.
+    fn hold_back(&self, config: &Config) -> f64 {
+        config
+            .hold_back_seconds
+            .map_or(self.min_hold_back(), |hold_back| {
+                hold_back.max(self.min_hold_back())
+            })
+    }
 
-            current_discontinuity = this.discontinuity;
-        }
+    /// How many leading segments may be skipped: those that end at least
+    /// `CAN-SKIP-UNTIL` before the end of the playlist. Always leaves the
+    /// final segment, so a delta is never empty.
+    fn skippable(&self, queue: &[QueueItem<'_>], step: StepSize) -> usize {
+        let durations = queue
+            .iter()
+            .map(|item| item.segment.duration.to_seconds(step))
+            .collect::<Vec<_>>();
+        let total = durations.iter().sum::<f64>();
+        let boundary = total - self.can_skip_until() as f64;
 
-        Ok(())
+        let mut end = 0.0;
+        let skipped = durations
+            .iter()
+            .take_while(|duration| {
+                end += *duration;
+                end <= boundary
+            })
+            .count();
+        skipped.min(queue.len().saturating_sub(1))
     }
-}
 
-impl Playlist {
-    pub fn master_playlist(&self, out: &mut String) -> fmt::Result {
-        let config = get_config();
+    fn render(
+        &self,
+        r: &mut String,
+        playlist: &Playlist,
+        now: Timestamp,
+        playhead: &Playhead,
+        delta: bool,
+    ) -> fmt::Result {
+        let config = playlist.config;
+        let player = config.player();
+        let media_base = config.segment_base();
 
-        writeln!(out, "#EXTM3U")?;
-        writeln!(out, "#EXT-X-INDEPENDENT-SEGMENTS")?;
-        writeln!(out)?;
+        if playhead.source_index >= self.sources.len() {
+            // An item without a variant for this stream; players retry an
+            // empty playlist, where a 500 would make them give up.
+            eprintln!(
+                "WARNING: no source for item {} in the {} stream",
+                playhead.source_index, self.bitrate
+            );
+            writeln!(r, "#EXTM3U")?;
+            writeln!(r, "#EXT-X-VERSION:{}", player.version)?;
+            writeln!(r, "#EXT-X-TARGETDURATION:{}", self.target_duration)?;
+            return Ok(());
+        }
+        let event = config.event_playlists;
+        let (mut current_discontinuity, mut marked, sequence, queue, played, ended) = if event {
+            let (queue, played, ended) = self.event_queue(playlist, playhead);
+            (0, 0, Sequence::default(), queue, played, ended)
+        } else {
+            let queue = window(self.queue(playhead, playlist.continuous), playlist);
+            (
+                playhead.discontinuity,
+                playhead.run,
+                self.sequence(playhead),
+                queue,
+                0,
+                false,
+            )
+        };
+        let skipped = if delta && !event {
+            self.skippable(&queue, playlist.step)
+        } else {
+            0
+        };
 
-        for (i, stream) in self.streams.iter().enumerate() {
-            let bitrate = stream.bitrate;
-            match &stream.kind {
-                VariantKind::Video { width, height } => {
-                    writeln!(
-                        out,
-                        "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\",AUDIO=\"audio\"",
-                        bitrate, width, height, "avc1.64e01f, mp4a.40.2"
-                    )?;
-                    writeln!(out, "{}/hls/variant{i}.m3u8", config.base)?;
-                    writeln!(out)?;
-                }
-                VariantKind::Audio => {
+        writeln!(r, "#EXTM3U")?;
+        writeln!(
+            r,
+            "## DEBUG: loop={};source={};",
+            playhead.loop_index, playhead.source_index
+        )?;
+        // EXT-X-SKIP needs version 9; full playlists stay readable by older
+        // players.
+        let version = if delta {
+            player.version.max(9)
+        } else {
+            player.version
+        };
+        writeln!(r, "#EXT-X-VERSION:{version}")?;
+        writeln!(r, "#EXT-X-TARGETDURATION:{}", self.target_duration)?;
+        if event {
+            writeln!(r, "#EXT-X-PLAYLIST-TYPE:EVENT")?;
+        }
+        let mut server_control = Vec::new();
+        if config.delta_playlists {
+            server_control.push(format!(
+                "CAN-SKIP-UNTIL={:.1}",
+                self.can_skip_until() as f64
+            ));
+        }
+        if config.server_hold_back {
+            server_control.push(format!("HOLD-BACK={:.1}", self.hold_back(config)));
+        }
+        if !server_control.is_empty() {
+            writeln!(r, "#EXT-X-SERVER-CONTROL:{}", server_control.join(","))?;
+        }
+        writeln!(r, "#EXT-X-MEDIA-SEQUENCE:{}", sequence.media)?;
+        writeln!(
+            r,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            sequence.discontinuity
+        )?;
+        if self.tiles.is_some() {
+            writeln!(r, "#EXT-X-IMAGES-ONLY")?;
+        }
+
+        if skipped > 0 {
+            writeln!(r, "#EXT-X-SKIP:SKIPPED-SEGMENTS={skipped}")?;
+            // Discontinuities among the skipped segments are implied by the
+            // client's copy.
+            current_discontinuity = queue[skipped - 1].discontinuity;
+            marked = queue[skipped - 1].run;
+        }
+
+        // Wall-clock time at the start of each segment, wound back through
+        // those already played and advanced through the skipped ones.
+        let elapsed = self.offset_in_segment(playhead).to_seconds(playlist.step);
+        let mut segment_time = now - SignedDuration::from_secs_f64(elapsed / config.speed as f64);
+        for this in &queue[..played] {
+            segment_time -= segment_duration(this.segment, playlist);
+        }
+        for this in &queue[..skipped] {
+            segment_time += segment_duration(this.segment, playlist);
+        }
+
+        let mut mapped_vid = None;
+        let extinf = ExtinfRounder::new(player.extinf_precision);
+
+        for (i, this) in queue.into_iter().skip(skipped).enumerate() {
+            let discontinuous = current_discontinuity < this.discontinuity;
+            for _ in marked..this.run {
+                writeln!(r, "#EXT-X-DISCONTINUITY")?;
+            }
+            marked = this.run;
+
+            if player.program_date_time && (i == 0 || discontinuous) {
+                writeln!(r, "#EXT-X-PROGRAM-DATE-TIME:{segment_time:.3}")?;
+                // An ad break; the ID holds across loops and refreshes.
+                if discontinuous && let Some(splice) = this.source.splice {
+                    write_splice(r, splice, this.discontinuity, segment_time, playlist)?;
+                } else if discontinuous && this.source.cue {
                     writeln!(
-                        out,
-                        "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"en\",NAME=\"aac_192\",AUTOSELECT=YES,DEFAULT=YES,URI=\"{}/hls/variant{i}.m3u8\"",
-                        config.base,
+                        r,
+                        "#EXT-X-DATERANGE:ID=\"cue-{}\",CLASS=\"cue\",START-DATE=\"{segment_time:.3}\"",
+                        this.discontinuity
                     )?;
-                    writeln!(out)?;
                 }
             }
+            segment_time += segment_duration(this.segment, playlist);
+
+            // A package playing again from its start maps its init again,
+            // whether the loop wraps a lone package onto itself or the
+            // package repeats back to back.
+            let replayed = discontinuous
+                && this.source.segment_offset == 0
+                && std::ptr::eq(this.segment, &self.segments[this.source.segments.start]);
+            if player.map && (i == 0 || replayed || mapped_vid != Some(this.source.vid)) {
+                write_map(r, media_base, this.source)?;
+                mapped_vid = Some(this.source.vid);
+            }
+
+            if config.segment_bitrates
+                && let Some(kbps) = this.segment.kbps(playlist.step)
+            {
+                writeln!(r, "#EXT-X-BITRATE:{kbps}")?;
+            }
+
+            let duration = extinf.extinf(this.segment, playlist.step);
+            let uri = this.segment.src.uri(this.source.vid);
+            writeln!(r, "#EXTINF:{duration},")?;
+            if let Some(tiles) = &self.tiles {
+                write_tiles(r, tiles)?;
+            }
+            if let Some(ByteRange { offset, length }) = this.segment.range {
+                writeln!(r, "#EXT-X-BYTERANGE:{length}@{offset}")?;
+            }
+            writeln!(r, "{media_base}{uri}")?;
+
+            current_discontinuity = this.discontinuity;
+        }
+
+        if ended {
+            writeln!(r, "#EXT-X-ENDLIST")?;
+        }
+        Ok(())
+    }
+}
+
+impl Stream {
+    pub fn contains_vid(&self, vid: u32) -> bool {
+        self.sources.iter().any(|source| source.vid == vid)
+    }
+
+    /// A standalone VOD playlist of one package's segments, outside the
+    /// loop, for checking a video before it joins the channel. Empty if the
+    /// stream has no source for `vid`; see [`Self::contains_vid`].
+    pub fn render_preview_playlist(
+        &self,
+        r: &mut impl fmt::Write,
+        playlist: &Playlist,
+        vid: u32,
+    ) -> fmt::Result {
+        let Some(first) = self.sources.iter().position(|source| source.vid == vid) else {
+            return Ok(());
+        };
+        let source = &self.sources[first];
+        // The package's pieces after its first cue follow on.
+        let end = self.sources[first + 1..]
+            .iter()
+            .take_while(|source| source.cue)
+            .last()
+            .unwrap_or(source)
+            .segments
+            .end;
+        let config = playlist.config;
+        let player = config.player();
+        let media_base = config.segment_base();
+        let segments = &self.segments[source.segments.start..end];
+        let target_duration = segments
+            .iter()
+            .map(|segment| segment.duration.to_seconds(playlist.step).round() as u64)
+            .max()
+            .unwrap_or(0);
+
+        writeln!(r, "#EXTM3U")?;
+        writeln!(r, "#EXT-X-VERSION:{}", player.version)?;
+        writeln!(r, "#EXT-X-TARGETDURATION:{target_duration}")?;
+        writeln!(r, "#EXT-X-PLAYLIST-TYPE:VOD")?;
+        writeln!(r, "#EXT-X-MEDIA-SEQUENCE:0")?;
+        if self.tiles.is_some() {
+            writeln!(r, "#EXT-X-IMAGES-ONLY")?;
+        }
+        if player.map {
+            write_map(r, media_base, source)?;
+        }
+
+        let extinf = ExtinfRounder::new(player.extinf_precision);
+        for segment in segments {
+            if config.segment_bitrates
+                && let Some(kbps) = segment.kbps(playlist.step)
+            {
+                writeln!(r, "#EXT-X-BITRATE:{kbps}")?;
+            }
+            let duration = extinf.extinf(segment, playlist.step);
+            writeln!(r, "#EXTINF:{duration},")?;
+            if let Some(tiles) = &self.tiles {
+                write_tiles(r, tiles)?;
+            }
+            if let Some(ByteRange { offset, length }) = segment.range {
+                writeln!(r, "#EXT-X-BYTERANGE:{length}@{offset}")?;
+            }
+            writeln!(r, "{media_base}{}", segment.src.uri(vid))?;
+        }
+        writeln!(r, "#EXT-X-ENDLIST")
+    }
+}
+
+/// The source's `EXT-X-MAP`, if it has an init segment.
+fn write_map(r: &mut impl fmt::Write, media_base: &str, source: &StreamSource) -> fmt::Result {
+    let Some(init) = &source.init else {
+        return Ok(());
+    };
+    let uri = init.uri(source.vid);
+    match source.init_range {
+        Some(ByteRange { offset, length }) => writeln!(
+            r,
+            "#EXT-X-MAP:URI=\"{media_base}{uri}\",BYTERANGE=\"{length}@{offset}\""
+        ),
+        None => writeln!(r, "#EXT-X-MAP:URI=\"{media_base}{uri}\""),
+    }
+}
+
+/// The `EXT-X-DATERANGE` for one end of an ad break starting the source
+/// `discontinuity`. Both ends share the out point's ID and start date, and
+/// carry the matching SCTE-35 splice.
+fn write_splice(
+    r: &mut impl fmt::Write,
+    splice: Splice,
+    discontinuity: usize,
+    segment_time: Timestamp,
+    playlist: &Playlist,
+) -> fmt::Result {
+    let seconds =
+        |duration: Duration| duration.to_seconds(playlist.step) / playlist.config.speed as f64;
+    match splice {
+        Splice::Out { duration } => {
+            let duration = seconds(duration);
+            let scte35 = scte35::splice_insert(discontinuity as u32, true, Some(duration));
+            writeln!(
+                r,
+                "#EXT-X-DATERANGE:ID=\"splice-{discontinuity}\",START-DATE=\"{segment_time:.3}\",PLANNED-DURATION={duration:.3},SCTE35-OUT={scte35}"
+            )
+        }
+        Splice::In { duration, pieces } => {
+            let out = discontinuity - pieces;
+            let start = segment_time - SignedDuration::from_secs_f64(seconds(duration));
+            let scte35 = scte35::splice_insert(out as u32, false, None);
+            writeln!(
+                r,
+                "#EXT-X-DATERANGE:ID=\"splice-{out}\",START-DATE=\"{start:.3}\",END-DATE=\"{segment_time:.3}\",SCTE35-IN={scte35}"
+            )
+        }
+    }
+}
+
+fn write_tiles(r: &mut impl fmt::Write, tiles: &TileLayout) -> fmt::Result {
+    writeln!(
+        r,
+        "#EXT-X-TILES:RESOLUTION={}x{},LAYOUT={}x{},DURATION={:.3}",
+        tiles.width, tiles.height, tiles.columns, tiles.rows, tiles.interval as f64
+    )
+}
+
+/// Rounds `EXTINF` durations to `precision` decimal places such that they
+/// always sum to the rounded length of the stream so far, so a long
+/// playlist doesn't drift from the media by a rounding error per segment.
+/// Each duration is the difference between its segment's rounded start and
+/// end in the stream, so can be a unit either side of its own rounding, but
+/// is the same in every window that lists the segment.
+struct ExtinfRounder {
+    precision: usize,
+}
+
+impl ExtinfRounder {
+    fn new(precision: usize) -> Self {
+        Self { precision }
+    }
+
+    fn units(&self, seconds: f64) -> i64 {
+        (seconds * 10f64.powi(self.precision as i32)).round() as i64
+    }
+
+    /// The segment's `EXTINF` duration, formatted.
+    fn extinf(&self, segment: &StreamSegment, step: StepSize) -> String {
+        let start = segment.start.to_seconds(step);
+        let end = start + segment.duration.to_seconds(step);
+        let units = self.units(end) - self.units(start);
+        let scale = 10i64.pow(self.precision as u32);
+        match self.precision {
+            0 => units.to_string(),
+            precision => format!("{}.{:0precision$}", units / scale, units % scale),
+        }
+    }
+}
+
+/// Wall-clock time a segment takes to play out at the configured speed.
+fn segment_duration(segment: &StreamSegment, playlist: &Playlist) -> SignedDuration {
+    let seconds = segment.duration.to_seconds(playlist.step);
+    SignedDuration::from_secs_f64(seconds / playlist.config.speed as f64)
+}
+
+/// The master playlist as data, for rendering to m3u8 (via `Display`) or
+/// serialising for the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct MasterPlaylist {
+    #[serde(skip)]
+    pub order: MasterOrder,
+    /// `EXT-X-INDEPENDENT-SEGMENTS`, a promise that every segment starts
+    /// with a keyframe; players seeking on a false promise glitch.
+    #[serde(skip)]
+    pub independent_segments: bool,
+    pub variants: Vec<VariantStreamInfo>,
+    pub renditions: Vec<MediaRendition>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageStreamInfo>,
+}
+
+/// An `EXT-X-STREAM-INF` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VariantStreamInfo {
+    pub bandwidth: u32,
+    pub width: u16,
+    pub height: u16,
+    pub codecs: String,
+    pub audio: String,
+    pub uri: String,
+}
+
+/// An `EXT-X-MEDIA` audio rendition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MediaRendition {
+    pub group_id: String,
+    pub language: String,
+    pub name: String,
+    pub channels: u8,
+    pub autoselect: bool,
+    pub default: bool,
+    pub uri: String,
+}
+
+/// An `EXT-X-IMAGE-STREAM-INF` entry, for trick-play thumbnails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageStreamInfo {
+    pub bandwidth: u32,
+    pub width: u16,
+    pub height: u16,
+    pub uri: String,
+}
+
+impl fmt::Display for ImageStreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "#EXT-X-IMAGE-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"jpeg\",URI=\"{}\"",
+            self.bandwidth, self.width, self.height, self.uri
+        )
+    }
+}
+
+impl fmt::Display for VariantStreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\",AUDIO=\"{}\"",
+            self.bandwidth, self.width, self.height, self.codecs, self.audio
+        )?;
+        writeln!(f, "{}", self.uri)
+    }
+}
+
+impl fmt::Display for MediaRendition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |flag| if flag { "YES" } else { "NO" };
+        writeln!(
+            f,
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",LANGUAGE=\"{}\",NAME=\"{}\",CHANNELS=\"{}\",AUTOSELECT={},DEFAULT={},URI=\"{}\"",
+            self.group_id,
+            self.language,
+            self.name,
+            self.channels,
+            yes_no(self.autoselect),
+            yes_no(self.default),
+            self.uri
+        )
+    }
+}
+
+impl fmt::Display for MasterPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#EXTM3U")?;
+        if self.independent_segments {
+            writeln!(f, "#EXT-X-INDEPENDENT-SEGMENTS")?;
+        }
+        writeln!(f)?;
+
+        let variants = self
+            .variants
+            .iter()
+            .map(|variant| variant as &dyn fmt::Display);
+        let renditions = self
+            .renditions
+            .iter()
+            .map(|rendition| rendition as &dyn fmt::Display);
+        let images = self.images.iter().map(|image| image as &dyn fmt::Display);
+        let entries: Vec<_> = match self.order {
+            MasterOrder::Ladder => variants.chain(renditions).chain(images).collect(),
+            MasterOrder::Apple => renditions.chain(variants).chain(images).collect(),
+        };
+        for entry in entries {
+            writeln!(f, "{entry}")?;
         }
         Ok(())
     }
 }
 
+/// Narrows the video rungs a master playlist offers, e.g. to the lowest
+/// ones for clients on mobile data. Audio renditions are always kept, as
+/// every variant refers to them. Rungs keep their usual variant URIs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RungFilter {
+    /// Indices into the full ladder; every rung when unset.
+    pub indices: Option<Vec<usize>>,
+    pub max_bitrate: Option<u32>,
+}
+
+impl RungFilter {
+    fn includes(&self, index: usize, stream: &Stream) -> bool {
+        if stream.kind == VariantKind::Audio {
+            return true;
+        }
+        let listed = self
+            .indices
+            .as_ref()
+            .is_none_or(|indices| indices.contains(&index));
+        let fits = self.max_bitrate.is_none_or(|max| stream.bitrate <= max);
+        listed && fits
+    }
+}
+
+impl Playlist {
+    pub fn master(&self) -> MasterPlaylist {
+        self.master_for(&RungFilter::default())
+    }
+
+    pub fn master_for(&self, filter: &RungFilter) -> MasterPlaylist {
+        let config = self.config;
+
+        let mut master = MasterPlaylist {
+            order: config.master_order,
+            independent_segments: self.independent_segments,
+            variants: Vec::new(),
+            renditions: Vec::new(),
+            images: Vec::new(),
+        };
+        let streams = self
+            .streams
+            .iter()
+            .enumerate()
+            .filter(|(i, stream)| filter.includes(*i, stream));
+
+        // Each video rung is offered once per audio group, with the codec
+        // of the group's first rendition.
+        let mut groups = Vec::<(String, &str)>::new();
+        for stream in &self.streams {
+            if let Some(rung) = &stream.audio
+                && !groups.iter().any(|(group, _)| *group == rung.group)
+            {
+                let codecs = stream.codecs.as_deref().unwrap_or(DEFAULT_AUDIO_CODECS);
+                groups.push((rung.group.clone(), codecs));
+            }
+        }
+        if groups.is_empty() {
+            groups.push((AudioRung::stereo().group, DEFAULT_AUDIO_CODECS));
+        }
+
+        for (i, stream) in streams {
+            let uri = format!("{}/hls/variant{i}.m3u8", config.base);
+            match (stream.kind, &stream.audio) {
+                (VariantKind::Video { width, height }, _) => {
+                    let video_codecs = stream.codecs.as_deref().unwrap_or(DEFAULT_VIDEO_CODECS);
+                    for (group, audio_codecs) in &groups {
+                        master.variants.push(VariantStreamInfo {
+                            bandwidth: stream.bitrate,
+                            width,
+                            height,
+                            codecs: format!("{video_codecs}, {audio_codecs}"),
+                            audio: group.clone(),
+                            uri: uri.clone(),
+                        });
+                    }
+                }
+                (VariantKind::Audio, rung) => {
+                    let rung = rung.clone().unwrap_or_else(AudioRung::stereo);
+                    // The first rendition in each group is its default.
+                    let default = !master
+                        .renditions
+                        .iter()
+                        .any(|rendition| rendition.group_id == rung.group);
+                    master.renditions.push(MediaRendition {
+                        group_id: rung.group,
+                        language: rung.language,
+                        name: rung.name,
+                        channels: rung.channels,
+                        autoselect: true,
+                        default,
+                        uri,
+                    });
+                }
+            }
+        }
+
+        if let Some(stream) = &self.images
+            && let VariantKind::Video { width, height } = stream.kind
+        {
+            master.images.push(ImageStreamInfo {
+                bandwidth: stream.bitrate,
+                width,
+                height,
+                uri: format!("{}/hls/images.m3u8", config.base),
+            });
+        }
+
+        if config.master_order == MasterOrder::Apple {
+            master.variants.sort_by_key(|variant| variant.bandwidth);
+        }
+        master
+    }
+
+    pub fn master_playlist(&self, out: &mut String) -> fmt::Result {
+        write!(out, "{}", self.master())
+    }
+
+    pub fn master_playlist_for(&self, out: &mut String, filter: &RungFilter) -> fmt::Result {
+        write!(out, "{}", self.master_for(filter))
+    }
+}
+
+impl Playlist {
+    /// The sequence numbers each stream advertises at `now`.
+    pub fn sequences(&self, now: Timestamp, takeovers: &Takeovers) -> Vec<Sequence> {
+        let playhead = self.at(now, takeovers);
+        self.streams
+            .iter()
+            .map(|stream| stream.sequence(&playhead))
+            .collect()
+    }
+
+    /// Offsets each stream so that, from `now` on, it never advertises a
+    /// lower sequence than `floor` (as persisted by a previous run). Without
+    /// this a change to the loop maths between deploys can move sequences
+    /// backwards, which players treat as a broken stream.
+    ///
+    /// The offset is fixed at startup, so sequences keep advancing at the
+    /// natural rate afterwards; they just never revisit served numbers.
+    /// Takeovers aren't persisted, so none have played yet.
+    pub fn rebase_sequences(&mut self, floor: &[Sequence], now: Timestamp) {
+        let current = self.sequences(now, &Takeovers::default());
+        for ((stream, current), floor) in self.streams.iter_mut().zip(current).zip(floor) {
+            stream.sequence_offset = current.offset_to(*floor);
+        }
+    }
+}
+
+/// What was playing at a given instant, for matching viewer reports to
+/// segments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SegmentLocation {
+    pub vid: u32,
+    /// Within the item's variant; the same for every stream of a package.
+    pub segment: usize,
+    /// As advertised by the first stream of the ladder.
+    pub media_sequence: usize,
+    pub discontinuity_sequence: usize,
+    pub loop_index: usize,
+}
+
+impl Playlist {
+    pub fn locate(&self, at: Timestamp, takeovers: &Takeovers) -> SegmentLocation {
+        let playhead = self.at(at, takeovers);
+        let anchor = &self.streams[0];
+        let sequence = anchor.sequence(&playhead);
+        let source = &anchor.sources[playhead.source_index];
+        SegmentLocation {
+            vid: source.vid,
+            segment: source.segment_offset + anchor.segment_index(&playhead),
+            media_sequence: sequence.media,
+            discontinuity_sequence: sequence.discontinuity,
+            loop_index: playhead.loop_index,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rung {
+    pub bitrate: u32,
+    #[serde(flatten)]
+    pub kind: VariantKind,
+}
+
 impl Playlist {
+    pub fn ladder(&self) -> Vec<Rung> {
+        self.streams
+            .iter()
+            .map(|stream| Rung {
+                bitrate: stream.bitrate,
+                kind: stream.kind,
+            })
+            .collect()
+    }
+
+    /// The store key of a package's poster, if it was packaged with one.
+    pub fn poster(&self, vid: u32) -> Option<String> {
+        let poster = self.posters.get(&vid)?;
+        Some(format!("{vid}/{}", poster.0))
+    }
+
+    /// Whether `vid`'s `resource` was in the store when the playlist
+    /// loaded, without touching the disk. Always true for stores that
+    /// aren't indexed.
+    pub fn has_blob(&self, vid: u32, resource: &RemoteResource) -> bool {
+        self.blobs
+            .as_ref()
+            .is_none_or(|blobs| blobs.contains(&format!("{vid}/{}", resource.0)))
+    }
+
+    /// Whether the media key `<vid>/<file>` is some variant's init segment.
+    pub fn is_init(&self, key: &str) -> bool {
+        self.inits.contains(key)
+    }
+
+    /// The length of the whole library, in media seconds.
+    pub fn loop_duration_seconds(&self) -> f64 {
+        self.duration.to_seconds(self.step)
+    }
+
+    /// How long one loop takes to play out at the configured speed.
+    pub fn loop_period(&self) -> SignedDuration {
+        SignedDuration::from_secs_f64(self.loop_duration_seconds() / self.config.speed as f64)
+    }
+
     pub fn schedule(&self) -> Schedule {
         Schedule {
             step: self.step,
@@ -357,4 +2124,1970 @@ impl Playlist {
             items: self.items.clone(),
         }
     }
+
+    /// What each of `streams` was built from, for telling whether they
+    /// still line up without rendering them.
+    pub fn stream_stats(&self) -> Vec<StreamStats> {
+        self.streams
+            .iter()
+            .map(|stream| StreamStats {
+                kind: stream.kind,
+                bitrate: stream.bitrate,
+                segments: stream.segments.len(),
+                duration_seconds: stream
+                    .segments
+                    .iter()
+                    .fold(Duration::zero(), |total, segment| {
+                        total.add(segment.duration)
+                    })
+                    .to_seconds(self.step),
+                max_segment_seconds: stream
+                    .segments
+                    .iter()
+                    .map(|segment| segment.duration)
+                    .max()
+                    .unwrap_or(Duration::zero())
+                    .to_seconds(self.step),
+            })
+            .collect()
+    }
+}
+
+/// One stream's segments; see [`Playlist::stream_stats`].
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStats {
+    pub kind: VariantKind,
+    pub bitrate: u32,
+    pub segments: usize,
+    pub duration_seconds: f64,
+    pub max_segment_seconds: f64,
+}
+
+/// The `<vid>/<file>` keys under each of `packages`' directories in a local
+/// store. Other stores can't be listed cheaply, so aren't indexed.
+fn index_blobs(store: &StoreConfig, packages: &[Package]) -> Option<BTreeSet<String>> {
+    let StoreConfig::Local { dir } = store else {
+        return None;
+    };
+    let mut blobs = BTreeSet::new();
+    for package in packages {
+        let Ok(entries) = fs::read_dir(Path::new(dir).join(package.vid.to_string())) else {
+            continue;
+        };
+        blobs.extend(
+            entries
+                .flatten()
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .map(|name| format!("{}/{name}", package.vid)),
+        );
+    }
+    Some(blobs)
+}
+
+/// Plays the newest of differing packages sharing a vid, such as an older
+/// copy of a package's JSON, wherever the vid is listed. A vid listed more
+/// than once is still played each time, in the listed order.
+fn dedup_packages(mut packages: Vec<Package>) -> Vec<Package> {
+    let mut newest = BTreeMap::<u32, &Package>::new();
+    let mut differing = BTreeSet::new();
+    for package in &packages {
+        let kept = newest.entry(package.vid).or_insert(package);
+        if kept.packaged_at != package.packaged_at {
+            differing.insert(package.vid);
+        }
+        if package.packaged_at > kept.packaged_at {
+            *kept = package;
+        }
+    }
+    let newest = differing
+        .into_iter()
+        .map(|vid| (vid, newest[&vid].clone()))
+        .collect::<BTreeMap<_, _>>();
+    for (vid, package) in &newest {
+        eprintln!(
+            "WARNING: package {vid} was loaded in differing versions; playing the one packaged at {}",
+            package.packaged_at
+        );
+    }
+    for package in &mut packages {
+        if let Some(newest) = newest.get(&package.vid) {
+            *package = newest.clone();
+        }
+    }
+    packages
+}
+
+/// Moves the transition `vid` to the end of the loop, and cuts its length
+/// from the end of the last package and the start of the first so that it
+/// plays in their place. Without the transition, or when its length isn't
+/// whole segments at either end, the loop is left as it was.
+fn crossfade_loop(mut packages: Vec<Package>, vid: u32) -> Vec<Package> {
+    let Some(index) = packages.iter().position(|package| package.vid == vid) else {
+        eprintln!("WARNING: loop_crossfade transition {vid} isn't loaded; looping without it");
+        return packages;
+    };
+    let transition = packages.remove(index);
+    let Some(seconds) = transition.variants.first().map(Variant::seconds) else {
+        return packages;
+    };
+
+    // Both ends are cut before either is put back, so a loop that can only
+    // be cut at one is left whole.
+    let Some(first) = packages
+        .first()
+        .and_then(|first| trim_package(first, seconds, false))
+    else {
+        eprintln!(
+            "WARNING: can't cut the {seconds:.3}s transition {vid} from the start of the loop; looping without it"
+        );
+        return packages;
+    };
+    // A lone package is cut at both ends.
+    let last = match packages.len() {
+        1 => &first,
+        _ => packages.last().unwrap(),
+    };
+    let Some(last) = trim_package(last, seconds, true) else {
+        eprintln!(
+            "WARNING: can't cut the {seconds:.3}s transition {vid} from the end of the loop; looping without it"
+        );
+        return packages;
+    };
+    let end = packages.len() - 1;
+    packages[0] = first;
+    packages[end] = last;
+    packages.push(transition);
+    packages
+}
+
+/// `package` without `seconds` of whole segments from the start or end of
+/// every variant; `None` when some variant's segments don't line up with it,
+/// or there'd be none left.
+fn trim_package(package: &Package, seconds: f64, from_end: bool) -> Option<Package> {
+    let mut trimmed = package.clone();
+    for variant in &mut trimmed.variants {
+        let time_base = variant.time_base.to_f64().unwrap();
+        let mut ordered = variant.segments.iter().collect::<Vec<_>>();
+        if from_end {
+            ordered.reverse();
+        }
+
+        let mut count = 0;
+        let mut cut = 0;
+        while (cut as f64 * time_base) < seconds - CROSSFADE_TOLERANCE {
+            cut += ordered.get(count)?.duration;
+            count += 1;
+        }
+        if ((cut as f64 * time_base) - seconds).abs() > CROSSFADE_TOLERANCE
+            || count == variant.segments.len()
+        {
+            return None;
+        }
+
+        if from_end {
+            let kept = variant.segments.len() - count;
+            variant.segments.truncate(kept);
+        } else {
+            variant.segments.drain(..count);
+            variant.cues = variant
+                .cues
+                .iter()
+                .filter_map(|cue| cue.checked_sub(cut))
+                .collect();
+            variant.ad_breaks = variant
+                .ad_breaks
+                .iter()
+                .filter_map(|ad_break| {
+                    Some(AdBreak {
+                        out: ad_break.out.checked_sub(cut)?,
+                        back: ad_break.back.checked_sub(cut)?,
+                    })
+                })
+                .collect();
+        }
+    }
+    Some(trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::test_config, package::Thumbnails};
+
+    fn variant(kind: VariantKind, bitrate: u32, segment_durations: &[u64]) -> Variant {
+        let time_base = Ratio::new(1, 1000);
+        let mut start = 0;
+        let segments = segment_durations
+            .iter()
+            .enumerate()
+            .map(|(i, duration)| {
+                let segment = Segment {
+                    src: RemoteResource(format!("s{bitrate}_{i}.mp4")),
+                    start,
+                    duration: *duration,
+                    range: None,
+                    size: None,
+                    keyframe: None,
+                };
+                start += duration;
+                segment
+            })
+            .collect();
+        Variant {
+            init_src: Some(RemoteResource(format!("init{bitrate}.mp4"))),
+            init_range: None,
+            time_base,
+            bitrate,
+            channels: (kind == VariantKind::Audio).then_some(2),
+            language: None,
+            cues: Vec::new(),
+            ad_breaks: Vec::new(),
+            codecs: None,
+            kind,
+            segments,
+        }
+    }
+
+    fn package(vid: u32, video_segments: &[u64], audio_segments: &[u64]) -> Package {
+        Package {
+            vid,
+            packaged_at: Timestamp::UNIX_EPOCH,
+            variants: vec![
+                variant(
+                    VariantKind::Video {
+                        width: 1920,
+                        height: 1080,
+                    },
+                    5000000,
+                    video_segments,
+                ),
+                variant(
+                    VariantKind::Video {
+                        width: 1280,
+                        height: 720,
+                    },
+                    1500000,
+                    video_segments,
+                ),
+                variant(
+                    VariantKind::Video {
+                        width: 960,
+                        height: 540,
+                    },
+                    400000,
+                    video_segments,
+                ),
+                variant(VariantKind::Audio, 192000, audio_segments),
+            ],
+            poster: None,
+            thumbnails: None,
+            repeats: None,
+        }
+    }
+
+    fn playlist(config: &str) -> Playlist {
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3])],
+            test_config(config),
+        )
+    }
+
+    /// A package of MPEG-TS segments, which need no init segment.
+    fn ts_package(vid: u32, video_segs: &[u64], audio_segs: &[u64]) -> Package {
+        let mut package = package(vid, video_segs, audio_segs);
+        for variant in &mut package.variants {
+            variant.init_src = None;
+            for (i, segment) in variant.segments.iter_mut().enumerate() {
+                segment.src = RemoteResource(format!("s{}_{i}.ts", variant.bitrate));
+            }
+        }
+        package
+    }
+
+    fn render(playlist: &Playlist, stream: usize) -> String {
+        let mut out = String::new();
+        playlist.streams[stream]
+            .render_variant_playlist(
+                &mut out,
+                playlist,
+                &Takeovers::default(),
+                Timestamp::UNIX_EPOCH,
+            )
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_playlist_capacity() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 2], &[10000; 2]),
+            ],
+            test_config(""),
+        );
+        for (i, stream) in playlist.streams.iter().enumerate() {
+            let out = render(&playlist, i);
+            assert!(
+                out.len() <= stream.capacity(),
+                "{} > {}",
+                out.len(),
+                stream.capacity()
+            );
+            assert!(out.len() * 2 > stream.capacity());
+        }
+    }
+
+    #[test]
+    fn test_window_seconds() {
+        let packages = || {
+            vec![
+                package(1, &[2000, 12000, 4000, 6000], &[6000; 4]),
+                package(2, &[3000; 5], &[5000; 3]),
+            ]
+        };
+        let extinfs = |out: &str| {
+            out.lines()
+                .filter_map(|line| line.strip_prefix("#EXTINF:"))
+                .map(|extinf| extinf.trim_end_matches(',').parse::<f64>().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), test_config(""));
+        assert_eq!(extinfs(&render(&playlist, 0)).len(), LOOKAHEAD);
+
+        let config = test_config("window_seconds = 60");
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), config);
+        for i in [0, 3] {
+            let durations = extinfs(&render(&playlist, i));
+            let total = durations.iter().sum::<f64>();
+            assert!(total >= 60.0, "{total}");
+            assert!(total - durations.last().unwrap() < 60.0, "{durations:?}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "window_seconds of 20s is under the 30s hold-back")]
+    fn test_window_under_hold_back() {
+        playlist("window_seconds = 20");
+    }
+
+    #[test]
+    fn test_loop_wrap() {
+        let render_at = |playlist: &Playlist, second| {
+            let mut out = String::new();
+            playlist.streams[0]
+                .render_variant_playlist(
+                    &mut out,
+                    playlist,
+                    &Takeovers::default(),
+                    Timestamp::from_second(second).unwrap(),
+                )
+                .unwrap();
+            out
+        };
+
+        // One segment before the wrap, from the last source back to the
+        // first.
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 3], &[10000; 3]),
+            ],
+            test_config(""),
+        );
+        let out = render_at(&playlist, 55);
+        assert!(out.contains(
+            "#EXT-X-MEDIA-SEQUENCE:5\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+             #EXT-X-MAP:URI=\"http://localhost/media/2/init5000000.mp4\"\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/2/s5000000_2.mp4\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_0.mp4\n"
+        ));
+
+        // A lone package wraps onto itself, and must still map again.
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3])],
+            test_config(""),
+        );
+        let out = render_at(&playlist, 25);
+        assert!(out.contains(
+            "http://localhost/media/1/s5000000_2.mp4\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_0.mp4\n"
+        ));
+        assert_eq!(
+            out.matches("#EXT-X-MAP").count(),
+            out.matches("#EXT-X-DISCONTINUITY\n").count() + 1
+        );
+    }
+
+    #[test]
+    fn test_stream_stats() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000, 12000], &[5000; 4]),
+                package(2, &[8000; 3], &[6000; 4]),
+            ],
+            test_config(""),
+        );
+        let stats = playlist.stream_stats();
+        assert_eq!(stats.len(), playlist.streams.len());
+        let top = &stats[0];
+        assert_eq!((top.bitrate, top.segments), (5000000, 5));
+        assert_eq!(
+            (top.duration_seconds, top.max_segment_seconds),
+            (46.0, 12.0)
+        );
+
+        let audio = stats
+            .iter()
+            .find(|stats| stats.kind == VariantKind::Audio && stats.segments > 0)
+            .unwrap();
+        assert_eq!(audio.segments, 8);
+        assert_eq!(
+            (audio.duration_seconds, audio.max_segment_seconds),
+            (44.0, 6.0)
+        );
+    }
+
+    #[test]
+    fn test_target_duration_per_stream() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[5000; 6])],
+            test_config(""),
+        );
+
+        assert!(render(&playlist, 0).contains("#EXT-X-TARGETDURATION:10\n"));
+        assert!(render(&playlist, 3).contains("#EXT-X-TARGETDURATION:5\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "package 2's 192000bps variant has no duration")]
+    fn test_zero_duration_variant() {
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 3], &[]),
+            ],
+            test_config(""),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "package 2's 8000000bps variant fits none of the streams")]
+    fn test_variant_without_stream() {
+        let mut oversized = package(2, &[10000; 3], &[10000; 3]);
+        oversized.variants[0].bitrate = 8000000;
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3]), oversized],
+            test_config(""),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "package 2 has 0 variants for the 1500000bps")]
+    fn test_missing_rung() {
+        let mut missing = package(2, &[10000; 3], &[10000; 3]);
+        missing.variants.remove(1);
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3]), missing],
+            test_config(""),
+        );
+    }
+
+    #[test]
+    fn test_configured_target_durations() {
+        let config = test_config("target_durations = { video = 10, audio = 5 }");
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[8000; 3], &[4000; 6])],
+            config,
+        );
+        assert!(render(&playlist, 0).contains("#EXT-X-TARGETDURATION:10\n"));
+        assert!(render(&playlist, 3).contains("#EXT-X-TARGETDURATION:5\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "aren't multiples of one another")]
+    fn test_misaligned_target_durations() {
+        test_config("target_durations = { video = 10, audio = 4 }");
+    }
+
+    #[test]
+    #[should_panic(expected = "has 8s segments, over its target duration of 6s")]
+    fn test_segments_over_target_duration() {
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[8000; 3], &[4000; 6])],
+            test_config("target_durations = { video = 6, audio = 2 }"),
+        );
+    }
+
+    #[test]
+    fn test_master_playlist_apple_order() {
+        let playlist = playlist(r#"master_order = "apple""#);
+        let mut out = String::new();
+        playlist.master_playlist(&mut out).unwrap();
+
+        assert_eq!(
+            out,
+            "\
+#EXTM3U
+#EXT-X-INDEPENDENT-SEGMENTS
+
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"en\",NAME=\"aac_192\",CHANNELS=\"2\",AUTOSELECT=YES,DEFAULT=YES,URI=\"http://localhost/hls/variant3.m3u8\"
+
+#EXT-X-STREAM-INF:BANDWIDTH=400000,RESOLUTION=960x540,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+http://localhost/hls/variant2.m3u8
+
+#EXT-X-STREAM-INF:BANDWIDTH=1500000,RESOLUTION=1280x720,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+http://localhost/hls/variant1.m3u8
+
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+http://localhost/hls/variant0.m3u8
+
+"
+        );
+    }
+
+    #[test]
+    fn test_extinf_precision() {
+        let step = StepSize::calculate([Ratio::new(1, 3)].into_iter());
+        let mut start = Duration::zero();
+        let segments = (0..16)
+            .map(|_| {
+                let duration = Duration::new(10, Ratio::new(1, 3), step);
+                let segment = StreamSegment {
+                    duration,
+                    start,
+                    src: RemoteResource("segment.m4s".to_string()),
+                    range: None,
+                    size: None,
+                };
+                start = start.add(duration);
+                segment
+            })
+            .collect::<Vec<_>>();
+        for precision in [3, 6] {
+            let extinf = ExtinfRounder::new(precision);
+            let durations = segments
+                .iter()
+                .map(|segment| extinf.extinf(segment, step))
+                .collect::<Vec<_>>();
+            assert!(
+                durations
+                    .iter()
+                    .all(|d| d.split_once('.').unwrap().1.len() == precision)
+            );
+            // Rounding each on its own would drift by 16 × 0.000333…s.
+            let total = durations
+                .iter()
+                .map(|d| d.parse::<f64>().unwrap())
+                .sum::<f64>();
+            assert!((total - 160.0 / 3.0).abs() <= 0.5 * 10f64.powi(-(precision as i32)) + 1e-9);
+        }
+
+        let config = test_config(
+            r#"
+            player_profile = "legacy"
+            [player_profiles.legacy]
+            extinf_precision = 3
+            "#,
+        );
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3])],
+            config,
+        );
+        assert!(render(&playlist, 0).contains("#EXTINF:10.000,\n"));
+    }
+
+    #[test]
+    fn test_extinf_stable_across_windows() {
+        let config = test_config(
+            r#"
+            player_profile = "legacy"
+            [player_profiles.legacy]
+            extinf_precision = 0
+            "#,
+        );
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[3400; 10], &[3400; 10]),
+                package(2, &[3400; 10], &[3400; 10]),
+            ],
+            config,
+        );
+        let extinfs = |now: Timestamp| {
+            let mut out = String::new();
+            playlist.streams[0]
+                .render_variant_playlist(&mut out, &playlist, &Takeovers::default(), now)
+                .unwrap();
+            let lines = out.lines().collect::<Vec<_>>();
+            lines
+                .iter()
+                .zip(&lines[1..])
+                .filter_map(|(extinf, uri)| {
+                    Some((
+                        uri.to_string(),
+                        extinf.strip_prefix("#EXTINF:")?.to_string(),
+                    ))
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let first = extinfs(Timestamp::UNIX_EPOCH);
+        let second = extinfs(Timestamp::UNIX_EPOCH + jiff::SignedDuration::from_secs(4));
+        let shared = first
+            .iter()
+            .filter(|(uri, _)| second.contains_key(*uri))
+            .collect::<Vec<_>>();
+        assert!(shared.len() > 1);
+        for (uri, extinf) in shared {
+            assert_eq!(&second[uri], extinf, "{uri}");
+        }
+    }
+
+    #[test]
+    fn test_event_playlist() {
+        let start = Timestamp::now();
+        let playlist = Playlist::with_config(
+            start,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 2], &[10000; 2]),
+            ],
+            test_config("event_playlists = true"),
+        );
+
+        let mut out = String::new();
+        playlist.streams[0]
+            .render_variant_playlist(
+                &mut out,
+                &playlist,
+                &Takeovers::default(),
+                start + jiff::SignedDuration::from_secs(35),
+            )
+            .unwrap();
+        assert!(out.contains("#EXT-X-PLAYLIST-TYPE:EVENT\n"));
+        assert!(out.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:0\n"));
+        assert!(!out.contains("#EXT-X-ENDLIST"));
+        // Everything from the first segment on, then a window past the
+        // playhead.
+        let vids = out
+            .lines()
+            .filter(|line| line.starts_with("http"))
+            .map(|line| line.split('/').nth_back(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vids[..5], ["1", "1", "1", "2", "2"]);
+        assert_eq!(vids.len(), 3 + LOOKAHEAD);
+
+        // Past the wrap at 50s, the next loop is appended rather than
+        // starting over.
+        let mut later = String::new();
+        playlist.streams[0]
+            .render_variant_playlist(
+                &mut later,
+                &playlist,
+                &Takeovers::default(),
+                start + jiff::SignedDuration::from_secs(55),
+            )
+            .unwrap();
+        assert!(later.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        let body = |out: &str| {
+            let (_, body) = out.split_once("#EXT-X-DISCONTINUITY-SEQUENCE:0\n").unwrap();
+            body.to_string()
+        };
+        assert!(body(&later).starts_with(&body(&out)));
+        assert!(body(&later).len() > body(&out).len());
+    }
+
+    #[test]
+    #[should_panic(expected = "play them from a channel with a recent start")]
+    fn test_event_playlist_from_epoch() {
+        // The main channel's start, which would list decades of loops.
+        Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3])],
+            test_config("event_playlists = true"),
+        );
+    }
+
+    #[test]
+    fn test_takeover() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 2], &[10000; 2]),
+            ],
+            test_config(""),
+        );
+        let at = |secs| Timestamp::UNIX_EPOCH + jiff::SignedDuration::from_secs(secs);
+        let takeovers = Takeovers::default();
+
+        assert_eq!(
+            playlist.take_over(at(5), &takeovers, 3),
+            Err(TakeoverError::UnknownVid)
+        );
+        // Cuts in once the first item ends at 30s.
+        assert_eq!(
+            playlist.take_over(at(5), &takeovers, 2),
+            Ok(TakeoverWindow {
+                vid: 2,
+                start: at(30),
+                end: at(50),
+            })
+        );
+        assert_eq!(
+            playlist.take_over(at(10), &takeovers, 1),
+            Err(TakeoverError::Busy)
+        );
+
+        let mut out = String::new();
+        playlist.streams[0]
+            .render_variant_playlist(&mut out, &playlist, &takeovers, at(5))
+            .unwrap();
+        let vids = out
+            .lines()
+            .filter(|line| line.starts_with("http"))
+            .map(|line| line.split('/').nth_back(1).unwrap())
+            .take(8)
+            .collect::<Vec<_>>();
+        assert_eq!(vids, ["1", "1", "1", "2", "2", "2", "2", "1"]);
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY\n").count(), 6);
+
+        let location =
+            |vid, segment, media_sequence, discontinuity_sequence, loop_index| SegmentLocation {
+                vid,
+                segment,
+                media_sequence,
+                discontinuity_sequence,
+                loop_index,
+            };
+        assert_eq!(playlist.locate(at(35), &takeovers), location(2, 0, 3, 1, 0));
+        // The loop picks up with the second item, 20s late, and sequence
+        // numbers carry on from the takeover's.
+        assert_eq!(playlist.locate(at(55), &takeovers), location(2, 0, 5, 2, 0));
+        assert_eq!(playlist.locate(at(75), &takeovers), location(1, 0, 7, 3, 1));
+
+        // The first has played out, so another can follow, and the first
+        // is only kept as the totals it adds to everything after.
+        assert!(playlist.take_over(at(75), &takeovers, 1).is_ok());
+        assert_eq!(takeovers.0.lock().unwrap().played.sources(), 1);
+        assert_eq!(playlist.locate(at(75), &takeovers), location(1, 0, 7, 3, 1));
+    }
+
+    #[test]
+    fn test_takeover_of_repeated_package() {
+        let mut sponsor = package(2, &[10000; 2], &[10000; 2]);
+        sponsor.repeats = Some(2);
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3]), sponsor],
+            test_config(""),
+        );
+        let at = |secs| Timestamp::UNIX_EPOCH + jiff::SignedDuration::from_secs(secs);
+        let takeovers = Takeovers::default();
+
+        // Once, not as many times as the loop plays it.
+        assert_eq!(
+            playlist.take_over(at(5), &takeovers, 2),
+            Ok(TakeoverWindow {
+                vid: 2,
+                start: at(30),
+                end: at(50),
+            })
+        );
+        // Then the loop picks up with its own first play of it.
+        assert_eq!(playlist.locate(at(55), &takeovers).vid, 2);
+        assert_eq!(playlist.locate(at(55), &takeovers).loop_index, 0);
+        assert_eq!(playlist.locate(at(55), &takeovers).segment, 0);
+    }
+
+    #[test]
+    fn test_locate_across_loop_boundary() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 2], &[10000; 2]),
+            ],
+            test_config(""),
+        );
+        let at = |secs| Timestamp::UNIX_EPOCH + jiff::SignedDuration::from_secs(secs);
+
+        assert_eq!(
+            playlist.locate(at(45), &Takeovers::default()),
+            SegmentLocation {
+                vid: 2,
+                segment: 1,
+                media_sequence: 4,
+                discontinuity_sequence: 1,
+                loop_index: 0,
+            }
+        );
+        // The 50s loop starts over with the first item.
+        assert_eq!(
+            playlist.locate(at(55), &Takeovers::default()),
+            SegmentLocation {
+                vid: 1,
+                segment: 0,
+                media_sequence: 5,
+                discontinuity_sequence: 2,
+                loop_index: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_zero_duration_segment() {
+        let mut package = package(1, &[10000; 3], &[10000; 3]);
+        package.variants[0].segments[1].duration = 0;
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, vec![package], test_config(""));
+
+        let out = render(&playlist, 0);
+        assert!(!out.contains("s5000000_1.mp4"));
+        assert!(out.contains("#EXTINF:10.000000,\nhttp://localhost/media/1/s5000000_0.mp4\n"));
+        assert!(out.contains("#EXTINF:10.000000,\nhttp://localhost/media/1/s5000000_2.mp4\n"));
+
+        // The other streams are unaffected.
+        assert!(render(&playlist, 1).contains("s1500000_1.mp4"));
+    }
+
+    #[test]
+    fn test_master_playlist_struct() {
+        let master = MasterPlaylist {
+            order: MasterOrder::Ladder,
+            independent_segments: true,
+            variants: vec![VariantStreamInfo {
+                bandwidth: 400000,
+                width: 960,
+                height: 540,
+                codecs: "avc1.64e01f, mp4a.40.2".to_string(),
+                audio: "audio".to_string(),
+                uri: "/hls/variant0.m3u8".to_string(),
+            }],
+            renditions: vec![MediaRendition {
+                group_id: "audio".to_string(),
+                language: "en".to_string(),
+                name: "aac_192".to_string(),
+                channels: 2,
+                autoselect: true,
+                default: true,
+                uri: "/hls/variant1.m3u8".to_string(),
+            }],
+            images: Vec::new(),
+        };
+
+        assert_eq!(
+            master.to_string(),
+            "\
+#EXTM3U
+#EXT-X-INDEPENDENT-SEGMENTS
+
+#EXT-X-STREAM-INF:BANDWIDTH=400000,RESOLUTION=960x540,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+/hls/variant0.m3u8
+
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"en\",NAME=\"aac_192\",CHANNELS=\"2\",AUTOSELECT=YES,DEFAULT=YES,URI=\"/hls/variant1.m3u8\"
+
+"
+        );
+        assert_eq!(
+            serde_json::to_value(&master).unwrap(),
+            serde_json::json!({
+                "variants": [{
+                    "bandwidth": 400000,
+                    "width": 960,
+                    "height": 540,
+                    "codecs": "avc1.64e01f, mp4a.40.2",
+                    "audio": "audio",
+                    "uri": "/hls/variant0.m3u8",
+                }],
+                "renditions": [{
+                    "group_id": "audio",
+                    "language": "en",
+                    "name": "aac_192",
+                    "channels": 2,
+                    "autoselect": true,
+                    "default": true,
+                    "uri": "/hls/variant1.m3u8",
+                }],
+            })
+        );
+
+        let playlist = playlist("");
+        let master = playlist.master();
+        assert_eq!(master.variants.len(), 3);
+        assert_eq!(master.variants[0].bandwidth, 5000000);
+        assert_eq!(
+            master.renditions[0].uri,
+            "http://localhost/hls/variant3.m3u8"
+        );
+    }
+
+    #[test]
+    fn test_surround_rendition() {
+        let with_surround = |vid| {
+            let mut package = package(vid, &[10000; 3], &[10000; 3]);
+            let mut surround = variant(VariantKind::Audio, 384000, &[10000; 3]);
+            surround.channels = Some(6);
+            package.variants.push(surround);
+            package
+        };
+
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_surround(1), with_surround(2)],
+            test_config(""),
+        );
+        let mut out = String::new();
+        playlist.master_playlist(&mut out).unwrap();
+        assert!(out.contains("NAME=\"aac_192\",CHANNELS=\"2\",AUTOSELECT=YES,DEFAULT=YES,URI=\"http://localhost/hls/variant3.m3u8\""));
+        assert!(out.contains("NAME=\"surround\",CHANNELS=\"6\",AUTOSELECT=YES,DEFAULT=NO,URI=\"http://localhost/hls/variant4.m3u8\""));
+        assert!(render(&playlist, 4).contains("\nhttp://localhost/media/2/s384000_0.mp4\n"));
+
+        // One stereo-only item means the surround stream would have a hole.
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_surround(1), package(2, &[10000; 3], &[10000; 3])],
+            test_config(""),
+        );
+        assert_eq!(playlist.streams.len(), 4);
+    }
+
+    #[test]
+    fn test_audio_rungs() {
+        let config = test_config(
+            r#"
+            [[audio_rungs]]
+            bitrate = 192000
+            name = "English"
+
+            [[audio_rungs]]
+            bitrate = 192000
+            language = "fr"
+            name = "Français"
+
+            [[audio_rungs]]
+            bitrate = 64000
+            name = "English (low)"
+            group = "low"
+            "#,
+        );
+        let with_audio = |vid| {
+            let mut package = package(vid, &[10000; 3], &[10000; 3]);
+            let mut french = variant(VariantKind::Audio, 128000, &[10000; 3]);
+            french.language = Some("fr".to_string());
+            package.variants.push(french);
+            package
+                .variants
+                .push(variant(VariantKind::Audio, 64000, &[10000; 3]));
+            package
+        };
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_audio(1), with_audio(2)],
+            config,
+        );
+        assert_eq!(playlist.streams.len(), 6);
+
+        let master = playlist.master();
+        assert_eq!(master.variants.len(), 6);
+        assert_eq!(
+            master
+                .variants
+                .iter()
+                .filter(|variant| variant.audio == "low")
+                .count(),
+            3
+        );
+        let renditions = master
+            .renditions
+            .iter()
+            .map(|rendition| {
+                (
+                    rendition.group_id.as_str(),
+                    rendition.language.as_str(),
+                    rendition.default,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            renditions,
+            [
+                ("audio", "en", true),
+                ("audio", "fr", false),
+                ("low", "en", true)
+            ]
+        );
+
+        assert!(render(&playlist, 3).contains("\nhttp://localhost/media/2/s192000_0.mp4\n"));
+        assert!(render(&playlist, 4).contains("\nhttp://localhost/media/2/s128000_0.mp4\n"));
+        assert!(render(&playlist, 5).contains("\nhttp://localhost/media/2/s64000_0.mp4\n"));
+    }
+
+    #[test]
+    fn test_hold_back() {
+        let packages = || vec![package(1, &[10000; 3], &[10000; 3])];
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), test_config(""));
+        assert!(!render(&playlist, 0).contains("HOLD-BACK"));
+
+        let config = test_config("server_hold_back = true");
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), config);
+        assert!(render(&playlist, 0).contains("\n#EXT-X-SERVER-CONTROL:HOLD-BACK=30.0\n"));
+
+        let config = test_config("server_hold_back = true\nhold_back_seconds = 45");
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), config);
+        assert!(render(&playlist, 0).contains("\n#EXT-X-SERVER-CONTROL:HOLD-BACK=45.0\n"));
+
+        // Below the minimum, players get the minimum.
+        let config =
+            test_config("server_hold_back = true\nhold_back_seconds = 12\ndelta_playlists = true");
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), config);
+        assert!(
+            render(&playlist, 0)
+                .contains("\n#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=60.0,HOLD-BACK=30.0\n")
+        );
+    }
+
+    #[test]
+    fn test_ad_break_splices() {
+        let mut with_break = package(1, &[10000; 3], &[10000; 3]);
+        for variant in &mut with_break.variants {
+            variant.ad_breaks = vec![AdBreak {
+                out: 10000,
+                back: 20000,
+            }];
+        }
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_break, package(2, &[10000; 3], &[10000; 3])],
+            test_config(&format!("player_profile = \"modern\"\n{PLAYER_PROFILES}")),
+        );
+
+        // Out at 10s and back in at 20s, each a discontinuity, under one ID
+        // and dated from the out point.
+        let out = render(&playlist, 0);
+        assert!(out.contains(&format!(
+            "#EXT-X-DISCONTINUITY\n\
+             #EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:10.000Z\n\
+             #EXT-X-DATERANGE:ID=\"splice-1\",START-DATE=\"1970-01-01T00:00:10.000Z\",PLANNED-DURATION=10.000,SCTE35-OUT={}\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_1.mp4\n",
+            scte35::splice_insert(1, true, Some(10.0))
+        )));
+        assert!(out.contains(&format!(
+            "#EXT-X-DISCONTINUITY\n\
+             #EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:20.000Z\n\
+             #EXT-X-DATERANGE:ID=\"splice-1\",START-DATE=\"1970-01-01T00:00:10.000Z\",END-DATE=\"1970-01-01T00:00:20.000Z\",SCTE35-IN={}\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_2.mp4\n",
+            scte35::splice_insert(1, false, None)
+        )));
+        assert!(!out.contains("CLASS=\"cue\""));
+        // The next loop's break pairs up the same way.
+        assert_eq!(out.matches("ID=\"splice-5\"").count(), 2);
+    }
+
+    #[test]
+    fn test_mismatched_cues() {
+        // Cues on the video alone would cut it into more sources than the
+        // audio, so they're ignored, ad breaks and all.
+        let mut with_cue = package(1, &[10000; 3], &[10000; 3]);
+        with_cue.variants[0].cues = vec![15000];
+        with_cue.variants[1].ad_breaks = vec![AdBreak {
+            out: 10000,
+            back: 20000,
+        }];
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_cue, package(2, &[10000; 3], &[10000; 3])],
+            test_config(&format!("player_profile = \"modern\"\n{PLAYER_PROFILES}")),
+        );
+        assert!(
+            playlist
+                .streams
+                .iter()
+                .all(|stream| stream.sources.len() == 2)
+        );
+        assert!(!render(&playlist, 0).contains("#EXT-X-DATERANGE"));
+    }
+
+    #[test]
+    fn test_cue_discontinuity() {
+        let mut with_cue = package(1, &[10000; 3], &[10000; 3]);
+        for variant in &mut with_cue.variants {
+            variant.cues = vec![15000];
+        }
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_cue, package(2, &[10000; 3], &[10000; 3])],
+            test_config(&format!("player_profile = \"modern\"\n{PLAYER_PROFILES}")),
+        );
+        assert_eq!(playlist.schedule().items.len(), 2);
+
+        // The cue falls to the boundary at 20s, an extra discontinuity
+        // beside the one between the packages at 30s.
+        let out = render(&playlist, 0);
+        assert!(out.contains(
+            "#EXT-X-DISCONTINUITY\n\
+             #EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:20.000Z\n\
+             #EXT-X-DATERANGE:ID=\"cue-1\",CLASS=\"cue\",START-DATE=\"1970-01-01T00:00:20.000Z\"\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_2.mp4\n"
+        ));
+        assert!(out.contains(
+            "#EXT-X-DISCONTINUITY\n#EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:30.000Z\n#EXT-X-MAP"
+        ));
+        // The loop repeats every 60s.
+        assert_eq!(out.matches("#EXT-X-DATERANGE").count(), 3);
+        assert!(out.contains("ID=\"cue-4\""));
+
+        let location = playlist.locate(Timestamp::from_second(25).unwrap(), &Takeovers::default());
+        assert_eq!((location.vid, location.segment), (1, 2));
+
+        let mut preview = String::new();
+        playlist.streams[0]
+            .render_preview_playlist(&mut preview, &playlist, 1)
+            .unwrap();
+        assert_eq!(preview.matches("#EXTINF").count(), 3);
+    }
+
+    #[test]
+    fn test_continuous_sources() {
+        // Starting `start` ms in, where the package before left off.
+        let probed = |vid, video_codecs: &str, start| {
+            let mut package = package(vid, &[10000; 3], &[10000; 3]);
+            for variant in &mut package.variants {
+                variant.codecs = Some(match variant.kind {
+                    VariantKind::Audio => "mp4a.40.2".to_string(),
+                    _ => video_codecs.to_string(),
+                });
+                for segment in &mut variant.segments {
+                    segment.start += start;
+                }
+            }
+            package
+        };
+        let render_continuous = |packages, seconds| {
+            let playlist = Playlist::with_config(
+                Timestamp::UNIX_EPOCH,
+                packages,
+                test_config("continuous = true"),
+            );
+            let mut out = String::new();
+            playlist.streams[0]
+                .render_variant_playlist(
+                    &mut out,
+                    &playlist,
+                    &Takeovers::default(),
+                    Timestamp::UNIX_EPOCH + SignedDuration::from_secs(seconds),
+                )
+                .unwrap();
+            out
+        };
+        let compatible = || vec![probed(1, "avc1.64001f", 0), probed(2, "avc1.64001f", 30000)];
+
+        // Only the loop starting over is marked, its timestamps going back.
+        let out = render_continuous(compatible(), 0);
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:0\n"), "{out}");
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY\n").count(), 2, "{out}");
+        assert_eq!(
+            out.matches(
+                "#EXT-X-DISCONTINUITY\n\
+                 #EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n\
+                 #EXTINF:10.000000,\n\
+                 http://localhost/media/1/s5000000_0.mp4\n"
+            )
+            .count(),
+            2,
+            "{out}"
+        );
+        // Still remapped for the second package's init segment.
+        assert!(
+            out.contains("#EXT-X-MAP:URI=\"http://localhost/media/2/"),
+            "{out}"
+        );
+        // Counting loops rather than packages once the first wrap is past.
+        let out = render_continuous(compatible(), 65);
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"), "{out}");
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY\n").count(), 2, "{out}");
+
+        let out = render_continuous(
+            vec![probed(1, "avc1.64001f", 0), probed(2, "avc1.4d401f", 30000)],
+            0,
+        );
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:0\n"), "{out}");
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY\n").count(), 5, "{out}");
+
+        // The second package's timestamps start over.
+        let out = render_continuous(
+            vec![probed(1, "avc1.64001f", 0), probed(2, "avc1.64001f", 0)],
+            0,
+        );
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY\n").count(), 5, "{out}");
+
+        // Unprobed codecs might differ.
+        let out = render_continuous(vec![package(1, &[10000; 3], &[10000; 3]); 2], 0);
+        assert!(out.contains("#EXT-X-DISCONTINUITY\n"), "{out}");
+    }
+
+    #[test]
+    fn test_load_empty_directory() {
+        let dir = std::env::temp_dir().join("frameserve-empty-packages");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let packages = Playlist::load_packages(dir.to_str().unwrap(), test_config(""));
+        assert!(packages.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "no packages to play")]
+    fn test_empty_playlist() {
+        Playlist::with_config(Timestamp::UNIX_EPOCH, Vec::new(), test_config(""));
+    }
+
+    #[test]
+    fn test_probed_codecs() {
+        // Asked for High, but the encoder settled for Main.
+        let mut main = package(1, &[10000; 3], &[10000; 3]);
+        main.variants[0].codecs = Some("avc1.4d401f".to_string());
+        main.variants[3].codecs = Some("mp4a.40.5".to_string());
+        // Written by hand, in upper case and the legacy decimal form.
+        let mut older = package(2, &[10000; 3], &[10000; 3]);
+        older.variants[0].codecs = Some("AVC1.100.40".to_string());
+        older.variants[1].codecs = Some("avc1.77.30".to_string());
+        let playlist =
+            Playlist::with_config(Timestamp::UNIX_EPOCH, vec![main, older], test_config(""));
+        let master = playlist.master();
+        assert_eq!(master.variants[0].codecs, "avc1.640028, mp4a.40.5");
+        assert_eq!(master.variants[1].codecs, "avc1.4d001e, mp4a.40.5");
+        // Nothing probed for the other rungs.
+        assert_eq!(master.variants[2].codecs, "avc1.64e01f, mp4a.40.5");
+    }
+
+    #[test]
+    fn test_source_offset() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000; 3], &[10000; 3]),
+            ],
+            test_config(""),
+        );
+        let now = Timestamp::UNIX_EPOCH;
+        assert!(
+            playlist
+                .at_source_offset(now, &Takeovers::default(), 30.0)
+                .is_none()
+        );
+        assert!(
+            playlist
+                .at_source_offset(now, &Takeovers::default(), -1.0)
+                .is_none()
+        );
+
+        let playhead = playlist
+            .at_source_offset(now, &Takeovers::default(), 15.0)
+            .unwrap();
+        let mut out = String::new();
+        playlist.streams[0]
+            .render_variant_playlist_at(&mut out, &playlist, now, &playhead)
+            .unwrap();
+        let first = out.lines().find(|line| line.starts_with("http")).unwrap();
+        assert_eq!(first, "http://localhost/media/1/s5000000_1.mp4");
+        assert!(out.contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+    }
+
+    #[test]
+    fn test_preview_playlist() {
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000, 10000, 10000, 4000], &[10000; 3]),
+            ],
+            test_config(""),
+        );
+        let stream = &playlist.streams[0];
+        assert!(stream.contains_vid(2));
+        assert!(!stream.contains_vid(3));
+
+        let mut out = String::new();
+        stream
+            .render_preview_playlist(&mut out, &playlist, 2)
+            .unwrap();
+        assert_eq!(out.matches("#EXTINF:").count(), 4);
+        assert_eq!(out.matches("#EXT-X-DISCONTINUITY").count(), 0);
+        assert!(out.contains("#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(out.contains("#EXT-X-MAP:URI=\"http://localhost/media/2/init5000000.mp4\"\n"));
+        assert!(out.contains("#EXTINF:4.000000,\nhttp://localhost/media/2/s5000000_3.mp4\n"));
+        assert!(out.ends_with("#EXT-X-ENDLIST\n"));
+    }
+
+    #[test]
+    fn test_independent_segments() {
+        let mut out = String::new();
+        playlist("").master_playlist(&mut out).unwrap();
+        assert!(out.starts_with("#EXTM3U\n#EXT-X-INDEPENDENT-SEGMENTS\n\n"));
+
+        let mut dependent = package(2, &[10000; 3], &[10000; 3]);
+        dependent.variants[1].segments[2].keyframe = Some(false);
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![package(1, &[10000; 3], &[10000; 3]), dependent],
+            test_config(""),
+        );
+        let mut out = String::new();
+        playlist.master_playlist(&mut out).unwrap();
+        assert!(out.starts_with("#EXTM3U\n\n#EXT-X-STREAM-INF"));
+    }
+
+    #[test]
+    fn test_loop_duration() {
+        let packages = || {
+            vec![
+                package(1, &[10000; 3], &[10000; 3]),
+                package(2, &[10000, 2500], &[10000, 2500]),
+            ]
+        };
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), test_config(""));
+        assert_eq!(playlist.loop_duration_seconds(), 42.5);
+        assert_eq!(playlist.loop_period(), SignedDuration::from_millis(42500));
+
+        let fast =
+            Playlist::with_config(Timestamp::UNIX_EPOCH, packages(), test_config("speed = 5"));
+        assert_eq!(fast.loop_duration_seconds(), 42.5);
+        assert_eq!(fast.loop_period(), SignedDuration::from_millis(8500));
+    }
+
+    #[test]
+    fn test_at_before_start() {
+        let start = Timestamp::from_second(1_000_000).unwrap();
+        let playlist = Playlist::with_config(
+            start,
+            vec![package(1, &[10000; 3], &[10000; 3])],
+            test_config(""),
+        );
+
+        let before = playlist.at(start - SignedDuration::from_hours(1), &Takeovers::default());
+        let at_start = playlist.at(start, &Takeovers::default());
+        assert_eq!(before.loop_index, 0);
+        assert_eq!(before.source_index, at_start.source_index);
+        assert_eq!(before.offset_in_source, Duration::zero());
+        assert_eq!(
+            playlist.locate(start - SignedDuration::from_secs(5), &Takeovers::default()),
+            playlist.locate(start, &Takeovers::default())
+        );
+
+        assert!(playlist.now() >= Timestamp::now() - SignedDuration::from_secs(1));
+    }
+
+    #[test]
+    fn test_master_playlist_rung_subset() {
+        let playlist = playlist("");
+        let filter = RungFilter {
+            indices: Some(vec![1, 2]),
+            max_bitrate: None,
+        };
+        let mut out = String::new();
+        playlist.master_playlist_for(&mut out, &filter).unwrap();
+        assert_eq!(
+            out,
+            "\
+#EXTM3U
+#EXT-X-INDEPENDENT-SEGMENTS
+
+#EXT-X-STREAM-INF:BANDWIDTH=1500000,RESOLUTION=1280x720,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+http://localhost/hls/variant1.m3u8
+
+#EXT-X-STREAM-INF:BANDWIDTH=400000,RESOLUTION=960x540,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"
+http://localhost/hls/variant2.m3u8
+
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"en\",NAME=\"aac_192\",CHANNELS=\"2\",AUTOSELECT=YES,DEFAULT=YES,URI=\"http://localhost/hls/variant3.m3u8\"
+
+"
+        );
+
+        let filter = RungFilter {
+            indices: None,
+            max_bitrate: Some(1500000),
+        };
+        let master = playlist.master_for(&filter);
+        let bandwidths = master
+            .variants
+            .iter()
+            .map(|variant| variant.bandwidth)
+            .collect::<Vec<_>>();
+        assert_eq!(bandwidths, [1500000, 400000]);
+        assert_eq!(master.renditions.len(), 1);
+    }
+
+    #[test]
+    fn test_image_stream() {
+        let layout = TileLayout {
+            columns: 2,
+            rows: 2,
+            ..TileLayout::new(5)
+        };
+        let with_thumbnails = |vid, layout| {
+            let mut package = package(vid, &[10000; 3], &[10000; 3]);
+            package.thumbnails = Some(Thumbnails {
+                layout,
+                bandwidth: 20000,
+                sheets: (0..2)
+                    .map(|i| RemoteResource(format!("sheet{i}.jpg")))
+                    .collect(),
+                vtt: RemoteResource("thumbs.vtt".to_string()),
+            });
+            package
+        };
+
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![with_thumbnails(1, layout), with_thumbnails(2, layout)],
+            test_config(""),
+        );
+        let mut master = String::new();
+        playlist.master_playlist(&mut master).unwrap();
+        assert!(master.contains("#EXT-X-IMAGE-STREAM-INF:BANDWIDTH=20000,RESOLUTION=160x90,CODECS=\"jpeg\",URI=\"http://localhost/hls/images.m3u8\"\n"));
+
+        let images = playlist.images.as_ref().unwrap();
+        let mut out = String::new();
+        images
+            .render_variant_playlist(
+                &mut out,
+                &playlist,
+                &Takeovers::default(),
+                Timestamp::UNIX_EPOCH,
+            )
+            .unwrap();
+        assert!(out.contains("#EXT-X-TARGETDURATION:20\n"));
+        assert!(out.contains("#EXT-X-IMAGES-ONLY\n"));
+        // The second sheet is cut short where the 30s item ends.
+        assert!(out.contains(
+            "#EXTINF:20.000000,\n#EXT-X-TILES:RESOLUTION=160x90,LAYOUT=2x2,DURATION=5.000\nhttp://localhost/media/1/sheet0.jpg\n\
+             #EXTINF:10.000000,\n#EXT-X-TILES:RESOLUTION=160x90,LAYOUT=2x2,DURATION=5.000\nhttp://localhost/media/1/sheet1.jpg\n\
+             #EXT-X-DISCONTINUITY\n"
+        ));
+
+        // Mismatched layouts can't share one image playlist.
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                with_thumbnails(1, layout),
+                with_thumbnails(2, TileLayout::new(5)),
+            ],
+            test_config(""),
+        );
+        assert!(playlist.images.is_none());
+        assert!(playlist.master().images.is_empty());
+    }
+
+    #[test]
+    fn test_delta_playlist() {
+        let disabled = playlist("");
+        let playlist = playlist("delta_playlists = true");
+        let mut full = String::new();
+        let mut delta = String::new();
+        playlist.streams[0]
+            .render_variant_playlist(
+                &mut full,
+                &playlist,
+                &Takeovers::default(),
+                Timestamp::UNIX_EPOCH,
+            )
+            .unwrap();
+        playlist.streams[0]
+            .render_delta_playlist(
+                &mut delta,
+                &playlist,
+                &Takeovers::default(),
+                Timestamp::UNIX_EPOCH,
+            )
+            .unwrap();
+
+        for out in [&full, &delta] {
+            assert!(out.contains("#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=60.0\n"));
+            assert!(out.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        }
+        assert!(full.contains("#EXT-X-VERSION:7\n"));
+        assert!(delta.contains("#EXT-X-VERSION:9\n"));
+
+        // 16 segments of 10s: everything ending 60s or more before the end
+        // goes.
+        assert!(delta.contains("#EXT-X-SKIP:SKIPPED-SEGMENTS=10\n"));
+        assert_eq!(full.matches("#EXTINF").count(), 16);
+        assert_eq!(delta.matches("#EXTINF").count(), 6);
+        let tail = |out: &str, segment: usize| {
+            let (offset, _) = out.match_indices("#EXTINF").nth(segment).unwrap();
+            out[offset..].to_string()
+        };
+        assert_eq!(tail(&delta, 0), tail(&full, 10));
+
+        // Without the flag a delta request gets the full playlist.
+        let mut fallback = String::new();
+        disabled.streams[0]
+            .render_delta_playlist(
+                &mut fallback,
+                &disabled,
+                &Takeovers::default(),
+                Timestamp::UNIX_EPOCH,
+            )
+            .unwrap();
+        assert!(!fallback.contains("#EXT-X-SKIP"));
+        assert!(!fallback.contains("#EXT-X-SERVER-CONTROL"));
+    }
+
+    const PLAYER_PROFILES: &str = r#"
+        [player_profiles.legacy]
+        version = 4
+        map = false
+
+        [player_profiles.modern]
+        version = 7
+        program_date_time = true
+    "#;
+
+    #[test]
+    fn test_player_profiles() {
+        let legacy = render(
+            &Playlist::with_config(
+                Timestamp::UNIX_EPOCH,
+                vec![ts_package(1, &[10000; 3], &[10000; 3])],
+                test_config(&format!("player_profile = \"legacy\"\n{PLAYER_PROFILES}")),
+            ),
+            0,
+        );
+        assert!(legacy.contains("#EXT-X-VERSION:4\n"));
+        assert!(!legacy.contains("#EXT-X-MAP"));
+        assert!(!legacy.contains("#EXT-X-PROGRAM-DATE-TIME"));
+        assert!(legacy.contains("\nhttp://localhost/media/1/s5000000_0.ts\n"));
+
+        let modern = render(
+            &playlist(&format!("player_profile = \"modern\"\n{PLAYER_PROFILES}")),
+            0,
+        );
+        assert!(modern.contains("#EXT-X-VERSION:7\n"));
+        assert!(modern.contains("#EXT-X-MAP:URI="));
+        // Every item in the loop is its own discontinuity, and restates the
+        // date: the 30s package starts over at 30s and 60s.
+        assert!(modern.contains("#EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:00.000Z\n"));
+        assert!(
+            modern.contains(
+                "#EXT-X-DISCONTINUITY\n#EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:00:30.000Z\n"
+            )
+        );
+        assert!(
+            modern.contains(
+                "#EXT-X-DISCONTINUITY\n#EXT-X-PROGRAM-DATE-TIME:1970-01-01T00:01:00.000Z\n"
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "package 1 needs EXT-X-MAP")]
+    fn test_player_profile_without_map() {
+        playlist(&format!("player_profile = \"legacy\"\n{PLAYER_PROFILES}"));
+    }
+
+    #[test]
+    #[should_panic(expected = "EXT-X-MAP needs EXT-X-VERSION 6")]
+    fn test_player_profile_version_mismatch() {
+        playlist(
+            r#"
+            player_profile = "old"
+            player_profiles.old.version = 5
+            "#,
+        );
+    }
+
+    #[test]
+    fn test_segment_bitrates() {
+        let mut package = package(1, &[10000, 10000, 5000], &[10000; 3]);
+        let sizes = [6_250_000, 5_000_000, 2_000_000];
+        for (segment, size) in package.variants[0].segments.iter_mut().zip(sizes) {
+            segment.size = Some(size);
+        }
+        let render_with = |config| {
+            let playlist = Playlist::with_config(
+                Timestamp::UNIX_EPOCH,
+                vec![package.clone()],
+                test_config(config),
+            );
+            render(&playlist, 0)
+        };
+
+        let out = render_with("segment_bitrates = true");
+        // size * 8 / duration / 1000
+        assert!(out.contains(
+            "#EXT-X-BITRATE:5000\n#EXTINF:10.000000,\nhttp://localhost/media/1/s5000000_0.mp4\n"
+        ));
+        assert!(out.contains(
+            "#EXT-X-BITRATE:4000\n#EXTINF:10.000000,\nhttp://localhost/media/1/s5000000_1.mp4\n"
+        ));
+        assert!(out.contains(
+            "#EXT-X-BITRATE:3200\n#EXTINF:5.000000,\nhttp://localhost/media/1/s5000000_2.mp4\n"
+        ));
+
+        assert!(!render_with("").contains("#EXT-X-BITRATE"));
+    }
+
+    #[test]
+    fn test_ts_segments() {
+        let package = ts_package(1, &[10000; 3], &[10000; 3]);
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, vec![package], test_config(""));
+
+        let out = render(&playlist, 0);
+        assert!(!out.contains("#EXT-X-MAP"));
+        assert!(out.contains("#EXTINF:10.000000,\nhttp://localhost/media/1/s5000000_0.ts\n"));
+    }
+
+    #[test]
+    fn test_segment_uri_prefix() {
+        let absolute = render(&playlist(""), 0);
+        assert!(absolute.contains("#EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n"));
+        assert!(absolute.contains("\nhttp://localhost/media/1/s5000000_0.mp4\n"));
+
+        let relative = render(&playlist("relative_segment_uris = true"), 0);
+        assert!(relative.contains("#EXT-X-MAP:URI=\"/media/1/init5000000.mp4\"\n"));
+        assert!(relative.contains("\n/media/1/s5000000_0.mp4\n"));
+        assert!(!relative.contains("http://localhost/media"));
+    }
+
+    #[test]
+    fn test_byte_range_playlist() {
+        let mut package = package(1, &[10000; 2], &[10000; 2]);
+        for variant in &mut package.variants {
+            for (i, segment) in variant.segments.iter_mut().enumerate() {
+                segment.src = RemoteResource(format!("media{}.mp4", variant.bitrate));
+                segment.range = Some(ByteRange {
+                    offset: i as u64 * 1000,
+                    length: 1000,
+                });
+            }
+        }
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, vec![package], test_config(""));
+
+        let out = render(&playlist, 0);
+        assert!(out.contains(
+            "#EXTINF:10.000000,\n#EXT-X-BYTERANGE:1000@0\nhttp://localhost/media/1/media5000000.mp4\n"
+        ));
+        assert!(out.contains(
+            "#EXTINF:10.000000,\n#EXT-X-BYTERANGE:1000@1000\nhttp://localhost/media/1/media5000000.mp4\n"
+        ));
+    }
+
+    #[test]
+    fn test_shared_init_map() {
+        let standalone = render(&playlist(""), 0);
+        assert!(
+            standalone.contains("#EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n")
+        );
+
+        let mut package = package(1, &[10000; 2], &[10000; 2]);
+        for variant in &mut package.variants {
+            let media = RemoteResource(format!("media{}.mp4", variant.bitrate));
+            variant.init_src = Some(media.clone());
+            variant.init_range = Some(ByteRange {
+                offset: 0,
+                length: 500,
+            });
+            for (i, segment) in variant.segments.iter_mut().enumerate() {
+                segment.src = media.clone();
+                segment.range = Some(ByteRange {
+                    offset: 500 + i as u64 * 1000,
+                    length: 1000,
+                });
+            }
+        }
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, vec![package], test_config(""));
+
+        let out = render(&playlist, 0);
+        assert!(out.contains(
+            "#EXT-X-MAP:URI=\"http://localhost/media/1/media5000000.mp4\",BYTERANGE=\"500@0\"\n"
+        ));
+        assert!(out.contains("#EXT-X-BYTERANGE:1000@500\n"));
+    }
+
+    #[test]
+    fn test_rebased_sequences_never_regress() {
+        // The previous deploy had been running long enough to reach these.
+        let persisted = vec![
+            Sequence {
+                media: 1000,
+                discontinuity: 300,
+            };
+            4
+        ];
+
+        let mut restarted = playlist("");
+        let now = Timestamp::UNIX_EPOCH;
+        assert!(restarted.sequences(now, &Takeovers::default())[0].media < 1000);
+
+        restarted.rebase_sequences(&persisted, now);
+        for sequence in restarted.sequences(now, &Takeovers::default()) {
+            assert_eq!(sequence, persisted[0]);
+        }
+
+        let out = render(&restarted, 0);
+        assert!(out.contains("#EXT-X-MEDIA-SEQUENCE:1000\n"));
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:300\n"));
+
+        let later = now + jiff::SignedDuration::from_secs(35);
+        let advanced = restarted.sequences(later, &Takeovers::default());
+        assert_eq!(advanced[0].media, 1000 + 3);
+        assert_eq!(advanced[0].discontinuity, 300 + 1);
+    }
+
+    #[test]
+    fn test_duplicate_vids() {
+        let stale = package(1, &[10000; 3], &[10000; 3]);
+        let newer = Package {
+            packaged_at: Timestamp::UNIX_EPOCH + SignedDuration::from_hours(1),
+            ..package(1, &[10000; 2], &[10000; 2])
+        };
+        let packages = vec![stale, package(2, &[10000; 2], &[10000; 2]), newer];
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages, test_config(""));
+
+        let items = |playlist: &Playlist| {
+            playlist
+                .schedule()
+                .items
+                .iter()
+                .map(|item| (item.vid, item.start.to_seconds(playlist.step)))
+                .collect::<Vec<_>>()
+        };
+        // The newer package 1 plays both times it's listed.
+        assert_eq!(items(&playlist), [(1, 0.0), (2, 20.0), (1, 40.0)]);
+        assert_eq!(playlist.loop_duration_seconds(), 60.0);
+
+        // Listing the same package again repeats it.
+        let packages = vec![
+            package(1, &[10000; 3], &[10000; 3]),
+            package(2, &[10000; 2], &[10000; 2]),
+            package(1, &[10000; 3], &[10000; 3]),
+        ];
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages, test_config(""));
+        assert_eq!(items(&playlist), [(1, 0.0), (2, 30.0), (1, 50.0)]);
+    }
+
+    #[test]
+    fn test_package_order() {
+        let packages = || {
+            [3, 1, 2].map(|vid| Package {
+                packaged_at: Timestamp::UNIX_EPOCH + SignedDuration::from_hours(4 - vid as i64),
+                ..package(vid, &[10000; 2], &[10000; 2])
+            })
+        };
+        let items = |packages: Vec<Package>, order| {
+            let config = test_config(&format!("package_order = {order:?}"));
+            let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages, config);
+            playlist
+                .schedule()
+                .items
+                .iter()
+                .map(|item| (item.vid, item.start.to_seconds(playlist.step)))
+                .collect::<Vec<_>>()
+        };
+
+        let listed = packages().to_vec();
+        let mut shuffled = packages().to_vec();
+        shuffled.rotate_left(1);
+        assert_ne!(
+            items(listed.clone(), "listed"),
+            items(shuffled.clone(), "listed")
+        );
+        for order in ["vid", "packaged_at"] {
+            assert_eq!(items(listed.clone(), order), items(shuffled.clone(), order));
+        }
+
+        assert_eq!(
+            items(listed, "packaged_at"),
+            [(3, 0.0), (2, 20.0), (1, 40.0)]
+        );
+    }
+
+    #[test]
+    fn test_repeated_package() {
+        let mut sponsor = package(1, &[10000; 3], &[10000; 3]);
+        sponsor.repeats = Some(2);
+        let packages = vec![sponsor, package(2, &[10000; 2], &[10000; 2])];
+        let playlist = Playlist::with_config(Timestamp::UNIX_EPOCH, packages, test_config(""));
+
+        let schedule = playlist.schedule();
+        let items = schedule
+            .items
+            .iter()
+            .map(|item| (item.vid, item.start.to_seconds(playlist.step)))
+            .collect::<Vec<_>>();
+        assert_eq!(items, [(1, 0.0), (1, 30.0), (2, 60.0)]);
+        assert_eq!(playlist.loop_duration_seconds(), 80.0);
+        assert_eq!(playlist.sources.len(), 3);
+
+        // Partway into package 2, both plays of package 1 count towards
+        // the sequence numbers, and the second play is marked off from the
+        // first and maps its init again, as where the loop wraps.
+        let mut out = String::new();
+        let at = Timestamp::UNIX_EPOCH + SignedDuration::from_secs(75);
+        playlist.streams[0]
+            .render_variant_playlist(&mut out, &playlist, &Takeovers::default(), at)
+            .unwrap();
+        assert!(out.contains("#EXT-X-MEDIA-SEQUENCE:7\n"));
+        assert!(out.contains("#EXT-X-DISCONTINUITY-SEQUENCE:2\n"));
+        assert!(out.contains(
+            "http://localhost/media/1/s5000000_2.mp4\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"http://localhost/media/1/init5000000.mp4\"\n\
+             #EXTINF:10.000000,\n\
+             http://localhost/media/1/s5000000_0.mp4\n"
+        ));
+
+        let json = serde_json::to_string(&playlist.schedule()).unwrap();
+        assert_eq!(json.matches("\"vid\":1").count(), 2);
+    }
+
+    #[test]
+    fn test_loop_crossfade() {
+        let packages = || {
+            vec![
+                package(1, &[4000, 10000, 10000], &[4000, 10000, 10000]),
+                package(2, &[10000, 10000, 4000], &[10000, 10000, 4000]),
+                package(9, &[4000], &[4000]),
+            ]
+        };
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            packages(),
+            test_config("loop_crossfade = 9"),
+        );
+        let schedule = playlist.schedule();
+        let items = schedule
+            .items
+            .iter()
+            .map(|item| (item.vid, item.start.to_seconds(playlist.step)))
+            .collect::<Vec<_>>();
+        // The transition stands in for the 4s cut from either end.
+        assert_eq!(items, [(1, 0.0), (2, 20.0), (9, 40.0)]);
+        assert_eq!(playlist.loop_duration_seconds(), 44.0);
+
+        // Lands mid-segment at the start of the loop.
+        let mut misaligned = packages();
+        misaligned[2] = package(9, &[3000], &[3000]);
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            misaligned,
+            test_config("loop_crossfade = 9"),
+        );
+        let vids = playlist
+            .schedule()
+            .items
+            .iter()
+            .map(|item| item.vid)
+            .collect::<Vec<_>>();
+        assert_eq!(vids, [1, 2]);
+        assert_eq!(playlist.loop_duration_seconds(), 48.0);
+
+        // Lands mid-segment at the end only; the start isn't cut either.
+        let mut misaligned = packages();
+        misaligned[1] = package(2, &[10000, 10000, 3000], &[10000, 10000, 3000]);
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            misaligned,
+            test_config("loop_crossfade = 9"),
+        );
+        assert_eq!(playlist.schedule().items.len(), 2);
+        assert_eq!(playlist.loop_duration_seconds(), 47.0);
+    }
+
+    #[test]
+    fn test_blob_index() {
+        let dir = std::env::temp_dir().join("frameserve-blob-index");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("1")).unwrap();
+        fs::write(dir.join("1/s5000000_0.mp4"), "").unwrap();
+        let config = test_config(&format!(
+            "store = {{ kind = \"local\", dir = {:?} }}",
+            dir.to_str().unwrap()
+        ));
+        let playlist = Playlist::with_config(
+            Timestamp::UNIX_EPOCH,
+            vec![
+                package(1, &[10000; 2], &[10000; 2]),
+                package(2, &[10000; 2], &[10000; 2]),
+            ],
+            config,
+        );
+        let blob = |name: &str| RemoteResource(name.to_string());
+
+        assert!(playlist.has_blob(1, &blob("s5000000_0.mp4")));
+        assert!(!playlist.has_blob(1, &blob("s5000000_1.mp4")));
+        // The same name under another package isn't the same file.
+        assert!(!playlist.has_blob(2, &blob("s5000000_0.mp4")));
+
+        // Written since the playlist loaded.
+        fs::write(dir.join("1/s5000000_1.mp4"), "").unwrap();
+        assert!(!playlist.has_blob(1, &blob("s5000000_1.mp4")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_allowlist() {
+        let dir = std::env::temp_dir().join("frameserve-allowlist-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for vid in [1, 2, 3] {
+            let json = serde_json::to_string(&package(vid, &[10000; 3], &[10000; 3])).unwrap();
+            fs::write(dir.join(format!("{vid}.json")), json).unwrap();
+        }
+        fs::write(dir.join("playlist.txt"), "1 One\n2 Two\n3 Three\n").unwrap();
+
+        let playlist = Playlist::load_with_config(
+            Timestamp::UNIX_EPOCH,
+            dir.to_str().unwrap(),
+            test_config(r#"packages.allow = ["1", "3"]"#),
+        );
+        let vids = playlist
+            .schedule()
+            .items
+            .iter()
+            .map(|item| item.vid)
+            .collect::<Vec<_>>();
+        assert_eq!(vids, [1, 3]);
+
+        let playlist = Playlist::load_with_config(
+            Timestamp::UNIX_EPOCH,
+            dir.to_str().unwrap(),
+            test_config(r#"packages.deny = ["2*"]"#),
+        );
+        let vids = playlist
+            .schedule()
+            .items
+            .iter()
+            .map(|item| item.vid)
+            .collect::<Vec<_>>();
+        assert_eq!(vids, [1, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
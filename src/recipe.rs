@@ -1,16 +1,68 @@
-use crate::inspect::{Codec, FieldOrder, Info, Profile, VideoStreamInfo};
+use crate::config::{Tools, get_tools};
+use crate::inspect::{AudioStreamInfo, Codec, FieldOrder, Info, Profile, VideoStreamInfo};
 use clap::ValueEnum;
 use num::{ToPrimitive, rational::Ratio};
 use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
 
-const GOP_DURATION: f64 = 10.0;
+/// Seconds per segment, and so per GOP, unless an output says otherwise.
+const SEGMENT_DURATION: u32 = 10;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VideoSpec {
     pub width: u16,
     pub height: u16,
+    /// The target for a two-pass encode, or the cap with `crf`; either way
+    /// the most the rung should need, as `BANDWIDTH` claims.
     pub bit_rate: u32,
+    /// Encode in one pass at this x264 CRF, capped at `bit_rate`, rather
+    /// than in two passes to `bit_rate`: easy content then takes fewer bits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u8>,
     pub profile: Profile,
+    #[serde(default)]
+    pub pix_fmt: PixelFormat,
+    #[serde(default)]
+    pub fit: Fit,
+    /// Seconds between keyframes, when shorter than the segment length it
+    /// defaults to. Must divide the segment length, so segments still start
+    /// on keyframes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gop_seconds: Option<u32>,
+}
+
+/// How a source of another aspect ratio is fitted to a rung.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale down to within the rung, keeping the aspect ratio, so the
+    /// output may be narrower or shorter than the rung.
+    #[default]
+    Scale,
+    /// Scale, then letterbox or pillarbox to exactly the rung's size, as
+    /// some players and ad systems require.
+    Pad,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    #[default]
+    Yuv420p,
+    Yuv420p10le,
+}
+
+impl PixelFormat {
+    pub fn flag(self) -> &'static str {
+        match self {
+            PixelFormat::Yuv420p => "yuv420p",
+            PixelFormat::Yuv420p10le => "yuv420p10le",
+        }
+    }
+
+    pub fn is_10bit(self) -> bool {
+        self == PixelFormat::Yuv420p10le
+    }
 }
 
 impl VideoSpec {
@@ -18,16 +70,23 @@ impl VideoSpec {
         Output {
             dir: format!("{}/{}", path.into(), self.dir_name()),
             spec: self,
+            segment_type: SegmentType::default(),
+            segment_duration: SEGMENT_DURATION,
         }
     }
 
     fn calculate_resize(&self, info: &VideoStreamInfo) -> Option<Resize> {
-        if info.width <= self.width && info.height <= self.height {
+        let (width, height) = info.display_size();
+        if self.fit == Fit::Pad {
+            return (width != self.width || height != self.height)
+                .then_some(Resize::Pad(self.width, self.height));
+        }
+        if width <= self.width && height <= self.height {
             return None;
         }
 
-        let width_ratio = self.width as f64 / info.width as f64;
-        let height_ratio = self.height as f64 / info.height as f64;
+        let width_ratio = self.width as f64 / width as f64;
+        let height_ratio = self.height as f64 / height as f64;
 
         if width_ratio < height_ratio {
             Some(Resize::Width(self.width))
@@ -36,39 +95,72 @@ impl VideoSpec {
         }
     }
 
+    fn calculate_format(&self, info: &VideoStreamInfo) -> Option<PixelFormat> {
+        if info.pix_fmt == self.pix_fmt.flag() {
+            None
+        } else {
+            Some(self.pix_fmt)
+        }
+    }
+
+    /// x264 only encodes 10-bit output under the High 10 profile.
+    fn encoder_profile(&self) -> Profile {
+        if self.pix_fmt.is_10bit() {
+            self.profile.max(Profile::High10)
+        } else {
+            self.profile
+        }
+    }
+
     fn dir_name(&self) -> String {
         let kbps = self.bit_rate / 1000;
         format!(
             "{}x{}_{kbps}k_{}",
             self.width,
             self.height,
-            self.profile.flag()
+            self.encoder_profile().flag()
         )
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Decision {
     Copy,
-    Transcode(&'static str),
+    /// Why, e.g. `source is hevc`.
+    Transcode(String),
 }
 
 impl VideoStreamInfo {
     pub fn resolve(&self, spec: &VideoSpec) -> Decision {
-        match self.codec {
-            Codec::H264 { profile } if profile <= spec.profile => {}
-            Codec::H264 { .. } => return Decision::Transcode("profile"),
-            _ => return Decision::Transcode("codec"),
+        let transcode = |reason: &str| Decision::Transcode(reason.to_string());
+        match &self.codec {
+            Codec::H264 { profile } if *profile <= spec.encoder_profile() => {}
+            Codec::H264 { .. } => return transcode("profile"),
+            Codec::Other(name) => return transcode(&format!("source is {name}")),
         }
 
-        if self.width > spec.width || self.height > spec.height {
-            return Decision::Transcode("size");
+        // A copy would keep the display matrix, which not every player
+        // honours.
+        if self.rotation() != 0 {
+            return transcode("rotation");
         }
 
-        if self.bit_rate > spec.bit_rate {
-            Decision::Transcode("bitrate")
-        } else {
-            Decision::Copy
+        let fits = match spec.fit {
+            Fit::Scale => self.width <= spec.width && self.height <= spec.height,
+            Fit::Pad => self.width == spec.width && self.height == spec.height,
+        };
+        if !fits {
+            return transcode("size");
+        }
+
+        if self.pix_fmt != spec.pix_fmt.flag() {
+            return transcode("pixel format");
+        }
+
+        // An unknown bitrate might be anything, so it can't be trusted.
+        match self.bit_rate {
+            Some(bit_rate) if bit_rate <= spec.bit_rate => Decision::Copy,
+            _ => transcode("bitrate"),
         }
     }
 }
@@ -115,12 +207,33 @@ impl CmdBuilder {
     }
 
     pub fn execute(&self) {
+        self.execute_with(get_tools());
+    }
+
+    pub fn execute_with(&self, tools: &Tools) {
+        if let Err(err) = self.try_execute_with(tools) {
+            panic!("{err}");
+        }
+    }
+
+    /// Like [`Self::execute`], but a failed run is returned rather than
+    /// panicking, for callers that carry on with other work.
+    pub fn try_execute(&self) -> Result<(), CmdError> {
+        self.try_execute_with(get_tools())
+    }
+
+    pub fn try_execute_with(&self, tools: &Tools) -> Result<(), CmdError> {
         self.print();
-        let p = std::process::Command::new("ffmpeg")
+        let status = tools
+            .ffmpeg()
             .args(&self.args)
             .status()
-            .unwrap();
-        assert!(p.success());
+            .map_err(|err| CmdError::Spawn(tools.ffmpeg.clone(), err))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(CmdError::Failed(status))
+        }
     }
 
     pub fn print(&self) {
@@ -129,6 +242,24 @@ impl CmdBuilder {
     }
 }
 
+#[derive(Debug)]
+pub enum CmdError {
+    /// ffmpeg, by its configured path, couldn't be started.
+    Spawn(String, std::io::Error),
+    Failed(std::process::ExitStatus),
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CmdError::Spawn(ffmpeg, err) => write!(f, "can't run {ffmpeg}: {err}"),
+            CmdError::Failed(status) => write!(f, "ffmpeg failed with {status}"),
+        }
+    }
+}
+
+impl std::error::Error for CmdError {}
+
 impl std::fmt::Display for CmdBuilder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for arg in &self.args {
@@ -138,84 +269,573 @@ impl std::fmt::Display for CmdBuilder {
     }
 }
 
+/// The source of an encode: either a single file, or several files joined
+/// end-to-end into one logical video (e.g. a multi-part episode).
+pub enum Input {
+    File(String),
+    Concat { list: String },
+}
+
+impl Input {
+    pub fn new(files: &[String], work_dir: &str) -> Self {
+        if let [file] = files {
+            return Input::File(file.clone());
+        }
+
+        fs::create_dir_all(work_dir).unwrap();
+        let list = format!("{work_dir}/concat.txt");
+        let files = files
+            .iter()
+            .map(|file| {
+                fs::canonicalize(file)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        fs::write(&list, concat_list(&files)).unwrap();
+        Input::Concat { list }
+    }
+
+    fn write(&self, cmd: &mut CmdBuilder) {
+        match self {
+            Input::File(file) => cmd.set("-i", file),
+            Input::Concat { list } => {
+                // The concat demuxer keeps timestamps continuous across the
+                // joins, so the packaged segment timeline stays contiguous.
+                cmd.set("-f", "concat");
+                cmd.set("-safe", "0");
+                cmd.set("-i", list);
+            }
+        }
+    }
+}
+
+fn concat_list(files: &[String]) -> String {
+    files
+        .iter()
+        .map(|file| format!("file '{}'\n", file.replace('\'', "'\\''")))
+        .collect()
+}
+
+/// Named x264 speed/quality trade-offs.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum QualityProfile {
+    Fast,
+    #[default]
+    Balanced,
+    Archive,
+}
+
+/// Content type hint passed to x264 as `-tune`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Tune {
+    #[default]
+    Film,
+    Animation,
+    Grain,
+    #[value(name = "stillimage")]
+    StillImage,
+}
+
+impl Tune {
+    pub fn flag(self) -> &'static str {
+        match self {
+            Tune::Film => "film",
+            Tune::Animation => "animation",
+            Tune::Grain => "grain",
+            Tune::StillImage => "stillimage",
+        }
+    }
+}
+
+/// The HLS segment container. MPEG-TS is for legacy devices without fMP4
+/// support; its segments are self-contained, so there is no init segment.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum SegmentType {
+    #[default]
+    Fmp4,
+    #[value(name = "ts")]
+    MpegTs,
+}
+
+impl SegmentType {
+    pub fn flag(self) -> &'static str {
+        match self {
+            SegmentType::Fmp4 => "fmp4",
+            SegmentType::MpegTs => "mpegts",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SegmentType::Fmp4 => "mp4",
+            SegmentType::MpegTs => "ts",
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Quality {
+    pub profile: QualityProfile,
+    pub tune: Tune,
+}
+
+impl Quality {
+    fn preset(self) -> &'static str {
+        match self.profile {
+            QualityProfile::Fast => "veryfast",
+            QualityProfile::Balanced => "slow",
+            QualityProfile::Archive => "veryslow",
+        }
+    }
+
+    fn write(self, cmd: &mut CmdBuilder) {
+        cmd.set("-preset", self.preset());
+        cmd.set("-tune", self.tune.flag());
+        if self.profile == QualityProfile::Archive {
+            cmd.x264_opt("aq-mode", "3");
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, ValueEnum)]
 pub enum Pass {
     First,
     Second,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeError {
+    NoVideoStream,
+    /// A rung's keyframes wouldn't fall on every segment boundary.
+    GopMisaligned {
+        width: u16,
+        height: u16,
+        gop_seconds: u32,
+        segment_duration: u32,
+    },
+}
+
+impl fmt::Display for TranscodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranscodeError::NoVideoStream => {
+                write!(
+                    f,
+                    "source has no video stream; audio-only files can't be encoded"
+                )
+            }
+            TranscodeError::GopMisaligned {
+                width,
+                height,
+                gop_seconds,
+                segment_duration,
+            } => write!(
+                f,
+                "the {width}x{height} rung's {gop_seconds}s GOP doesn't divide its {segment_duration}s segments"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TranscodeError {}
+
+/// Builds the ffmpeg invocation for one pass. A source without audio gets
+/// only the video renditions; a source without video is rejected. Skip
+/// [`Pass::First`] when no output [needs it](Output::needs_first_pass).
 pub fn transcode_video(
-    input: &str,
+    input: &Input,
     info: &Info,
     pass: Pass,
+    quality: Quality,
     outputs: &[Output],
-    audio_dir: &str,
-) -> CmdBuilder {
-    let v = info.video_stream();
-    let a = info.audio_stream();
+    audio_outputs: &[AudioOutput],
+) -> Result<CmdBuilder, TranscodeError> {
+    for output in outputs {
+        output.check_gop()?;
+    }
+    let v = info
+        .find_video_stream()
+        .ok_or(TranscodeError::NoVideoStream)?;
 
     let mut cmd = CmdBuilder::new();
 
-    cmd.set("-i", input);
+    // ffmpeg's default, but the resize decisions depend on it: frames are
+    // turned upright before any filter sees them, and the display matrix
+    // is dropped from the output.
+    if v.rotation() != 0 {
+        cmd.arg("-autorotate");
+    }
+    input.write(&mut cmd);
     cmd.set("-map_metadata", "-1");
 
     let mut filter_graph = FilterGraph::default();
     if v.field_order != FieldOrder::Progressive {
         filter_graph.add_global_filter("yadif=1");
     }
-    if v.pix_fmt != "yuv420p" {
-        filter_graph.add_global_filter("format=yuv420p");
+    let mapped = outputs
+        .iter()
+        .filter(|output| pass == Pass::Second || output.needs_first_pass())
+        .map(|output| MappedOutput {
+            label: filter_graph.add_output(OutputFilters {
+                resize: output.spec.calculate_resize(v),
+                format: output.spec.calculate_format(v),
+            }),
+            output,
+        })
+        .collect::<Vec<_>>();
+    filter_graph.write(&mut cmd);
+
+    for MappedOutput { output, label } in mapped {
+        output.write(&mut cmd, v, label, pass, quality);
     }
-    for output in outputs {
-        filter_graph.add_output(output.spec.calculate_resize(v));
+
+    if let (Pass::Second, Some(a)) = (pass, info.find_audio_stream()) {
+        for output in audio_outputs {
+            output.write(&mut cmd, a);
+        }
     }
-    filter_graph.write(&mut cmd);
 
-    for (i, output) in outputs.iter().enumerate() {
-        output.write(&mut cmd, v, StreamRef::new_output(i), pass);
+    Ok(cmd)
+}
+
+/// How far into the video the poster frame is taken, past any cold open.
+const POSTER_POSITION: f64 = 0.1;
+const POSTER_HEIGHT: u16 = 720;
+
+/// Builds the ffmpeg invocation that grabs one representative frame as a
+/// JPEG poster.
+pub fn extract_poster(
+    input: &Input,
+    info: &Info,
+    out_file: &str,
+) -> Result<CmdBuilder, TranscodeError> {
+    let v = info
+        .find_video_stream()
+        .ok_or(TranscodeError::NoVideoStream)?;
+    let seconds = v.duration_ts as f64 * v.time_base.to_f64().unwrap();
+
+    let mut cmd = CmdBuilder::new();
+    // Seeking before the input jumps to the keyframe before the position
+    // and only decodes from there, rather than from the start; the frame
+    // taken is still the one at the position.
+    cmd.set("-ss", format!("{:.3}", seconds * POSTER_POSITION));
+    input.write(&mut cmd);
+
+    let mut filters = Vec::new();
+    if v.field_order != FieldOrder::Progressive {
+        filters.push("yadif".to_string());
+    }
+    let (_, height) = v.display_size();
+    filters.push(format!("scale=-2:{}", height.min(POSTER_HEIGHT)));
+    cmd.set("-vf", filters.join(","));
+    cmd.set("-frames:v", "1");
+    cmd.set("-q:v", "3");
+    cmd.arg("-y");
+    cmd.arg(out_file);
+
+    Ok(cmd)
+}
+
+/// How scrubbing thumbnails are laid out: one tile every `interval`
+/// seconds, packed `columns` x `rows` to a sprite sheet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileLayout {
+    pub interval: u32,
+    pub width: u16,
+    pub height: u16,
+    pub columns: u8,
+    pub rows: u8,
+}
+
+impl TileLayout {
+    pub fn new(interval: u32) -> Self {
+        Self {
+            interval,
+            width: 160,
+            height: 90,
+            columns: 5,
+            rows: 5,
+        }
+    }
+
+    pub fn per_sheet(&self) -> u32 {
+        self.columns as u32 * self.rows as u32
+    }
+
+    /// Seconds of video one full sheet covers.
+    pub fn sheet_seconds(&self) -> u32 {
+        self.interval * self.per_sheet()
+    }
+
+    /// Tiles needed to cover `seconds` of video. Even a video shorter than
+    /// one interval gets one.
+    pub fn tiles(&self, seconds: f64) -> u32 {
+        ((seconds / self.interval as f64).ceil() as u32).max(1)
+    }
+}
+
+/// Builds the ffmpeg invocation that writes the thumbnail sprite sheets as
+/// `sprite001.jpg` onwards. The last sheet is padded out if the video ends
+/// part way through it.
+pub fn extract_sprites(
+    input: &Input,
+    info: &Info,
+    out_dir: &str,
+    layout: TileLayout,
+) -> Result<CmdBuilder, TranscodeError> {
+    let v = info
+        .find_video_stream()
+        .ok_or(TranscodeError::NoVideoStream)?;
+
+    let mut cmd = CmdBuilder::new();
+    input.write(&mut cmd);
+
+    let TileLayout {
+        interval,
+        width,
+        height,
+        columns,
+        rows,
+    } = layout;
+    let mut filters = Vec::new();
+    if v.field_order != FieldOrder::Progressive {
+        filters.push("yadif".to_string());
     }
+    filters.push(format!("fps=1/{interval}"));
+    filters.push(format!(
+        "scale={width}:{height}:force_original_aspect_ratio=decrease"
+    ));
+    filters.push(format!("pad={width}:{height}:(ow-iw)/2:(oh-ih)/2"));
+    filters.push(format!("tile={columns}x{rows}"));
+    cmd.set("-vf", filters.join(","));
+    cmd.set("-q:v", "4");
+    cmd.arg("-y");
+    cmd.arg(format!("{out_dir}/sprite%03d.jpg"));
+
+    fs::create_dir_all(out_dir).unwrap();
+    Ok(cmd)
+}
+
+/// Builds the ffmpeg invocation that renders a `seconds` long transition
+/// from the end of `from` into the start of `to`, for the `loop_crossfade`
+/// setting. `to` is scaled to `from`'s size and rate, and the result is
+/// near-lossless, as it is encoded again like any other source.
+pub fn render_crossfade(
+    from: &str,
+    info: &Info,
+    to: &str,
+    seconds: f64,
+    out_file: &str,
+) -> Result<CmdBuilder, TranscodeError> {
+    let v = info
+        .find_video_stream()
+        .ok_or(TranscodeError::NoVideoStream)?;
+
+    let mut cmd = CmdBuilder::new();
+    cmd.set("-sseof", format!("-{seconds:.3}"));
+    cmd.set("-i", from);
+    cmd.set("-t", format!("{seconds:.3}"));
+    cmd.set("-i", to);
+
+    let rate = v.r_frame_rate;
+    let normalize = format!(
+        "fps={}/{},scale={}:{},setsar=1,format=yuv420p",
+        rate.numer(),
+        rate.denom(),
+        v.width,
+        v.height
+    );
+    let mut graph = format!(
+        "[0:v]{normalize}[from];[1:v]{normalize}[to];\
+         [from][to]xfade=transition=fade:duration={seconds:.3}:offset=0[v]"
+    );
+    if info.find_audio_stream().is_some() {
+        graph.push_str(&format!(";[0:a][1:a]acrossfade=d={seconds:.3}[a]"));
+    }
+    cmd.set("-filter_complex", graph);
+    cmd.set("-map", "[v]");
+    if info.find_audio_stream().is_some() {
+        cmd.set("-map", "[a]");
+        cmd.set("-c:a", "flac");
+    }
+    cmd.set("-c:v", "libx264");
+    cmd.set("-crf", "10");
+    cmd.set("-t", format!("{seconds:.3}"));
+    cmd.arg("-y");
+    cmd.arg(out_file);
+
+    Ok(cmd)
+}
+
+const AUDIO_ENCODER: &str = "aac_at";
+
+/// One audio rendition. `channels` is an upper bound: a mono source stays
+/// mono rather than being upmixed.
+pub struct AudioOutput {
+    pub dir: String,
+    pub channels: u8,
+    pub bit_rate: u32,
+    pub segment_type: SegmentType,
+    pub segment_duration: u32,
+    pub encoder: String,
+}
+
+impl AudioOutput {
+    pub fn stereo(dir: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            channels: 2,
+            bit_rate: 192_000,
+            segment_type: SegmentType::default(),
+            segment_duration: SEGMENT_DURATION,
+            encoder: AUDIO_ENCODER.to_string(),
+        }
+    }
+
+    /// A 5.1 rendition alongside the stereo one, if the source has more
+    /// than two channels to begin with.
+    pub fn surround(info: &AudioStreamInfo, dir: impl Into<String>) -> Option<Self> {
+        (info.channels > 2).then(|| Self {
+            dir: dir.into(),
+            channels: 6,
+            bit_rate: 384_000,
+            segment_type: SegmentType::default(),
+            segment_duration: SEGMENT_DURATION,
+            encoder: AUDIO_ENCODER.to_string(),
+        })
+    }
+
+    pub fn segment_type(self, segment_type: SegmentType) -> Self {
+        Self {
+            segment_type,
+            ..self
+        }
+    }
+
+    pub fn segment_duration(self, segment_duration: u32) -> Self {
+        Self {
+            segment_duration,
+            ..self
+        }
+    }
+
+    pub fn encoder(self, encoder: impl Into<String>) -> Self {
+        Self {
+            encoder: encoder.into(),
+            ..self
+        }
+    }
+
+    fn write(&self, cmd: &mut CmdBuilder, info: &AudioStreamInfo) {
+        let channels = self.channels.min(info.channels);
 
-    if pass == Pass::Second {
         cmd.set("-map", "0:a");
-        if a.channels <= 2 && a.bit_rate <= 192000 && a.codec_name == "aac" {
+        let bit_rate_fits = info.bit_rate.is_some_and(|rate| rate <= self.bit_rate);
+        if info.channels == channels && bit_rate_fits && info.codec_name == "aac" {
             cmd.set("-c:a", "copy");
         } else {
-            cmd.set("-ac", "2");
-            cmd.set("-c:a", "aac_at");
-            cmd.set("-b:a", "192k");
+            cmd.set("-ac", channels.to_string());
+            cmd.set("-c:a", &self.encoder);
+            cmd.set("-b:a", format!("{}k", self.bit_rate / 1000));
         }
-        with_hls_muxer(&mut cmd, &audio_dir);
+        with_hls_muxer(cmd, &self.dir, self.segment_type, self.segment_duration);
     }
-
-    cmd
 }
 
 pub struct Output {
     dir: String,
     spec: VideoSpec,
+    segment_type: SegmentType,
+    segment_duration: u32,
 }
 
 impl Output {
-    fn write(&self, cmd: &mut CmdBuilder, info: &VideoStreamInfo, stream: StreamRef, pass: Pass) {
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Whether [`Pass::First`] encodes this output; CRF rungs are only
+    /// encoded in the second.
+    pub fn needs_first_pass(&self) -> bool {
+        self.spec.crf.is_none()
+    }
+
+    pub fn segment_type(self, segment_type: SegmentType) -> Self {
+        Self {
+            segment_type,
+            ..self
+        }
+    }
+
+    pub fn segment_duration(self, segment_duration: u32) -> Self {
+        Self {
+            segment_duration,
+            ..self
+        }
+    }
+
+    /// Seconds between keyframes; a segment's length unless the rung
+    /// overrides it.
+    fn gop_seconds(&self) -> u32 {
+        self.spec.gop_seconds.unwrap_or(self.segment_duration)
+    }
+
+    fn check_gop(&self) -> Result<(), TranscodeError> {
+        let gop_seconds = self.gop_seconds();
+        if gop_seconds == 0 || !self.segment_duration.is_multiple_of(gop_seconds) {
+            return Err(TranscodeError::GopMisaligned {
+                width: self.spec.width,
+                height: self.spec.height,
+                gop_seconds,
+                segment_duration: self.segment_duration,
+            });
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        cmd: &mut CmdBuilder,
+        info: &VideoStreamInfo,
+        stream: StreamRef,
+        pass: Pass,
+        quality: Quality,
+    ) {
         cmd.set("-map", stream);
         cmd.set("-c:v", "libx264");
-        cmd.set("-preset", "slow");
-        cmd.set("-tune", "film");
-        cmd.set("-profile:v", self.spec.profile.flag());
-        cmd.set("-b:v", self.spec.bit_rate.to_string());
+        quality.write(cmd);
+        cmd.set("-profile:v", self.spec.encoder_profile().flag());
+        cmd.set("-pix_fmt", self.spec.pix_fmt.flag());
+        match self.spec.crf {
+            Some(crf) => cmd.set("-crf", crf.to_string()),
+            None => cmd.set("-b:v", self.spec.bit_rate.to_string()),
+        }
         cmd.set("-maxrate", self.spec.bit_rate.to_string());
         cmd.set("-bufsize", (self.spec.bit_rate * 2).to_string());
         cmd.set("-flags", "+cgop");
 
-        let gop = calculate_gop_length(info.avg_frame_rate, GOP_DURATION);
+        let gop = calculate_gop_length(info.avg_frame_rate, self.gop_seconds());
+        let min_gop = gop.floor().to_integer();
 
-        // https://superuser.com/a/1223359
-        cmd.set("-force_key_frames", format!("expr:eq(mod(n,{gop}),0)"));
-        cmd.x264_opt("rc-lookahead", gop.to_string());
-        cmd.x264_opt("keyint", (gop * 2).to_string());
-        cmd.x264_opt("min-keyint", gop.to_string());
+        // https://superuser.com/a/1223359, but on the exact GOP so a
+        // fractional one doesn't drift.
+        cmd.set(
+            "-force_key_frames",
+            format!("expr:gte(n,n_forced*{}/{})", gop.numer(), gop.denom()),
+        );
+        cmd.x264_opt("rc-lookahead", min_gop.to_string());
+        cmd.x264_opt("keyint", (gop * 2).ceil().to_integer().to_string());
+        cmd.x264_opt("min-keyint", min_gop.to_string());
         cmd.flush_x264opts();
 
+        // CRF needs no first pass, and x264 refuses one for a second.
+        if self.spec.crf.is_some() {
+            with_hls_muxer(cmd, &self.dir, self.segment_type, self.segment_duration);
+            return;
+        }
+
         cmd.set(
             "-passlogfile",
             format!("{}x{}", self.spec.width, self.spec.height),
@@ -227,16 +847,24 @@ impl Output {
             cmd.arg("/dev/null");
         } else {
             cmd.set("-pass", "2");
-            with_hls_muxer(cmd, &self.dir);
+            with_hls_muxer(cmd, &self.dir, self.segment_type, self.segment_duration);
         }
     }
 }
 
-fn with_hls_muxer(cmd: &mut CmdBuilder, out_dir: &str) {
+fn with_hls_muxer(
+    cmd: &mut CmdBuilder,
+    out_dir: &str,
+    segment_type: SegmentType,
+    segment_duration: u32,
+) {
     cmd.set("-f", "hls");
-    cmd.set("-hls_time", GOP_DURATION.to_string());
-    cmd.set("-hls_segment_filename", format!("{}/s%05d.mp4", out_dir));
-    cmd.set("-hls_segment_type", "fmp4");
+    cmd.set("-hls_time", segment_duration.to_string());
+    cmd.set(
+        "-hls_segment_filename",
+        format!("{}/s%05d.{}", out_dir, segment_type.extension()),
+    );
+    cmd.set("-hls_segment_type", segment_type.flag());
     cmd.set("-hls_list_size", "0");
 
     cmd.arg(format!("{}/stream.m3u8", out_dir));
@@ -247,13 +875,61 @@ fn with_hls_muxer(cmd: &mut CmdBuilder, out_dir: &str) {
 #[derive(Default)]
 struct FilterGraph {
     global_filters: Vec<String>,
-    outputs: Vec<Option<Resize>>,
+    outputs: Vec<GraphOutput>,
+}
+
+/// One of the graph's outputs, by the labels it's written under.
+struct GraphOutput {
+    filters: OutputFilters,
+    /// Where the split hands the stream over, when there are filters.
+    intermediate: StreamRef,
+    label: StreamRef,
+}
+
+/// A video output and the label of the graph output it's encoded from, as
+/// [`FilterGraph::add_output`] gave it.
+struct MappedOutput<'a> {
+    output: &'a Output,
+    label: StreamRef,
+}
+
+#[derive(Copy, Clone, Default)]
+struct OutputFilters {
+    resize: Option<Resize>,
+    format: Option<PixelFormat>,
+}
+
+impl OutputFilters {
+    fn chain(&self) -> Option<String> {
+        let mut filters = Vec::new();
+        if let Some(resize) = self.resize {
+            filters.push(match resize {
+                Resize::Width(w) => format!("scale={w}:-2"),
+                Resize::Height(h) => format!("scale=-2:{h}"),
+                Resize::Pad(w, h) => format!(
+                    "scale={w}:{h}:force_original_aspect_ratio=decrease,\
+                     pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1"
+                ),
+            });
+        }
+        if let Some(format) = self.format {
+            filters.push(format!("format={}", format.flag()));
+        }
+
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(","))
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
 enum Resize {
     Width(u16),
     Height(u16),
+    /// Exactly this width and height; see [`Fit::Pad`].
+    Pad(u16, u16),
 }
 
 impl FilterGraph {
@@ -261,14 +937,23 @@ impl FilterGraph {
         self.global_filters.push(filter.into());
     }
 
-    fn add_output(&mut self, filter: Option<Resize>) {
-        self.outputs.push(filter);
+    /// Returns the label to `-map` the filtered stream from.
+    fn add_output(&mut self, filters: OutputFilters) -> StreamRef {
+        let index = self.outputs.len();
+        let label = StreamRef::new_output(index);
+        self.outputs.push(GraphOutput {
+            filters,
+            intermediate: StreamRef::new_variant_intermediate(index),
+            label: label.clone(),
+        });
+        label
     }
 
     fn write(&self, cmd: &mut CmdBuilder) {
         let mut components = Vec::new();
 
-        let mut stream_head = StreamRef::new("[0:v]");
+        // `V` rather than `v`: cover art is a video stream too.
+        let mut stream_head = StreamRef::new("[0:V]");
         for (i, filter) in self.global_filters.iter().enumerate() {
             let destination = StreamRef::new_global_intermediate(i);
             components.push(format!("{}{}{}", stream_head, filter, destination));
@@ -278,12 +963,11 @@ impl FilterGraph {
         let split_destinations = self
             .outputs
             .iter()
-            .enumerate()
-            .map(|(i, resize_filter)| {
-                if resize_filter.is_some() {
-                    StreamRef::new_variant_intermediate(i).0
+            .map(|output| {
+                if output.filters.chain().is_some() {
+                    output.intermediate.0.as_str()
                 } else {
-                    StreamRef::new_output(i).0
+                    output.label.0.as_str()
                 }
             })
             .collect::<Vec<_>>();
@@ -294,15 +978,9 @@ impl FilterGraph {
             split_destinations.join("")
         ));
 
-        for (i, resize_filter) in self.outputs.iter().enumerate() {
-            if let Some(resize) = resize_filter {
-                let filter = match resize {
-                    Resize::Width(w) => format!("scale={w}:-2"),
-                    Resize::Height(h) => format!("scale=-2:{h}"),
-                };
-                let src = StreamRef::new_variant_intermediate(i);
-                let dst = StreamRef::new_output(i);
-                components.push(format!("{}{}{}", src, filter, dst));
+        for output in &self.outputs {
+            if let Some(filter) = output.filters.chain() {
+                components.push(format!("{}{}{}", output.intermediate, filter, output.label));
             }
         }
 
@@ -311,6 +989,7 @@ impl FilterGraph {
     }
 }
 
+#[derive(Clone)]
 struct StreamRef(String);
 
 impl StreamRef {
@@ -343,7 +1022,624 @@ impl std::fmt::Display for StreamRef {
     }
 }
 
-fn calculate_gop_length(ratio: Ratio<u32>, gop_duration: f64) -> u32 {
-    let gop_frames = ratio.to_f64().unwrap() * gop_duration;
-    gop_frames.round() as u32
+/// Frames per GOP, unrounded for fractional frame rates.
+fn calculate_gop_length(frame_rate: Ratio<u32>, gop_duration: u32) -> Ratio<u32> {
+    frame_rate * gop_duration
+}
+
+/// The frames the `-force_key_frames` expression selects: the first frame
+/// at or after each multiple of `gop`.
+#[cfg(test)]
+fn keyframe_positions(gop: Ratio<u32>, frames: u32) -> impl Iterator<Item = u32> {
+    (0..)
+        .map(move |k| (gop * k).ceil().to_integer())
+        .take_while(move |n| *n < frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::StreamKind;
+
+    const VIDEO_STREAM: &str = r#"{
+        "index": 0, "codec_type": "video", "codec_name": "h264", "profile": "High",
+        "width": 1920, "height": 1080, "start_pts": 0, "duration_ts": 1000,
+        "field_order": "progressive", "bit_rate": "4000000",
+        "r_frame_rate": "25/1", "avg_frame_rate": "25/1",
+        "pix_fmt": "yuv420p", "time_base": "1/12800"
+    }"#;
+
+    const AUDIO_STREAM: &str = r#"{
+        "index": 1, "codec_type": "audio", "codec_name": "aac",
+        "bit_rate": "128000", "start_pts": 0, "duration_ts": 1000,
+        "sample_rate": "48000", "channels": 2, "time_base": "1/48000"
+    }"#;
+
+    fn info(streams: &[&str]) -> Info {
+        serde_json::from_str(&format!(r#"{{"streams": [{}]}}"#, streams.join(","))).unwrap()
+    }
+
+    /// A fresh directory for a test's outputs, which encoding creates; the
+    /// test removes it when it's done.
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn outputs(dir: &str) -> Vec<Output> {
+        vec![
+            VideoSpec {
+                width: 1920,
+                height: 1080,
+                bit_rate: 5_000_000,
+                profile: Profile::High,
+                pix_fmt: PixelFormat::Yuv420p,
+                crf: None,
+                fit: Fit::Scale,
+                gop_seconds: None,
+            }
+            .out_dir(dir),
+        ]
+    }
+
+    #[test]
+    fn test_transcode_audio_only_source() {
+        let input = Input::File("in.m4a".to_string());
+        let info = info(&[AUDIO_STREAM]);
+        let result = transcode_video(
+            &input,
+            &info,
+            Pass::Second,
+            Quality::default(),
+            &outputs("out"),
+            &[],
+        );
+        assert_eq!(result.err(), Some(TranscodeError::NoVideoStream));
+    }
+
+    #[test]
+    fn test_transcode_video_only_source() {
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM]);
+        let dir = test_dir("frameserve-video-only-test");
+        let cmd = transcode_video(
+            &input,
+            &info,
+            Pass::Second,
+            Quality::default(),
+            &outputs(&dir),
+            &[],
+        )
+        .unwrap()
+        .to_string();
+        assert!(cmd.contains("-map [out0]"));
+        assert!(!cmd.contains("0:a"));
+        assert!(!cmd.contains("-c:a"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concat_input() {
+        let list = concat_list(&["/a/part1.mkv".to_string(), "/a/it's.mkv".to_string()]);
+        assert_eq!(list, "file '/a/part1.mkv'\nfile '/a/it'\\''s.mkv'\n");
+
+        let mut cmd = CmdBuilder::new();
+        Input::Concat {
+            list: "encodes/1/concat.txt".to_string(),
+        }
+        .write(&mut cmd);
+        assert_eq!(
+            cmd.to_string(),
+            "-hide_banner -f concat -safe 0 -i encodes/1/concat.txt "
+        );
+    }
+
+    fn filter_complex(graph: &FilterGraph) -> String {
+        let mut cmd = CmdBuilder::default();
+        graph.write(&mut cmd);
+        cmd.args[1].clone()
+    }
+
+    #[test]
+    fn test_format_filter_selection() {
+        let info = info(&[&VIDEO_STREAM.replace("yuv420p", "yuv420p10le")]);
+        let source = info.video_stream();
+        let spec = |pix_fmt| VideoSpec {
+            width: 1920,
+            height: 1080,
+            bit_rate: 5_000_000,
+            profile: Profile::High,
+            pix_fmt,
+            crf: None,
+            fit: Fit::Scale,
+            gop_seconds: None,
+        };
+        let ten_bit = spec(PixelFormat::Yuv420p10le);
+        let eight_bit = spec(PixelFormat::Yuv420p);
+
+        assert_eq!(ten_bit.calculate_format(source), None);
+        assert_eq!(
+            eight_bit.calculate_format(source),
+            Some(PixelFormat::Yuv420p)
+        );
+        assert_eq!(ten_bit.encoder_profile(), Profile::High10);
+        assert_eq!(
+            source.resolve(&eight_bit),
+            Decision::Transcode("pixel format".to_string())
+        );
+
+        let mut graph = FilterGraph::default();
+        for spec in [&ten_bit, &eight_bit] {
+            graph.add_output(OutputFilters {
+                resize: spec.calculate_resize(source),
+                format: spec.calculate_format(source),
+            });
+        }
+        assert_eq!(
+            filter_complex(&graph),
+            "[0:V]split=2[out0][v1];[v1]format=yuv420p[out1]"
+        );
+    }
+
+    #[test]
+    fn test_pad_to_rung() {
+        let info = info(&[&VIDEO_STREAM.replace(r#""width": 1920"#, r#""width": 1440"#)]);
+        let source = info.video_stream();
+        let spec = |fit| VideoSpec {
+            width: 1920,
+            height: 1080,
+            bit_rate: 5_000_000,
+            profile: Profile::High,
+            pix_fmt: PixelFormat::Yuv420p,
+            crf: None,
+            fit,
+            gop_seconds: None,
+        };
+
+        // A 4:3 source already fits within the 16:9 rung.
+        assert!(spec(Fit::Scale).calculate_resize(source).is_none());
+        assert_eq!(source.resolve(&spec(Fit::Scale)), Decision::Copy);
+
+        let padded = spec(Fit::Pad);
+        assert_eq!(
+            source.resolve(&padded),
+            Decision::Transcode("size".to_string())
+        );
+        let mut graph = FilterGraph::default();
+        graph.add_output(OutputFilters {
+            resize: padded.calculate_resize(source),
+            format: padded.calculate_format(source),
+        });
+        assert_eq!(
+            filter_complex(&graph),
+            "[0:V]split=1[v0];[v0]scale=1920:1080:force_original_aspect_ratio=decrease,\
+             pad=1920:1080:(ow-iw)/2:(oh-ih)/2,setsar=1[out0]"
+        );
+    }
+
+    #[test]
+    fn test_rotated_source() {
+        let rotated = VIDEO_STREAM.replace(
+            r#""time_base": "1/12800""#,
+            r#""time_base": "1/12800", "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]"#,
+        );
+        let info = info(&[&rotated]);
+        let source = info.video_stream();
+        assert_eq!(source.rotation(), 90);
+        assert_eq!(source.display_size(), (1080, 1920));
+
+        // Upright it's 1080x1920, so it has to come down to fit 1080 high.
+        let outputs = outputs("out");
+        let spec = &outputs[0].spec;
+        assert!(matches!(
+            spec.calculate_resize(source),
+            Some(Resize::Height(1080))
+        ));
+        assert_eq!(
+            source.resolve(spec),
+            Decision::Transcode("rotation".to_string())
+        );
+
+        let input = Input::File("in.mp4".to_string());
+        let cmd = transcode_video(
+            &input,
+            &info,
+            Pass::First,
+            Quality::default(),
+            &outputs,
+            &[],
+        )
+        .unwrap()
+        .to_string();
+        assert!(cmd.starts_with("-hide_banner -autorotate -i in.mp4 "));
+        assert!(cmd.contains("[v0]scale=-2:1080[out0]"));
+
+        let tagged = VIDEO_STREAM.replace(
+            r#""time_base": "1/12800""#,
+            r#""time_base": "1/12800", "tags": {"rotate": "180"}"#,
+        );
+        let info = self::info(&[&tagged]);
+        assert_eq!(info.video_stream().rotation(), 180);
+        assert_eq!(info.video_stream().display_size(), (1920, 1080));
+    }
+
+    #[test]
+    fn test_outputs_map_their_own_labels() {
+        let spec = |width, height, bit_rate| VideoSpec {
+            width,
+            height,
+            bit_rate,
+            profile: Profile::High,
+            pix_fmt: PixelFormat::Yuv420p,
+            crf: None,
+            fit: Fit::Scale,
+            gop_seconds: None,
+        };
+        // The source is already 1080p, so only the 720p rung is filtered.
+        let outputs = [
+            spec(1920, 1080, 5_000_000).out_dir("out"),
+            spec(1280, 720, 1_500_000).out_dir("out"),
+        ];
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM]);
+        let cmd = transcode_video(
+            &input,
+            &info,
+            Pass::First,
+            Quality::default(),
+            &outputs,
+            &[],
+        )
+        .unwrap()
+        .to_string();
+
+        assert!(cmd.contains("-filter_complex [0:V]split=2[out0][v1];[v1]scale=-2:720[out1] "));
+        let maps = cmd.split("-map ").skip(1).collect::<Vec<_>>();
+        assert_eq!(maps.len(), 2);
+        assert!(maps[0].starts_with("[out0] ") && maps[0].contains("-passlogfile 1920x1080 "));
+        assert!(maps[1].starts_with("[out1] ") && maps[1].contains("-passlogfile 1280x720 "));
+    }
+
+    #[test]
+    fn test_crf_capped_rung() {
+        let dir = test_dir("frameserve-crf-test");
+        let spec = |width, height, bit_rate, crf| VideoSpec {
+            width,
+            height,
+            bit_rate,
+            crf,
+            profile: Profile::High,
+            pix_fmt: PixelFormat::Yuv420p,
+            fit: Fit::Scale,
+            gop_seconds: None,
+        };
+        let outputs = [
+            spec(1920, 1080, 5_000_000, None).out_dir(&dir),
+            spec(1280, 720, 1_500_000, Some(23)).out_dir(&dir),
+        ];
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM]);
+        let encode = |pass| {
+            transcode_video(&input, &info, pass, Quality::default(), &outputs, &[])
+                .unwrap()
+                .to_string()
+        };
+
+        // Only the two-pass rung takes part in the first pass.
+        let first = encode(Pass::First);
+        assert_eq!(first.matches("-map ").count(), 1);
+        assert!(first.contains("[0:V]split=1[out0] "));
+
+        let second = encode(Pass::Second);
+        let crf = second.split("-map ").nth(2).unwrap();
+        assert!(crf.contains("-crf 23 -maxrate 1500000 -bufsize 3000000 "));
+        assert!(!crf.contains("-b:v") && !crf.contains("-pass"));
+        assert!(second.contains("-b:v 5000000 -maxrate 5000000 "));
+        assert!(second.contains("-pass 2 "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_quality_profiles() {
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM]);
+        let encode = |profile, tune| {
+            let quality = Quality { profile, tune };
+            transcode_video(&input, &info, Pass::First, quality, &outputs("out"), &[])
+                .unwrap()
+                .to_string()
+        };
+
+        let fast = encode(QualityProfile::Fast, Tune::Film);
+        assert!(fast.contains("-preset veryfast -tune film "));
+        assert!(!fast.contains("aq-mode"));
+
+        let balanced = encode(QualityProfile::Balanced, Tune::Animation);
+        assert!(balanced.contains("-preset slow -tune animation "));
+
+        let archive = encode(QualityProfile::Archive, Tune::Grain);
+        assert!(archive.contains("-preset veryslow -tune grain "));
+        assert!(archive.contains("aq-mode=3"));
+    }
+
+    #[test]
+    fn test_fractional_gop() {
+        let fps = Ratio::new(30000, 1001);
+        let gop = calculate_gop_length(fps, SEGMENT_DURATION);
+        assert_eq!(gop, Ratio::new(300000, 1001));
+
+        // Twelve hours of 29.97 fps video.
+        let frames = 12 * 60 * 60 * 30000 / 1001;
+        let mut count = 0;
+        for (k, n) in keyframe_positions(gop, frames).enumerate() {
+            let error =
+                Ratio::from_integer(n) / fps - Ratio::from_integer(k as u32 * SEGMENT_DURATION);
+            assert!(error >= Ratio::from_integer(0));
+            assert!(error < fps.recip());
+            count += 1;
+        }
+        assert_eq!(count, 12 * 60 * 6);
+
+        let mut cmd = CmdBuilder::new();
+        let mut info = info(&[VIDEO_STREAM]);
+        let StreamKind::Video(video) = &mut info.streams[0].kind else {
+            unreachable!()
+        };
+        video.avg_frame_rate = fps;
+        outputs("out")[0].write(
+            &mut cmd,
+            info.video_stream(),
+            StreamRef::new_output(0),
+            Pass::First,
+            Quality::default(),
+        );
+        let cmd = cmd.to_string();
+        assert!(cmd.contains("-force_key_frames expr:gte(n,n_forced*300000/1001) "));
+        assert!(cmd.contains("rc-lookahead=299:keyint=600:min-keyint=299 "));
+    }
+
+    #[test]
+    fn test_rung_gop_override() {
+        let dir = test_dir("frameserve-gop-test");
+        let output = |gop_seconds| {
+            VideoSpec {
+                width: 960,
+                height: 540,
+                bit_rate: 400_000,
+                profile: Profile::Main,
+                pix_fmt: PixelFormat::Yuv420p,
+                crf: None,
+                fit: Fit::Scale,
+                gop_seconds,
+            }
+            .out_dir(&dir)
+            .segment_duration(8)
+        };
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM]);
+        let encode = |output| {
+            transcode_video(
+                &input,
+                &info,
+                Pass::Second,
+                Quality::default(),
+                &[output],
+                &[],
+            )
+            .map(|cmd| cmd.to_string())
+        };
+
+        // Two 4s GOPs to each 8s segment.
+        let cmd = encode(output(Some(4))).unwrap();
+        assert!(cmd.contains("-force_key_frames expr:gte(n,n_forced*100/1) "));
+        assert!(cmd.contains("rc-lookahead=100:keyint=200:min-keyint=100 "));
+        assert!(cmd.contains("-hls_time 8 "));
+        // Without an override, one GOP per segment.
+        let cmd = encode(output(None)).unwrap();
+        assert!(cmd.contains("-force_key_frames expr:gte(n,n_forced*200/1) "));
+
+        assert_eq!(
+            encode(output(Some(3))).unwrap_err(),
+            TranscodeError::GopMisaligned {
+                width: 960,
+                height: 540,
+                gop_seconds: 3,
+                segment_duration: 8,
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn audio_args(channels: u8, codec_name: &str, bit_rate: u32) -> Vec<String> {
+        let info = info(&[&AUDIO_STREAM
+            .replace("\"channels\": 2", &format!("\"channels\": {channels}"))
+            .replace("\"aac\"", &format!("\"{codec_name}\""))
+            .replace("128000", &bit_rate.to_string())]);
+        let a = info.audio_stream();
+
+        let dir = test_dir("frameserve-audio-test");
+        let outputs = [
+            Some(AudioOutput::stereo(&dir)),
+            AudioOutput::surround(a, &dir),
+        ];
+
+        let args = outputs
+            .iter()
+            .flatten()
+            .map(|output| {
+                let mut cmd = CmdBuilder::default();
+                output.write(&mut cmd, a);
+                cmd.to_string()
+                    .split(" -f hls ")
+                    .next()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        args
+    }
+
+    #[test]
+    fn test_ts_segments() {
+        let input = Input::File("in.mp4".to_string());
+        let info = info(&[VIDEO_STREAM, AUDIO_STREAM]);
+        let dir = test_dir("frameserve-ts-test");
+        let outputs = outputs(&dir)
+            .into_iter()
+            .map(|output| output.segment_type(SegmentType::MpegTs))
+            .collect::<Vec<_>>();
+        let audio =
+            [AudioOutput::stereo(format!("{dir}/aac_192k")).segment_type(SegmentType::MpegTs)];
+
+        let cmd = transcode_video(
+            &input,
+            &info,
+            Pass::Second,
+            Quality::default(),
+            &outputs,
+            &audio,
+        )
+        .unwrap()
+        .to_string();
+        assert_eq!(cmd.matches("-hls_segment_type mpegts ").count(), 2);
+        assert!(!cmd.contains("fmp4"));
+        assert!(cmd.contains("/1920x1080_5000k_high/s%05d.ts "));
+        assert!(cmd.contains(&format!("-hls_segment_filename {dir}/aac_192k/s%05d.ts ")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_audio_channel_layouts() {
+        assert_eq!(
+            audio_args(6, "ac3", 448000),
+            [
+                "-map 0:a -ac 2 -c:a aac_at -b:a 192k",
+                "-map 0:a -ac 6 -c:a aac_at -b:a 384k",
+            ]
+        );
+        assert_eq!(audio_args(2, "aac", 128000), ["-map 0:a -c:a copy"]);
+        assert_eq!(audio_args(1, "aac", 64000), ["-map 0:a -c:a copy"]);
+        assert_eq!(
+            audio_args(1, "mp3", 64000),
+            ["-map 0:a -ac 1 -c:a aac_at -b:a 192k"]
+        );
+    }
+
+    #[test]
+    fn test_extract_poster() {
+        let input = Input::File("in.mp4".to_string());
+        let cmd = extract_poster(&input, &info(&[VIDEO_STREAM]), "out/poster.jpg")
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            cmd,
+            "-hide_banner -ss 0.008 -i in.mp4 -vf scale=-2:720 -frames:v 1 -q:v 3 -y out/poster.jpg "
+        );
+
+        let result = extract_poster(&input, &info(&[AUDIO_STREAM]), "out/poster.jpg");
+        assert_eq!(result.err(), Some(TranscodeError::NoVideoStream));
+    }
+
+    #[test]
+    fn test_render_crossfade() {
+        let cmd = render_crossfade(
+            "last.mp4",
+            &info(&[VIDEO_STREAM, AUDIO_STREAM]),
+            "first.mp4",
+            2.0,
+            "fade.mkv",
+        )
+        .unwrap()
+        .to_string();
+        assert_eq!(
+            cmd,
+            "-hide_banner -sseof -2.000 -i last.mp4 -t 2.000 -i first.mp4 -filter_complex \
+             [0:v]fps=25/1,scale=1920:1080,setsar=1,format=yuv420p[from];\
+             [1:v]fps=25/1,scale=1920:1080,setsar=1,format=yuv420p[to];\
+             [from][to]xfade=transition=fade:duration=2.000:offset=0[v];\
+             [0:a][1:a]acrossfade=d=2.000[a] \
+             -map [v] -map [a] -c:a flac -c:v libx264 -crf 10 -t 2.000 -y fade.mkv "
+        );
+
+        let silent = render_crossfade(
+            "last.mp4",
+            &info(&[VIDEO_STREAM]),
+            "first.mp4",
+            2.0,
+            "fade.mkv",
+        )
+        .unwrap()
+        .to_string();
+        assert!(!silent.contains("acrossfade") && !silent.contains("-map [a]"));
+    }
+
+    #[test]
+    fn test_extract_sprites() {
+        let input = Input::File("in.mp4".to_string());
+        let dir = test_dir("frameserve-sprites-test");
+        let cmd = extract_sprites(&input, &info(&[VIDEO_STREAM]), &dir, TileLayout::new(5))
+            .unwrap()
+            .to_string();
+        assert!(cmd.contains(
+            "-vf fps=1/5,scale=160:90:force_original_aspect_ratio=decrease,pad=160:90:(ow-iw)/2:(oh-ih)/2,tile=5x5 "
+        ));
+        assert!(cmd.ends_with("/sprite%03d.jpg "));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let layout = TileLayout::new(5);
+        assert_eq!(layout.sheet_seconds(), 125);
+        assert_eq!(layout.tiles(3.0), 1);
+        assert_eq!(layout.tiles(10.0), 2);
+        assert_eq!(layout.tiles(10.5), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_configured_ffmpeg() {
+        let dir = std::env::temp_dir().join("frameserve-ffmpeg-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("ffmpeg-args");
+        let stub = crate::config::test_tool(
+            &dir,
+            "ffmpeg",
+            &format!("#!/bin/sh\necho \"$@\" > '{}'\n", log.display()),
+        );
+
+        let tools = Tools {
+            ffmpeg: stub,
+            ffprobe: "ffprobe".to_string(),
+            audio_encoder: "aac".to_string(),
+        };
+        let mut cmd = CmdBuilder::new();
+        cmd.set("-i", "in.mp4");
+        cmd.execute_with(&tools);
+        assert_eq!(fs::read_to_string(log).unwrap(), "-hide_banner -i in.mp4\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_run_is_returned() {
+        let tools = |ffmpeg: &str| Tools {
+            ffmpeg: ffmpeg.to_string(),
+            ffprobe: "ffprobe".to_string(),
+            audio_encoder: "aac".to_string(),
+        };
+        let cmd = CmdBuilder::new();
+        assert!(cmd.try_execute_with(&tools("true")).is_ok());
+        assert!(matches!(
+            cmd.try_execute_with(&tools("false")),
+            Err(CmdError::Failed(_))
+        ));
+        let missing = cmd
+            .try_execute_with(&tools("/nonexistent/ffmpeg"))
+            .unwrap_err();
+        assert!(
+            missing
+                .to_string()
+                .starts_with("can't run /nonexistent/ffmpeg: ")
+        );
+    }
 }
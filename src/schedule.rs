@@ -1,4 +1,4 @@
-use jiff::Timestamp;
+use jiff::{SignedDuration, Timestamp};
 use serde::Serialize;
 
 use crate::duration::{Duration, StepSize};
@@ -15,5 +15,102 @@ pub struct Schedule {
 pub struct Item {
     pub vid: u32,
     pub start: Duration,
+    /// Where the item ends in the loop, from the loop's start.
     pub duration: Duration,
 }
+
+/// One play of an item, at wall-clock times; see [`Schedule::airings`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Airing {
+    pub vid: u32,
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+impl Schedule {
+    /// Every play of an item that overlaps `from..until`, with the loop
+    /// playing `speed` times faster than real time.
+    pub fn airings(&self, from: Timestamp, until: Timestamp, speed: u64) -> Vec<Airing> {
+        let loop_seconds = self.duration.to_seconds(self.step) / speed as f64;
+        let at = |seconds: f64| self.start + SignedDuration::from_secs_f64(seconds);
+        if loop_seconds <= 0.0 || until <= from {
+            return Vec::new();
+        }
+
+        let elapsed = from.duration_since(self.start).as_secs_f64().max(0.0);
+        let mut airings = Vec::new();
+        for loop_index in (elapsed / loop_seconds).floor() as u64.. {
+            let loop_start = loop_index as f64 * loop_seconds;
+            if at(loop_start) >= until {
+                break;
+            }
+            for item in &self.items {
+                let airing = Airing {
+                    vid: item.vid,
+                    start: at(loop_start + item.start.to_seconds(self.step) / speed as f64),
+                    end: at(loop_start + item.duration.to_seconds(self.step) / speed as f64),
+                };
+                if airing.end > from && airing.start < until {
+                    airings.push(airing);
+                }
+            }
+        }
+        airings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::rational::Ratio;
+
+    use super::*;
+
+    #[test]
+    fn test_airings() {
+        let step = StepSize::calculate([Ratio::new(1, 1000)].into_iter());
+        let seconds = |seconds| Duration::from_seconds(seconds, step);
+        let item = |vid, start, end| Item {
+            vid,
+            start: seconds(start),
+            duration: seconds(end),
+        };
+        let schedule = Schedule {
+            step,
+            start: Timestamp::UNIX_EPOCH,
+            duration: seconds(90.0),
+            items: vec![item(1, 0.0, 60.0), item(2, 60.0, 90.0)],
+        };
+        let at = |seconds| Timestamp::from_second(seconds).unwrap();
+
+        // From partway through the first item, into the second loop.
+        let airings = schedule.airings(at(30), at(120), 1);
+        assert_eq!(
+            airings,
+            [
+                Airing {
+                    vid: 1,
+                    start: at(0),
+                    end: at(60)
+                },
+                Airing {
+                    vid: 2,
+                    start: at(60),
+                    end: at(90)
+                },
+                Airing {
+                    vid: 1,
+                    start: at(90),
+                    end: at(150)
+                },
+            ]
+        );
+
+        // At double speed a loop takes 45s.
+        let airings = schedule.airings(at(450), at(490), 2);
+        let times = airings
+            .iter()
+            .map(|airing| (airing.vid, airing.start, airing.end))
+            .collect::<Vec<_>>();
+        assert_eq!(times, [(1, at(450), at(480)), (2, at(480), at(495))]);
+    }
+}
@@ -0,0 +1,121 @@
+//! Just enough SCTE-35 to mark ad breaks in `EXT-X-DATERANGE`: a
+//! `splice_insert` taking effect immediately, as the tag's dates say when.
+
+/// Ticks per second of SCTE-35 times and durations.
+const CLOCK: f64 = 90000.0;
+
+const TABLE_ID: u8 = 0xfc;
+const SPLICE_INSERT: u8 = 0x05;
+
+/// A `splice_info_section` holding a `splice_insert` for `event_id`, as the
+/// hex `SCTE35-OUT` and `SCTE35-IN` take. Leaving the program (`out`) it
+/// carries the break's planned duration, in seconds.
+pub fn splice_insert(event_id: u32, out: bool, duration: Option<f64>) -> String {
+    let mut command = event_id.to_be_bytes().to_vec();
+    // Not cancelled; reserved bits are set.
+    command.push(0x7f);
+    // Out of network, program splice, duration, immediate, event ID
+    // compliance.
+    command.push(
+        u8::from(out) << 7 | 1 << 6 | u8::from(duration.is_some()) << 5 | 1 << 4 | 1 << 3 | 0x07,
+    );
+    if let Some(duration) = duration {
+        let ticks = (duration * CLOCK).round() as u64 & 0x1_ffff_ffff;
+        // No auto return, the in point is signalled.
+        command.push(0x7e | (ticks >> 32) as u8);
+        command.extend_from_slice(&(ticks as u32).to_be_bytes());
+    }
+    // Unique program ID, avail number, avails expected.
+    command.extend_from_slice(&[0x00, 0x01, 0x00, 0x00]);
+
+    // The command's bytes after splice_command_type.
+    let command_length = command.len() as u16;
+    // From protocol_version through the CRC, after section_length.
+    let section_length = 10 + 1 + command_length + 2 + 4;
+
+    let mut section = vec![
+        TABLE_ID,
+        // No section syntax, not private, SAP type unspecified.
+        0x30 | (section_length >> 8) as u8,
+        section_length as u8,
+        // Protocol version.
+        0x00,
+        // Unencrypted, no PTS adjustment.
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        // CW index.
+        0xff,
+        // Every tier, then the command length.
+        0xff,
+        0xf0 | (command_length >> 8) as u8,
+        command_length as u8,
+        SPLICE_INSERT,
+    ];
+    section.extend_from_slice(&command);
+    // No descriptors.
+    section.extend_from_slice(&[0x00, 0x00]);
+    section.extend_from_slice(&crc32(&section).to_be_bytes());
+
+    let hex = section
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<String>();
+    format!("0x{hex}")
+}
+
+/// CRC-32/MPEG-2, which ends every `splice_info_section`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex = hex.strip_prefix("0x").unwrap();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_splice_insert() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+
+        let out = bytes(&splice_insert(7, true, Some(30.0)));
+        let back = bytes(&splice_insert(7, false, None));
+        for section in [&out, &back] {
+            assert_eq!(section[0], TABLE_ID);
+            let section_length = u16::from_be_bytes([section[1], section[2]]) & 0x0fff;
+            assert_eq!(section.len(), 3 + section_length as usize);
+            let command_length = u16::from_be_bytes([section[11], section[12]]) & 0x0fff;
+            assert_eq!(command_length as usize, section.len() - 14 - 2 - 4);
+            assert_eq!(section[13], SPLICE_INSERT);
+            assert_eq!(section[14..18], 7_u32.to_be_bytes());
+            // The CRC checks out when run over itself.
+            assert_eq!(crc32(section), 0);
+        }
+
+        // Out of network, with 30s at 90kHz.
+        assert_eq!(out[19] >> 7, 1);
+        assert_eq!(out[20..25], [0x7e, 0x00, 0x29, 0x32, 0xe0]);
+        assert_eq!(back[19] >> 5, 0b010);
+        assert_eq!(back.len(), out.len() - 5);
+    }
+}
@@ -0,0 +1,47 @@
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+/// The media and discontinuity sequence numbers a variant playlist
+/// advertises. Players require both to be monotonic, including across
+/// server restarts, so the last values served are persisted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence {
+    pub media: usize,
+    pub discontinuity: usize,
+}
+
+impl Sequence {
+    /// What to add to `self` so that it is no lower than `floor`.
+    pub fn offset_to(self, floor: Sequence) -> Sequence {
+        Sequence {
+            media: floor.media.saturating_sub(self.media),
+            discontinuity: floor.discontinuity.saturating_sub(self.discontinuity),
+        }
+    }
+
+    #[must_use]
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(self, other: Sequence) -> Sequence {
+        Sequence {
+            media: self.media + other.media,
+            discontinuity: self.discontinuity + other.discontinuity,
+        }
+    }
+}
+
+/// Sequences per stream, in ladder order. A missing file means nothing has
+/// been served yet.
+pub fn load_sequences(path: &str) -> Vec<Sequence> {
+    match fs::read_to_string(path) {
+        Ok(src) => serde_json::from_str(&src).unwrap(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => panic!("can't read {path}: {err}"),
+    }
+}
+
+pub fn save_sequences(path: &str, sequences: &[Sequence]) -> io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    fs::write(&tmp, serde_json::to_string(sequences).unwrap())?;
+    fs::rename(tmp, path)
+}
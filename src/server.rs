@@ -0,0 +1,1147 @@
+use std::{collections::BTreeMap, fmt, io, sync::OnceLock, time::Duration};
+
+use crate::{
+    config::{Config, get_config},
+    package::Package,
+    playout::{MasterPlaylist, Playlist, Rung, RungFilter, Stream, TakeoverError, Takeovers},
+    sequence::{load_sequences, save_sequences},
+    store::{self, BlobStore, Location},
+    validate::validate,
+};
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use bytes::Bytes;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tower_http::{
+    cors::CorsLayer, limit::RequestBodyLimitLayer, services::ServeFile, timeout::TimeoutLayer,
+};
+
+/// Every route frameserve serves, for `main` or to mount under another app.
+pub fn build_router(app_state: AppState) -> Router {
+    let config = get_config();
+
+    let mut router = channel_router(app_state.clone());
+    for (name, channel) in app_state.channels {
+        router = router.nest(&format!("/ch/{name}"), channel_router(channel.clone()));
+    }
+
+    let media_timeout = timeout_layer(Duration::from_secs(config.media_timeout_secs));
+    let media = get(media_handler).layer(media_timeout);
+    let shared = Router::new()
+        .route("/version", get(version_handler))
+        .layer(timeout_layer(Duration::from_secs(
+            config.request_timeout_secs,
+        )))
+        .route("/media/{*key}", media)
+        .with_state(app_state);
+
+    router
+        .merge(shared)
+        .layer(RequestBodyLimitLayer::new(config.max_body_bytes))
+        .layer(CorsLayer::permissive())
+}
+
+/// The routes of one channel's playout. Channels share the media routes.
+fn channel_router(app_state: AppState) -> Router {
+    let config = get_config();
+
+    let media_timeout = timeout_layer(Duration::from_secs(config.media_timeout_secs));
+    let loaded = middleware::from_fn_with_state(app_state.clone(), require_playlist);
+    let poster = get(poster_handler)
+        .layer(media_timeout)
+        .layer(loaded.clone());
+
+    Router::new()
+        .route("/hls/index.m3u8", get(hls_index_playlist))
+        .route("/hls/index.json", get(hls_index_json))
+        .route("/hls/{variant}", get(hls_variant_playlist))
+        .route("/preview/{vid}/{variant}", get(preview_playlist))
+        .route("/schedule", get(playlist_handler))
+        .route("/locate", get(locate_handler))
+        .route("/loop", get(loop_handler))
+        .route("/override", post(override_handler))
+        .route("/validate", get(validate_handler))
+        .route("/debug/streams", get(debug_streams_handler))
+        .route_layer(loaded)
+        .layer(timeout_layer(Duration::from_secs(
+            config.request_timeout_secs,
+        )))
+        .route("/poster/{vid}", poster)
+        .with_state(app_state)
+}
+
+/// Until there are packages (see `allow_empty`) there is nothing to play.
+async fn require_playlist(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.playlist.get().is_none() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No packages have been loaded yet\n",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Polls `packages_dir` until there is something to play, then starts it.
+async fn wait_for_packages(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        let config = state.config;
+        let packages = Playlist::load_packages(&config.packages_dir, config);
+        if !packages.is_empty() {
+            state.start(packages);
+            return;
+        }
+    }
+}
+
+async fn persist_sequences(state: AppState, path: &'static str) {
+    let playlist = state.playlist();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        let sequences = playlist.sequences(playlist.now(), state.takeovers);
+        if let Err(err) = save_sequences(path, &sequences) {
+            eprintln!("Failed to persist sequences to {path}: {err}");
+        }
+    }
+}
+
+fn timeout_layer(timeout: Duration) -> TimeoutLayer {
+    TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, timeout)
+}
+
+/// Playlists carry an explicit length so HEAD requests (which `get` routes
+/// answer without a body) report it too; some players and proxies insist.
+fn playlist_response(buffer: String) -> Response {
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/vnd.apple.mpegurl".to_string(),
+            ),
+            (header::CONTENT_LENGTH, buffer.len().to_string()),
+        ],
+        buffer,
+    )
+        .into_response()
+}
+
+/// `?rungs=0,2` and/or `?max_bitrate=` narrow the master playlist to part
+/// of the ladder.
+#[derive(Deserialize)]
+struct MasterQuery {
+    rungs: Option<String>,
+    max_bitrate: Option<u32>,
+}
+
+impl MasterQuery {
+    fn filter(&self) -> Option<RungFilter> {
+        let indices = match &self.rungs {
+            Some(rungs) => Some(
+                rungs
+                    .split(',')
+                    .map(|index| index.trim().parse().ok())
+                    .collect::<Option<Vec<usize>>>()?,
+            ),
+            None => None,
+        };
+        Some(RungFilter {
+            indices,
+            max_bitrate: self.max_bitrate,
+        })
+    }
+}
+
+/// The master playlist `query` asks for; a filter leaving no rungs to play
+/// is not found rather than an empty playlist.
+fn filtered_master(
+    state: &AppState,
+    query: &MasterQuery,
+) -> Result<MasterPlaylist, (StatusCode, &'static str)> {
+    let Some(filter) = query.filter() else {
+        return Err((StatusCode::BAD_REQUEST, "Rungs must be indices\n"));
+    };
+    let master = state.playlist().master_for(&filter);
+    if master.variants.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "No rungs match the filter\n"));
+    }
+    Ok(master)
+}
+
+async fn hls_index_playlist(
+    State(state): State<AppState>,
+    Query(query): Query<MasterQuery>,
+) -> Response {
+    match filtered_master(&state, &query) {
+        Ok(master) => playlist_response(master.to_string()),
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn hls_index_json(
+    State(state): State<AppState>,
+    Query(query): Query<MasterQuery>,
+) -> Response {
+    match filtered_master(&state, &query) {
+        Ok(master) => Json(master).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PlaylistQuery {
+    #[serde(rename = "_HLS_skip")]
+    skip: Option<String>,
+    /// Seconds into the current source to serve from; only with `debug`.
+    source_offset: Option<f64>,
+}
+
+async fn hls_variant_playlist(
+    State(state): State<AppState>,
+    Path(variant): Path<String>,
+    Query(query): Query<PlaylistQuery>,
+) -> impl IntoResponse {
+    let playlist = state.playlist();
+    let Some(stream) = find_stream(playlist, &variant) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let now = playlist.now();
+    let mut buffer = String::with_capacity(stream.capacity());
+    if let Some(seconds) = query.source_offset
+        && state.config.debug
+    {
+        let Some(playhead) = playlist.at_source_offset(now, state.takeovers, seconds) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        stream
+            .render_variant_playlist_at(&mut buffer, playlist, now, &playhead)
+            .unwrap();
+        return playlist_response(buffer);
+    }
+
+    // `v2` asks for date ranges to be skipped too, which would need
+    // `EXT-X-SKIP:RECENTLY-REMOVED-DATERANGES`; the full playlist is still
+    // a valid answer.
+    match query.skip.as_deref() {
+        Some("YES") => stream.render_delta_playlist(&mut buffer, playlist, state.takeovers, now),
+        _ => stream.render_variant_playlist(&mut buffer, playlist, state.takeovers, now),
+    }
+    .unwrap();
+
+    playlist_response(buffer)
+}
+
+/// A stream by its playlist name: `variant<i>.m3u8` or `images.m3u8`.
+fn find_stream<'a>(playlist: &'a Playlist, name: &str) -> Option<&'a Stream> {
+    if name == "images.m3u8" {
+        return playlist.images.as_ref();
+    }
+    name.strip_prefix("variant")
+        .and_then(|rest| rest.strip_suffix(".m3u8"))
+        .and_then(|index| index.parse::<usize>().ok())
+        .and_then(|index| playlist.streams.get(index))
+}
+
+async fn preview_playlist(
+    State(state): State<AppState>,
+    Path((vid, variant)): Path<(u32, String)>,
+    method: Method,
+) -> Response {
+    let stream = find_stream(state.playlist(), &variant).filter(|stream| stream.contains_vid(vid));
+    let Some(stream) = stream else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let playlist = state.playlist();
+    // A HEAD request is owed the `Content-Length` a streamed body can't give.
+    if state.config.stream_previews && method != Method::HEAD {
+        return streamed_playlist_response(move |writer| {
+            stream.render_preview_playlist(writer, playlist, vid)
+        });
+    }
+
+    let mut buffer = String::new();
+    stream
+        .render_preview_playlist(&mut buffer, playlist, vid)
+        .unwrap();
+    playlist_response(buffer)
+}
+
+/// How much of a streamed playlist is sent at a time.
+const PLAYLIST_CHUNK_BYTES: usize = 16 * 1024;
+
+/// Hands a rendered playlist on in chunks, so only one chunk per request
+/// is held at a time rather than the whole playlist.
+struct ChunkWriter {
+    buffer: String,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl ChunkWriter {
+    fn flush(&mut self) -> fmt::Result {
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        // The client went away.
+        self.sender.blocking_send(Ok(chunk)).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Write for ChunkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buffer.push_str(s);
+        if self.buffer.len() >= PLAYLIST_CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Like [`playlist_response`], but rendered on a blocking thread as the
+/// body is sent. There's no `Content-Length`, as it isn't known up front.
+/// A render that fails part way errors the body, so the client sees it cut
+/// off rather than a playlist that merely ends early.
+fn streamed_playlist_response<F>(render: F) -> Response
+where
+    F: FnOnce(&mut ChunkWriter) -> fmt::Result + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            buffer: String::with_capacity(PLAYLIST_CHUNK_BYTES),
+            sender,
+        };
+        let mut rendered = render(&mut writer);
+        if rendered.is_ok() && !writer.buffer.is_empty() {
+            rendered = writer.flush();
+        }
+        if rendered.is_err() {
+            let _ = writer
+                .sender
+                .blocking_send(Err(io::Error::other("rendering the playlist failed")));
+        }
+    });
+    let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((chunk, receiver))
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+/// For files that never change under their name; see `immutable_inits`.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+async fn media_handler(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    request: Request,
+) -> Response {
+    let mut response = serve_blob(state.store, &key, request).await;
+    if state.config.immutable_inits && response.status().is_success() && state.is_init(&key) {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL),
+        );
+    }
+    response
+}
+
+async fn poster_handler(
+    State(state): State<AppState>,
+    Path(vid): Path<u32>,
+    request: Request,
+) -> Response {
+    match state.playlist().poster(vid) {
+        Some(key) => serve_blob(state.store, &key, request).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn serve_blob(store: &dyn BlobStore, key: &str, request: Request) -> Response {
+    match store.locate(key) {
+        Some(Location::File(path)) => ServeFile::new(path).oneshot(request).await.into_response(),
+        Some(Location::Redirect(url)) => Redirect::temporary(&url).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn playlist_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.playlist().schedule())
+}
+
+#[derive(Deserialize)]
+struct LocateQuery {
+    at: Timestamp,
+}
+
+async fn locate_handler(
+    State(state): State<AppState>,
+    Query(query): Query<LocateQuery>,
+) -> impl IntoResponse {
+    Json(state.playlist().locate(query.at, state.takeovers))
+}
+
+#[derive(Serialize)]
+struct LoopInfo {
+    /// Media seconds in one pass over the library.
+    duration_secs: f64,
+    /// Wall-clock seconds one pass takes, allowing for `speed`.
+    period_secs: f64,
+    speed: u64,
+}
+
+async fn loop_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(LoopInfo {
+        duration_secs: state.playlist().loop_duration_seconds(),
+        period_secs: state.playlist().loop_period().as_secs_f64(),
+        speed: state.config.speed,
+    })
+}
+
+/// Checks the playlists as currently served; see [`validate`].
+async fn validate_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let playlist = state.playlist();
+    Json(validate(playlist, state.takeovers, playlist.now()))
+}
+
+/// Each stream's segment count and length; only with `debug`.
+async fn debug_streams_handler(State(state): State<AppState>) -> Response {
+    if !state.config.debug {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(state.playlist().stream_stats()).into_response()
+}
+
+#[derive(Deserialize)]
+struct OverrideRequest {
+    vid: u32,
+}
+
+/// Plays a vid next, once, then carries on with the loop; see
+/// [`Playlist::take_over`].
+async fn override_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<OverrideRequest>,
+) -> Response {
+    let Some(token) = &state.config.override_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token);
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let playlist = state.playlist();
+    match playlist.take_over(playlist.now(), state.takeovers, request.vid) {
+        Ok(window) => Json(window).into_response(),
+        Err(err) => {
+            let status = match err {
+                TakeoverError::UnknownVid => StatusCode::NOT_FOUND,
+                TakeoverError::Busy => StatusCode::CONFLICT,
+            };
+            (status, format!("{err}\n")).into_response()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    commit: &'static str,
+    built_at: Timestamp,
+    config: ConfigSummary,
+}
+
+#[derive(Serialize)]
+struct ConfigSummary {
+    bind_address: &'static str,
+    base: &'static str,
+    media_base: &'static str,
+    speed: u64,
+    ladder: Vec<Rung>,
+}
+
+async fn version_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let config = get_config();
+    let built_at = env!("FRAMESERVE_BUILD_TIMESTAMP").parse().unwrap();
+
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("FRAMESERVE_GIT_COMMIT"),
+        built_at: Timestamp::from_second(built_at).unwrap(),
+        config: ConfigSummary {
+            bind_address: &config.bind_address,
+            base: &config.base,
+            media_base: &config.media_base,
+            speed: config.speed,
+            ladder: state
+                .playlist
+                .get()
+                .map(Playlist::ladder)
+                .unwrap_or_default(),
+        },
+    })
+}
+
+/// What the handlers share: the playout and where its media lives.
+#[derive(Clone)]
+pub struct AppState {
+    /// Set once there are packages to play; see [`AppState::start`].
+    playlist: &'static OnceLock<Playlist>,
+    /// Asked for through `/override`, beside the loaded `playlist`.
+    takeovers: &'static Takeovers,
+    store: &'static dyn BlobStore,
+    /// The global config, or the channel's; see [`Config::for_channel`].
+    config: &'static Config,
+    start: Timestamp,
+    /// Served under `/ch/<name>`, sharing `store`.
+    channels: &'static BTreeMap<String, AppState>,
+}
+
+impl AppState {
+    pub fn new(store: Box<dyn BlobStore>) -> Self {
+        Self {
+            playlist: Box::leak(Box::new(OnceLock::new())),
+            takeovers: Box::leak(Box::default()),
+            store: Box::leak(store),
+            config: get_config(),
+            start: Timestamp::UNIX_EPOCH,
+            channels: Box::leak(Box::default()),
+        }
+    }
+
+    /// Plays `packages_dir` from the configured store, and each of
+    /// `channels` from theirs.
+    pub fn from_config() -> Self {
+        let config = get_config();
+        let app_state = AppState::new(store::from_config(&config.store));
+        app_state.load();
+
+        let channels = config
+            .channels
+            .iter()
+            .map(|channel| {
+                let config = Box::leak(Box::new(config.for_channel(channel)));
+                let start = channel.start.unwrap_or(Timestamp::UNIX_EPOCH);
+                let state = app_state.channel(config, start);
+                state.load();
+                (channel.name.clone(), state)
+            })
+            .collect();
+        app_state.with_channels(channels)
+    }
+
+    /// A channel of its own playing from the same store.
+    pub fn channel(&self, config: &'static Config, start: Timestamp) -> Self {
+        Self {
+            playlist: Box::leak(Box::new(OnceLock::new())),
+            takeovers: Box::leak(Box::default()),
+            store: self.store,
+            config,
+            start,
+            channels: Box::leak(Box::default()),
+        }
+    }
+
+    pub fn with_channels(self, channels: BTreeMap<String, AppState>) -> Self {
+        Self {
+            channels: Box::leak(Box::new(channels)),
+            ..self
+        }
+    }
+
+    /// Plays `packages_dir`, or waits for packages to appear there if
+    /// `allow_empty` is set.
+    fn load(&self) {
+        let config = self.config;
+        let packages = Playlist::load_packages(&config.packages_dir, config);
+        if packages.is_empty() && config.allow_empty {
+            println!(
+                "No packages in {} yet; waiting for some",
+                config.packages_dir
+            );
+            tokio::spawn(wait_for_packages(self.clone()));
+        } else {
+            self.start(packages);
+        }
+    }
+
+    /// Starts the playout from `packages`, picking up the sequence numbers
+    /// of the last run.
+    pub fn start(&self, packages: Vec<Package>) {
+        let config = self.config;
+        let mut playlist = Playlist::with_config(self.start, packages, config);
+        if let Some(path) = &config.sequence_state {
+            let now = playlist.now();
+            playlist.rebase_sequences(&load_sequences(path), now);
+        }
+
+        if self.playlist.set(playlist).is_ok()
+            && let Some(path) = &config.sequence_state
+        {
+            tokio::spawn(persist_sequences(self.clone(), path));
+        }
+    }
+
+    /// Only for routes behind [`require_playlist`].
+    fn playlist(&self) -> &'static Playlist {
+        self.playlist.get().expect("no playlist yet")
+    }
+
+    /// Whether any channel plays `key` as an init segment. The media route
+    /// is shared, so it can't tell which channel a request is for.
+    fn is_init(&self, key: &str) -> bool {
+        self.playlist
+            .get()
+            .is_some_and(|playlist| playlist.is_init(key))
+            || self.channels.values().any(|channel| channel.is_init(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::test_config, package::ByteRange};
+    use axum::{
+        body::to_bytes,
+        http::{Method, Request},
+    };
+
+    async fn send(method: Method, uri: &str) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let config = get_config();
+        state.start(Playlist::load_packages(&config.packages_dir, config));
+        build_router(state).oneshot(request).await.unwrap()
+    }
+
+    async fn head(uri: &str) -> Response {
+        send(Method::HEAD, uri).await
+    }
+
+    async fn assert_head_has_length(uri: &str) {
+        let response = head(uri).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let length = response.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        assert!(length > 0);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_request_times_out() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        state.start(vec![package()]);
+
+        // A body that never arrives holds the handler up until the timeout.
+        let body = futures_util::stream::pending::<Result<Bytes, io::Error>>();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/override")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from_stream(body))
+            .unwrap();
+        let response = build_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_for_packages() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let app = build_router(AppState::new(store));
+        let get = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get("/hls/index.m3u8")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"No packages have been loaded yet\n");
+
+        let response = app.clone().oneshot(get("/poster/1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = app.oneshot(get("/version")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// A one-video package held in memory rather than read from disk.
+    fn package() -> Package {
+        let segments = |prefix: &str| {
+            (0..3)
+                .map(|i| serde_json::json!({"src": format!("{prefix}{i}.mp4"), "start": i * 10000, "duration": 10000}))
+                .collect::<Vec<_>>()
+        };
+        serde_json::from_value(serde_json::json!({
+            "vid": 1,
+            "packaged_at": "2025-01-01T00:00:00Z",
+            "variants": [
+                {
+                    "init_src": "video-init.mp4", "time_base": [1, 1000], "bitrate": 5000000,
+                    "kind": "video", "info": {"width": 1920, "height": 1080},
+                    "segments": segments("video"),
+                },
+                {
+                    "init_src": "video720-init.mp4", "time_base": [1, 1000], "bitrate": 1500000,
+                    "kind": "video", "info": {"width": 1280, "height": 720},
+                    "segments": segments("video720-"),
+                },
+                {
+                    "init_src": "video540-init.mp4", "time_base": [1, 1000], "bitrate": 400000,
+                    "kind": "video", "info": {"width": 960, "height": 540},
+                    "segments": segments("video540-"),
+                },
+                {
+                    "init_src": "audio-init.mp4", "time_base": [1, 1000], "bitrate": 192000,
+                    "kind": "audio", "segments": segments("audio"),
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    async fn fetch(app: &Router, uri: &str) -> (StatusCode, Option<HeaderValue>, String) {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_playlists() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        state.start(vec![package()]);
+        let app = build_router(state);
+
+        let (status, content_type, body) = fetch(&app, "/hls/index.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.unwrap(), "application/vnd.apple.mpegurl");
+        assert!(body.starts_with("#EXTM3U\n"), "{body}");
+        assert!(body.contains("#EXT-X-STREAM-INF:"), "{body}");
+        assert!(body.contains("#EXT-X-MEDIA:TYPE=AUDIO"), "{body}");
+
+        let (status, content_type, body) = fetch(&app, "/hls/variant0.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.unwrap(), "application/vnd.apple.mpegurl");
+        assert!(body.contains("#EXT-X-TARGETDURATION:10\n"), "{body}");
+        assert!(body.contains("#EXT-X-MAP:URI="), "{body}");
+        assert!(body.contains("#EXTINF:10.000000,\n"), "{body}");
+
+        let (status, _, _) = fetch(&app, "/hls/variant99.m3u8").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _, body) = fetch(&app, "/validate").await;
+        assert_eq!(status, StatusCode::OK);
+        let report = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(report["playlists"].as_array().unwrap().len(), 5);
+        assert_eq!(report["passed"], true, "{body}");
+        assert_eq!(report["violations"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let config = test_config(
+            r#"
+            [[channels]]
+            name = "news"
+            packages_dir = "news"
+
+            [[channels]]
+            name = "films"
+            packages_dir = "films"
+            audio_rungs = [{ name = "stereo", bitrate = 192000 }]
+            "#,
+        );
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let channels = config
+            .channels
+            .iter()
+            .map(|channel| {
+                let config = Box::leak(Box::new(config.for_channel(channel)));
+                let channel_state = state.channel(config, Timestamp::UNIX_EPOCH);
+                channel_state.start(vec![package()]);
+                (channel.name.clone(), channel_state)
+            })
+            .collect();
+        let app = build_router(state.with_channels(channels));
+
+        let (status, _, news) = fetch(&app, "/ch/news/hls/index.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            news.contains("http://localhost/ch/news/hls/variant0.m3u8"),
+            "{news}"
+        );
+        assert!(news.contains(r#"NAME="aac_192""#), "{news}");
+
+        let (status, _, films) = fetch(&app, "/ch/films/hls/index.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            films.contains("http://localhost/ch/films/hls/variant0.m3u8"),
+            "{films}"
+        );
+        assert!(films.contains(r#"NAME="stereo""#), "{films}");
+
+        let (status, _, _) = fetch(&app, "/ch/films/hls/variant0.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = fetch(&app, "/ch/sport/hls/index.m3u8").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // The main channel was never started.
+        let (status, _, _) = fetch(&app, "/hls/index.m3u8").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_preview() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let buffered = state.channel(test_config(""), Timestamp::UNIX_EPOCH);
+        let streamed = state.channel(test_config("stream_previews = true"), Timestamp::UNIX_EPOCH);
+        buffered.start(vec![package()]);
+        streamed.start(vec![package()]);
+
+        let uri = "/preview/1/variant0.m3u8";
+        let (status, _, expected) = fetch(&build_router(buffered), uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, content_type, body) = fetch(&build_router(streamed.clone()), uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.unwrap(), "application/vnd.apple.mpegurl");
+        assert_eq!(body, expected);
+        assert!(body.ends_with("#EXT-X-ENDLIST\n"));
+
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = build_router(streamed).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+
+        // Failing part way through errors the body rather than ending it.
+        let response = streamed_playlist_response(|writer| {
+            fmt::Write::write_str(writer, "#EXTM3U\n")?;
+            Err(fmt::Error)
+        });
+        assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_byte_range_variant_playlist() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let mut package = package();
+        // Each variant's init and segments in one file, as `--shared-init`
+        // packages them.
+        for variant in &mut package.variants {
+            let file = variant.init_src.clone().unwrap();
+            variant.init_range = Some(ByteRange {
+                offset: 0,
+                length: 500,
+            });
+            for (i, segment) in variant.segments.iter_mut().enumerate() {
+                segment.src = file.clone();
+                segment.range = Some(ByteRange {
+                    offset: 500 + i as u64 * 1000,
+                    length: 1000,
+                });
+            }
+        }
+        let state = state.channel(test_config(""), Timestamp::UNIX_EPOCH);
+        state.start(vec![package]);
+
+        let (status, _, body) = fetch(&build_router(state), "/hls/variant0.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(
+            "#EXT-X-MAP:URI=\"http://localhost/media/1/video-init.mp4\",BYTERANGE=\"500@0\"\n"
+        ));
+        assert!(
+            body.contains("#EXT-X-BYTERANGE:1000@1500\nhttp://localhost/media/1/video-init.mp4\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_immutable_inits() {
+        let dir = std::env::temp_dir().join("frameserve-immutable-inits");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("1")).unwrap();
+        std::fs::write(dir.join("1/video-init.mp4"), "init").unwrap();
+        std::fs::write(dir.join("1/video0.mp4"), "media").unwrap();
+
+        let mut config = test_config("").clone();
+        config.immutable_inits = true;
+        let store = Box::new(store::LocalStore::new(&dir));
+        let state =
+            AppState::new(store).channel(Box::leak(Box::new(config)), Timestamp::UNIX_EPOCH);
+        state.start(vec![package()]);
+        let app = build_router(state);
+
+        let cache_control = |uri| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers().get(header::CACHE_CONTROL).cloned()
+            }
+        };
+        assert_eq!(
+            cache_control("/media/1/video-init.mp4").await.unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        assert!(cache_control("/media/1/video0.mp4").await.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delta_playlists() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store).channel(
+            test_config("delta_playlists = true\nwindow_seconds = 180"),
+            Timestamp::UNIX_EPOCH,
+        );
+        state.start(vec![package()]);
+        let app = build_router(state);
+
+        let (status, _, body) = fetch(&app, "/hls/variant0.m3u8?_HLS_skip=YES").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("#EXT-X-SKIP:SKIPPED-SEGMENTS="), "{body}");
+        // Date ranges can't be skipped, so v2 gets everything.
+        let (status, _, body) = fetch(&app, "/hls/variant0.m3u8?_HLS_skip=v2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("#EXT-X-SKIP"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_relative_segment_uris() {
+        let dir = std::env::temp_dir().join("frameserve-relative-uris");
+        std::fs::create_dir_all(dir.join("1")).unwrap();
+        for i in 0..3 {
+            std::fs::write(dir.join(format!("1/video{i}.mp4")), "media").unwrap();
+        }
+
+        let mut config = test_config("").clone();
+        config.relative_segment_uris = true;
+        let store = Box::new(store::LocalStore::new(&dir));
+        let state =
+            AppState::new(store).channel(Box::leak(Box::new(config)), Timestamp::UNIX_EPOCH);
+        state.start(vec![package()]);
+        let app = build_router(state);
+
+        let (status, _, body) = fetch(&app, "/hls/variant0.m3u8").await;
+        assert_eq!(status, StatusCode::OK);
+        let uri = body.lines().find(|line| !line.starts_with('#')).unwrap();
+        assert!(uri.starts_with("/media/1/video"), "{uri}");
+        let (status, _, body) = fetch(&app, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "media");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_override() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let app = build_router(state.clone());
+        state.start(vec![package()]);
+        let post = |token: &str, vid: u32| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/override")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"vid": {vid}}}"#)))
+                .unwrap()
+        };
+
+        // Disabled without a token configured.
+        let response = app.oneshot(post("secret", 1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = test_config(r#"override_token = "secret""#);
+        let state = state.channel(config, Timestamp::UNIX_EPOCH);
+        state.start(vec![package()]);
+        let app = build_router(state);
+
+        let response = app.clone().oneshot(post("guess", 1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(post("secret", 9)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.clone().oneshot(post("secret", 1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let window = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(window["vid"], 1);
+
+        let response = app.oneshot(post("secret", 1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_head_master_playlist() {
+        assert_head_has_length("/hls/index.m3u8").await;
+    }
+
+    #[tokio::test]
+    async fn test_head_variant_playlist() {
+        assert_head_has_length("/hls/variant0.m3u8").await;
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let response = send(Method::GET, "/version").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["config"]["ladder"].as_array().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_master_rung_subset() {
+        let response = send(Method::GET, "/hls/index.json?rungs=1,2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let master = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        let uris = master["variants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| variant["uri"].as_str().unwrap().rsplit('/').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(uris, ["variant1.m3u8", "variant2.m3u8"]);
+
+        let response = send(Method::GET, "/hls/index.m3u8?rungs=one").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(Method::GET, "/hls/index.m3u8?max_bitrate=1").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(Method::GET, "/hls/index.json?rungs=99").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_images_playlist_without_thumbnails() {
+        let response = send(Method::GET, "/hls/images.m3u8").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_poster() {
+        let response = send(Method::GET, "/poster/19001085").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(Method::GET, "/poster/latest").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_preview() {
+        let response = send(Method::GET, "/preview/19001085/variant0.m3u8").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(!body.contains("/73005431/"));
+
+        let response = send(Method::GET, "/preview/1/variant0.m3u8").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(Method::GET, "/preview/19001085/variant9.m3u8").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_debug_streams() {
+        let store = Box::new(store::LocalStore::new("segments"));
+        let state = AppState::new(store);
+        let hidden = state.channel(test_config(""), Timestamp::UNIX_EPOCH);
+        hidden.start(vec![package()]);
+        let (status, _, _) = fetch(&build_router(hidden), "/debug/streams").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut config = test_config("").clone();
+        config.debug = true;
+        let state = state.channel(Box::leak(Box::new(config)), Timestamp::UNIX_EPOCH);
+        state.start(vec![package()]);
+        let (status, _, body) = fetch(&build_router(state), "/debug/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        let debug = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        let top = &debug[0];
+        assert_eq!(top["bitrate"], 5000000);
+        assert_eq!(top["segments"], 3);
+        assert_eq!(top["duration_seconds"], 30.0);
+    }
+
+    #[tokio::test]
+    async fn test_loop() {
+        let response = send(Method::GET, "/loop").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        let duration = info["duration_secs"].as_f64().unwrap();
+        assert!(duration > 0.0);
+        let period = info["period_secs"].as_f64().unwrap();
+        assert!((period - duration).abs() < 1e-6, "{period} vs {duration}");
+    }
+
+    #[tokio::test]
+    async fn test_locate() {
+        let response = send(Method::GET, "/locate?at=1970-01-01T00:00:00Z").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let location = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(location["segment"], 0);
+        assert_eq!(location["media_sequence"], 0);
+        assert_eq!(location["loop_index"], 0);
+
+        let response = send(Method::GET, "/locate?at=yesterday").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use crate::config::StoreConfig;
+
+/// Where packaged blobs (init and media segments) live. Keys are
+/// `<vid>/<resource>`, matching the URIs written into the playlists.
+pub trait BlobStore: Send + Sync {
+    fn put(&self, file: &str, key: &str) -> io::Result<()>;
+
+    /// How a client should fetch `key`, or `None` if the key is malformed.
+    fn locate(&self, key: &str) -> Option<Location>;
+}
+
+pub enum Location {
+    File(PathBuf),
+    Redirect(String),
+}
+
+pub fn from_config(config: &StoreConfig) -> Box<dyn BlobStore> {
+    match config {
+        StoreConfig::Local { dir } => Box::new(LocalStore::new(dir)),
+        StoreConfig::S3 {
+            bucket,
+            endpoint,
+            public_base,
+        } => Box::new(S3Store {
+            bucket: bucket.clone(),
+            endpoint: endpoint.clone(),
+            public_base: public_base.clone(),
+        }),
+    }
+}
+
+/// Keys come straight from request paths, so anything that could step
+/// outside the store is rejected.
+fn is_safe_key(key: &str) -> bool {
+    !key.is_empty()
+        && Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put(&self, file: &str, key: &str) -> io::Result<()> {
+        let dst = self.dir.join(key);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(file, dst)?;
+        Ok(())
+    }
+
+    fn locate(&self, key: &str) -> Option<Location> {
+        is_safe_key(key).then(|| Location::File(self.dir.join(key)))
+    }
+}
+
+/// An S3-compatible bucket. Uploads go through the `aws` CLI, the same way
+/// encoding goes through `ffmpeg`; reads are redirected to `public_base`,
+/// typically a CDN or the bucket's public website endpoint.
+pub struct S3Store {
+    bucket: String,
+    endpoint: Option<String>,
+    public_base: String,
+}
+
+impl BlobStore for S3Store {
+    fn put(&self, file: &str, key: &str) -> io::Result<()> {
+        let mut cmd = Command::new("aws");
+        cmd.args(["s3", "cp", file, &format!("s3://{}/{key}", self.bucket)]);
+        if let Some(endpoint) = &self.endpoint {
+            cmd.args(["--endpoint-url", endpoint]);
+        }
+
+        let status = cmd.status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("aws s3 cp exited with {status}")))
+        }
+    }
+
+    fn locate(&self, key: &str) -> Option<Location> {
+        is_safe_key(key).then(|| Location::Redirect(format!("{}/{key}", self.public_base)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_store_round_trip() {
+        let dir = std::env::temp_dir().join("frameserve-store-test");
+        let _ = fs::remove_dir_all(&dir);
+        let src = std::env::temp_dir().join("frameserve-store-test.mp4");
+        fs::write(&src, b"segment").unwrap();
+
+        let store = LocalStore::new(&dir);
+        store.put(src.to_str().unwrap(), "1/abc.mp4").unwrap();
+
+        let Some(Location::File(path)) = store.locate("1/abc.mp4") else {
+            panic!("expected a local file");
+        };
+        assert_eq!(fs::read(path).unwrap(), b"segment");
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&src).unwrap();
+    }
+
+    #[test]
+    fn test_s3_store_redirects() {
+        let store = S3Store {
+            bucket: "media".to_string(),
+            endpoint: None,
+            public_base: "https://cdn.example.com".to_string(),
+        };
+        let Some(Location::Redirect(url)) = store.locate("1/abc.mp4") else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(url, "https://cdn.example.com/1/abc.mp4");
+    }
+
+    #[test]
+    fn test_unsafe_keys_are_rejected() {
+        let store = LocalStore::new("segments");
+        assert!(store.locate("../fserve.toml").is_none());
+        assert!(store.locate("/etc/passwd").is_none());
+        assert!(store.locate("").is_none());
+    }
+}
@@ -18,3 +18,34 @@ pub fn range_compare<T: Ord>(value: T, range: &Range<T>) -> Ordering {
         Ordering::Equal
     }
 }
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters, e.g. `1900*`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("19001085", "19001085"));
+        assert!(!glob_match("19001085", "19001086"));
+        assert!(glob_match("1900*", "19001085"));
+        assert!(glob_match("*85", "19001085"));
+        assert!(glob_match("1*0*5", "19001085"));
+        assert!(!glob_match("7*", "19001085"));
+    }
+}
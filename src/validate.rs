@@ -0,0 +1,261 @@
+use jiff::{SignedDuration, Timestamp};
+use serde::Serialize;
+
+use crate::playout::{Playlist, Takeovers};
+
+/// How far apart the two renders compared for sequence numbers are.
+const LATER: SignedDuration = SignedDuration::from_secs(60);
+
+/// The outcome of [`validate`]: every playlist checked, and what was wrong
+/// with them.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub passed: bool,
+    pub playlists: Vec<String>,
+    pub violations: Vec<Violation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub playlist: String,
+    pub problem: String,
+}
+
+impl Report {
+    fn add(&mut self, playlist: &str, problems: Vec<String>) {
+        if !self.playlists.iter().any(|name| name == playlist) {
+            self.playlists.push(playlist.to_string());
+        }
+        self.violations
+            .extend(problems.into_iter().map(|problem| Violation {
+                playlist: playlist.to_string(),
+                problem,
+            }));
+        self.passed = self.violations.is_empty();
+    }
+}
+
+/// Renders the master playlist and every media playlist as they'd be
+/// served at `now`, and again a minute later, and checks them against a
+/// subset of Apple's HLS authoring rules.
+pub fn validate(playlist: &Playlist, takeovers: &Takeovers, now: Timestamp) -> Report {
+    let mut report = Report {
+        passed: true,
+        ..Report::default()
+    };
+
+    report.add("index.m3u8", check_master(&playlist.master().to_string()));
+
+    let streams = playlist
+        .streams
+        .iter()
+        .enumerate()
+        .map(|(i, stream)| (format!("variant{i}.m3u8"), stream))
+        .chain(
+            playlist
+                .images
+                .iter()
+                .map(|stream| ("images.m3u8".to_string(), stream)),
+        );
+    for (name, stream) in streams {
+        let render = |at| {
+            let mut out = String::new();
+            stream
+                .render_variant_playlist(&mut out, playlist, takeovers, at)
+                .map(|()| out)
+        };
+        let (Ok(current), Ok(later)) = (render(now), render(now + LATER)) else {
+            report.add(&name, vec!["failed to render".to_string()]);
+            continue;
+        };
+        report.add(&name, check_media(&current));
+        report.add(&name, check_sequences(&current, &later));
+    }
+    report
+}
+
+/// The attributes of a tag such as `EXT-X-STREAM-INF`, with any quotes
+/// taken off their values.
+fn attributes(list: &str) -> Vec<(&str, &str)> {
+    let mut attributes = Vec::new();
+    let mut rest = list;
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, next) = quoted.split_once('"').unwrap_or((quoted, ""));
+                (value, next.strip_prefix(',').unwrap_or(next))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        attributes.push((name.trim(), value));
+        rest = next;
+    }
+    attributes
+}
+
+fn tag_value<'a>(playlist: &'a str, tag: &str) -> Option<&'a str> {
+    playlist
+        .lines()
+        .find_map(|line| line.strip_prefix(tag)?.strip_prefix(':'))
+}
+
+/// Every variant has `CODECS`, and every video variant names an audio
+/// group that the playlist has renditions for.
+fn check_master(master: &str) -> Vec<String> {
+    let groups = master
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXT-X-MEDIA:"))
+        .map(attributes)
+        .filter(|attributes| attributes.contains(&("TYPE", "AUDIO")))
+        .filter_map(|attributes| {
+            attributes
+                .into_iter()
+                .find_map(|(name, value)| (name == "GROUP-ID").then_some(value))
+        })
+        .collect::<Vec<_>>();
+
+    let mut problems = Vec::new();
+    let variants = master
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXT-X-STREAM-INF:"));
+    for (i, variant) in variants.enumerate() {
+        let attributes = attributes(variant);
+        let get = |wanted| {
+            attributes
+                .iter()
+                .find_map(|(name, value)| (*name == wanted).then_some(*value))
+        };
+        if get("CODECS").is_none_or(str::is_empty) {
+            problems.push(format!("variant {i} has no CODECS"));
+        }
+        if get("RESOLUTION").is_some() {
+            match get("AUDIO") {
+                Some(group) if groups.contains(&group) => {}
+                Some(group) => problems.push(format!(
+                    "variant {i} refers to audio group {group:?}, which has no renditions"
+                )),
+                None => problems.push(format!("variant {i} has no audio group")),
+            }
+        }
+    }
+    problems
+}
+
+/// There are segments, `TARGETDURATION` covers every one of them, rounded,
+/// and fMP4 segments have an `EXT-X-MAP` ahead of them.
+pub(crate) fn check_media(media: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let Some(target) =
+        tag_value(media, "#EXT-X-TARGETDURATION").and_then(|v| v.parse::<u64>().ok())
+    else {
+        return vec!["no EXT-X-TARGETDURATION".to_string()];
+    };
+    // As served for a rung no package has a variant for.
+    if !media.contains("#EXTINF:") {
+        return vec!["no segments".to_string()];
+    }
+    if tag_value(media, "#EXT-X-MEDIA-SEQUENCE").is_none() {
+        problems.push("no EXT-X-MEDIA-SEQUENCE".to_string());
+    }
+
+    let mut mapped = false;
+    for line in media.lines() {
+        if line.starts_with("#EXT-X-MAP:") {
+            mapped = true;
+        } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let duration = extinf
+                .split_once(',')
+                .map_or(extinf, |(duration, _)| duration)
+                .parse::<f64>()
+                .unwrap_or(f64::INFINITY);
+            if duration.round() as u64 > target {
+                problems.push(format!(
+                    "EXTINF of {duration}s exceeds EXT-X-TARGETDURATION of {target}s"
+                ));
+            }
+        } else if !line.starts_with('#') && !line.is_empty() {
+            let path = line.split_once('?').map_or(line, |(path, _)| path);
+            let fmp4 = path.ends_with(".mp4") || path.ends_with(".m4s");
+            if fmp4 && !mapped {
+                problems.push(format!("fMP4 segment {line} has no EXT-X-MAP before it"));
+                mapped = true;
+            }
+        }
+    }
+    problems
+}
+
+/// Neither sequence number goes backwards between two renders.
+fn check_sequences(current: &str, later: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    for tag in ["#EXT-X-MEDIA-SEQUENCE", "#EXT-X-DISCONTINUITY-SEQUENCE"] {
+        let sequence = |media| tag_value(media, tag).and_then(|v| v.parse::<u64>().ok());
+        if let (Some(current), Some(later)) = (sequence(current), sequence(later))
+            && later < current
+        {
+            problems.push(format!("{} went back from {current} to {later}", &tag[1..]));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_duration_too_short() {
+        let media = "#EXTM3U\n\
+            #EXT-X-VERSION:7\n\
+            #EXT-X-TARGETDURATION:4\n\
+            #EXT-X-MEDIA-SEQUENCE:10\n\
+            #EXTINF:4.400000,\n\
+            http://localhost/media/1/a.ts\n\
+            #EXTINF:6.000000,\n\
+            http://localhost/media/1/b.ts\n";
+        let problems = check_media(media);
+        assert_eq!(
+            problems,
+            ["EXTINF of 6s exceeds EXT-X-TARGETDURATION of 4s"]
+        );
+
+        let unmapped = media
+            .replace(".ts", ".mp4")
+            .replace("TARGETDURATION:4", "TARGETDURATION:6");
+        assert_eq!(
+            check_media(&unmapped),
+            ["fMP4 segment http://localhost/media/1/a.mp4 has no EXT-X-MAP before it"]
+        );
+    }
+
+    #[test]
+    fn test_master_audio_groups() {
+        let master = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=5192000,RESOLUTION=1920x1080,CODECS=\"avc1.64e01f, mp4a.40.2\",AUDIO=\"audio\"\n\
+            variant0.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1692000,RESOLUTION=1280x720,AUDIO=\"surround\"\n\
+            variant1.m3u8\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"aac_192\",DEFAULT=YES,URI=\"variant3.m3u8\"\n";
+        assert_eq!(
+            check_master(master),
+            [
+                "variant 1 has no CODECS",
+                "variant 1 refers to audio group \"surround\", which has no renditions"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sequences_go_backwards() {
+        let at = |media, discontinuity| {
+            format!(
+                "#EXT-X-MEDIA-SEQUENCE:{media}\n#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuity}\n"
+            )
+        };
+        assert!(check_sequences(&at(10, 2), &at(12, 3)).is_empty());
+        assert_eq!(
+            check_sequences(&at(10, 2), &at(9, 2)),
+            ["EXT-X-MEDIA-SEQUENCE went back from 10 to 9"]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    fmt::{self, Display},
+    fs,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    inspect::{try_combine_inspect, try_inspect},
+    validate::check_media,
+};
+
+/// What [`verify_encode`] found wrong with one rung's directory; nothing
+/// when it passed.
+#[derive(Debug, Serialize)]
+pub struct RungReport {
+    pub rung: String,
+    pub problems: Vec<String>,
+}
+
+impl RungReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for RungReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return writeln!(f, "  {:<24}ok", self.rung);
+        }
+        writeln!(f, "  {:<24}FAILED", self.rung)?;
+        for problem in &self.problems {
+            writeln!(f, "    {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks each rung an encode left in `dir` (one video's directory, such as
+/// `encodes/<vid>`) before it's packaged: its `stream.m3u8` is finished and
+/// valid, every file it lists is there, and video is no larger than the
+/// rung its directory is named for.
+pub fn verify_encode(dir: &Path) -> Result<Vec<RungReport>, String> {
+    let entries = fs::read_dir(dir).map_err(|err| format!("{}: {err}", dir.display()))?;
+    let mut rungs = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    if rungs.is_empty() {
+        return Err(format!("{} has no rung directories", dir.display()));
+    }
+    rungs.sort();
+
+    let reports = rungs
+        .iter()
+        .map(|rung_dir| {
+            let rung = rung_dir.file_name().unwrap().to_string_lossy().into_owned();
+            let mut problems = check_files(rung_dir);
+            if problems.is_empty()
+                && let Some(size) = rung_size(&rung)
+            {
+                match probe_size(rung_dir) {
+                    Ok(actual) => problems.extend(check_resolution(size, actual)),
+                    Err(err) => problems.push(err),
+                }
+            }
+            RungReport { rung, problems }
+        })
+        .collect();
+    Ok(reports)
+}
+
+/// The most a video rung's output may be, from a directory name such as
+/// `1920x1080_5000k_high`. `None` for audio.
+fn rung_size(rung: &str) -> Option<(u16, u16)> {
+    let (width, height) = rung.split('_').next()?.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// The init segment `stream.m3u8` maps, if any, and the segments it lists,
+/// as file names.
+fn listed_files(m3u8: &str) -> (Option<&str>, Vec<&str>) {
+    let init = m3u8
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXT-X-MAP:"))
+        .find_map(|map| map.split_once("URI=\"")?.1.split_once('"'))
+        .map(|(uri, _)| uri);
+    let segments = m3u8
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    (init, segments)
+}
+
+fn check_files(rung_dir: &Path) -> Vec<String> {
+    let m3u8 = match fs::read_to_string(rung_dir.join("stream.m3u8")) {
+        Ok(m3u8) => m3u8,
+        Err(err) => return vec![format!("can't read stream.m3u8: {err}")],
+    };
+    let mut problems = Vec::new();
+    if !m3u8.contains("#EXT-X-ENDLIST") {
+        problems.push("stream.m3u8 has no EXT-X-ENDLIST; the encode didn't finish".to_string());
+    }
+    problems.extend(check_media(&m3u8));
+
+    let (init, segments) = listed_files(&m3u8);
+    for file in init.into_iter().chain(segments) {
+        match fs::metadata(rung_dir.join(file)) {
+            Ok(metadata) if metadata.len() == 0 => problems.push(format!("{file} is empty")),
+            Ok(_) => {}
+            Err(_) => problems.push(format!("{file} is missing")),
+        }
+    }
+    for extinf in m3u8
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXTINF:"))
+    {
+        let duration = extinf
+            .split_once(',')
+            .map_or(extinf, |(duration, _)| duration);
+        if duration
+            .parse::<f64>()
+            .is_ok_and(|duration| duration <= 0.0)
+        {
+            problems.push(format!("a segment lasts {duration}s"));
+        }
+    }
+    problems
+}
+
+/// The size of the first segment's video, as ffprobe gives it.
+fn probe_size(rung_dir: &Path) -> Result<(u16, u16), String> {
+    let m3u8 = fs::read_to_string(rung_dir.join("stream.m3u8"))
+        .map_err(|err| format!("can't read stream.m3u8: {err}"))?;
+    let (init, segments) = listed_files(&m3u8);
+    let first = segments
+        .first()
+        .ok_or("stream.m3u8 lists no segments to probe")?;
+    let path = |file| rung_dir.join(file).to_string_lossy().into_owned();
+    let info = match init {
+        Some(init) => try_combine_inspect(&path(init), &path(first))?,
+        None => try_inspect(&path(first))?,
+    };
+    let video = info
+        .find_video_stream()
+        .ok_or_else(|| format!("{first} has no video stream"))?;
+    Ok((video.width, video.height))
+}
+
+/// Video is scaled down to fit its rung, or padded to it exactly, but never
+/// scaled past it.
+fn check_resolution((width, height): (u16, u16), actual: (u16, u16)) -> Option<String> {
+    (actual.0 > width || actual.1 > height).then(|| {
+        format!(
+            "video is {}x{}, larger than the rung's {width}x{height}",
+            actual.0, actual.1
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn rung(root: &Path, name: &str, m3u8: &str, files: &[&str]) -> PathBuf {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("stream.m3u8"), m3u8).unwrap();
+        for file in files {
+            fs::write(dir.join(file), "data").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_check_files() {
+        let root = std::env::temp_dir().join("frameserve-verify-encode");
+        let _ = fs::remove_dir_all(&root);
+        let m3u8 = "#EXTM3U\n\
+            #EXT-X-VERSION:7\n\
+            #EXT-X-TARGETDURATION:10\n\
+            #EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXTINF:10.000000,\n\
+            s00000.mp4\n\
+            #EXTINF:4.200000,\n\
+            s00001.mp4\n\
+            #EXT-X-ENDLIST\n";
+
+        let good = rung(
+            &root,
+            "aac_192k",
+            m3u8,
+            &["init.mp4", "s00000.mp4", "s00001.mp4"],
+        );
+        assert!(check_files(&good).is_empty());
+
+        let unfinished = m3u8.replace("#EXT-X-ENDLIST\n", "");
+        let bad = rung(&root, "960x540_400k_main", &unfinished, &["s00000.mp4"]);
+        assert_eq!(
+            check_files(&bad),
+            [
+                "stream.m3u8 has no EXT-X-ENDLIST; the encode didn't finish",
+                "init.mp4 is missing",
+                "s00001.mp4 is missing"
+            ]
+        );
+
+        let missing = check_files(&root.join("1280x720_1500k_high"));
+        assert_eq!(missing.len(), 1);
+        assert!(
+            missing[0].starts_with("can't read stream.m3u8: "),
+            "{missing:?}"
+        );
+
+        // Data ffprobe makes nothing of is a problem with the rung, not a
+        // panic.
+        let garbage = rung(
+            &root,
+            "1920x1080_5000k_high",
+            m3u8,
+            &["init.mp4", "s00000.mp4"],
+        );
+        assert!(probe_size(&garbage).is_err());
+        assert!(probe_size(&root.join("1280x720_1500k_high")).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_resolution() {
+        assert_eq!(rung_size("1920x1080_5000k_high"), Some((1920, 1080)));
+        assert_eq!(rung_size("aac_384k_6ch"), None);
+
+        // A 4:3 source scaled to fit a 16:9 rung.
+        assert_eq!(check_resolution((1920, 1080), (1440, 1080)), None);
+        assert_eq!(
+            check_resolution((1280, 720), (1920, 1080)).unwrap(),
+            "video is 1920x1080, larger than the rung's 1280x720"
+        );
+    }
+}